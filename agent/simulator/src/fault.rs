@@ -0,0 +1,117 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Misbehaviour seen in the field.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fault {
+    /// Delays the response.
+    Latency { ms: u64 },
+    /// Answers with this Modbus exception code instead of data.
+    Exception { code: u8 },
+    /// Reads the request and never answers.
+    NoResponse,
+    /// Closes the connection instead of answering.
+    Disconnect,
+}
+
+/// A fault and the requests it applies to.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FaultRule {
+    #[serde(flatten)]
+    pub fault: Fault,
+    /// Only requests with this function code.
+    #[serde(default)]
+    pub function: Option<u8>,
+    /// Only requests touching this inclusive address range.
+    #[serde(default)]
+    pub addresses: Option<[u16; 2]>,
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+    /// Number of hits before the rule expires; unlimited when absent.
+    #[serde(default)]
+    pub times: Option<u32>,
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+impl FaultRule {
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            function: None,
+            addresses: None,
+            probability: 1.0,
+            times: None,
+        }
+    }
+
+    pub fn function(mut self, function: u8) -> Self {
+        self.function = Some(function);
+        self
+    }
+
+    pub fn addresses(mut self, first: u16, last: u16) -> Self {
+        self.addresses = Some([first, last]);
+        self
+    }
+
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    pub fn times(mut self, times: u32) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    fn matches(&self, function: u8, first: u16, last: u16) -> bool {
+        self.function.is_none_or(|f| f == function)
+            && self
+                .addresses
+                .is_none_or(|[from, to]| first <= to && last >= from)
+    }
+}
+
+/// What the connection does with one request.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Reply,
+    Exception(u8),
+    NoResponse,
+    Disconnect,
+}
+
+/// Applies every matching rule: latencies add up, and the first matching
+/// exception, silence or disconnect replaces the normal reply.
+pub fn resolve(
+    rules: &mut Vec<FaultRule>,
+    function: u8,
+    first: u16,
+    last: u16,
+    rng: &mut impl Rng,
+) -> (Duration, Action) {
+    let mut delay = Duration::ZERO;
+    let mut action = Action::Reply;
+    for rule in rules.iter_mut() {
+        if !rule.matches(function, first, last) || !rng.gen_bool(rule.probability) {
+            continue;
+        }
+        match &rule.fault {
+            Fault::Latency { ms } => delay += Duration::from_millis(*ms),
+            _ if action != Action::Reply => continue,
+            Fault::Exception { code } => action = Action::Exception(*code),
+            Fault::NoResponse => action = Action::NoResponse,
+            Fault::Disconnect => action = Action::Disconnect,
+        }
+        if let Some(times) = &mut rule.times {
+            *times = times.saturating_sub(1);
+        }
+    }
+    rules.retain(|rule| rule.times != Some(0));
+    (delay, action)
+}
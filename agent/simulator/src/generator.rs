@@ -0,0 +1,198 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::f64::consts::TAU;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::SimError;
+
+/// Where a point's value comes from over time.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GeneratorConfig {
+    Constant { value: u16 },
+    /// Sawtooth from `min` to `max` over `period_ms`.
+    Ramp { min: u16, max: u16, period_ms: u64 },
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period_ms: u64,
+    },
+    /// Moves by up to `step` either way on every tick, within bounds.
+    RandomWalk {
+        start: u16,
+        step: u16,
+        min: u16,
+        max: u16,
+    },
+    /// Replays `elapsed_ms,value[,value...]` rows, holding each value until
+    /// the next row. A header row is skipped.
+    Csv {
+        file: PathBuf,
+        #[serde(default = "default_column")]
+        column: usize,
+        #[serde(default = "default_repeat")]
+        repeat: bool,
+    },
+}
+
+fn default_column() -> usize {
+    1
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+pub enum Generator {
+    Constant(u16),
+    Ramp { min: u16, max: u16, period: Duration },
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period: Duration,
+    },
+    RandomWalk {
+        value: u16,
+        step: u16,
+        min: u16,
+        max: u16,
+    },
+    Csv {
+        rows: Vec<(u64, u16)>,
+        repeat: bool,
+    },
+}
+
+impl Generator {
+    pub fn new(config: &GeneratorConfig) -> Result<Self, SimError> {
+        let positive = |period_ms: u64| {
+            if period_ms == 0 {
+                Err(SimError::Map("period_ms must be greater than 0".to_string()))
+            } else {
+                Ok(Duration::from_millis(period_ms))
+            }
+        };
+        Ok(match config {
+            GeneratorConfig::Constant { value } => Generator::Constant(*value),
+            GeneratorConfig::Ramp {
+                min,
+                max,
+                period_ms,
+            } => Generator::Ramp {
+                min: *min,
+                max: (*max).max(*min),
+                period: positive(*period_ms)?,
+            },
+            GeneratorConfig::Sine {
+                offset,
+                amplitude,
+                period_ms,
+            } => Generator::Sine {
+                offset: *offset,
+                amplitude: *amplitude,
+                period: positive(*period_ms)?,
+            },
+            GeneratorConfig::RandomWalk {
+                start,
+                step,
+                min,
+                max,
+            } => Generator::RandomWalk {
+                value: (*start).clamp(*min, (*max).max(*min)),
+                step: *step,
+                min: *min,
+                max: (*max).max(*min),
+            },
+            GeneratorConfig::Csv {
+                file,
+                column,
+                repeat,
+            } => Generator::Csv {
+                rows: load_csv(file, *column)?,
+                repeat: *repeat,
+            },
+        })
+    }
+
+    /// Value at `elapsed` since the simulator started.
+    pub fn sample(&mut self, elapsed: Duration, rng: &mut impl Rng) -> u16 {
+        match self {
+            Generator::Constant(value) => *value,
+            Generator::Ramp { min, max, period } => {
+                let phase = (elapsed.as_millis() % period.as_millis()) as f64
+                    / period.as_millis() as f64;
+                *min + (phase * f64::from(*max - *min)).round() as u16
+            }
+            Generator::Sine {
+                offset,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64();
+                let value = *offset + *amplitude * (TAU * phase).sin();
+                value.round().clamp(0.0, f64::from(u16::MAX)) as u16
+            }
+            Generator::RandomWalk {
+                value,
+                step,
+                min,
+                max,
+            } => {
+                let delta = rng.gen_range(-i32::from(*step)..=i32::from(*step));
+                *value = (i32::from(*value) + delta).clamp(i32::from(*min), i32::from(*max)) as u16;
+                *value
+            }
+            Generator::Csv { rows, repeat } => {
+                let Some(&(last, _)) = rows.last() else {
+                    return 0;
+                };
+                let mut at = elapsed.as_millis() as u64;
+                if *repeat && last > 0 {
+                    at %= last + 1;
+                }
+                let index = rows.partition_point(|(t, _)| *t <= at);
+                rows[index.saturating_sub(1)].1
+            }
+        }
+    }
+}
+
+fn load_csv(path: &PathBuf, column: usize) -> Result<Vec<(u64, u16)>, SimError> {
+    let raw = fs::read_to_string(path).map_err(|source| SimError::Read {
+        path: path.display().to_string(),
+        source,
+    })?;
+    let mut rows = Vec::new();
+    for (i, line) in raw.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let Ok(at) = fields[0].parse::<u64>() else {
+            if rows.is_empty() && i == 0 {
+                continue;
+            }
+            return Err(SimError::Map(format!(
+                "{}:{}: expected elapsed milliseconds, got {:?}",
+                path.display(),
+                i + 1,
+                fields[0]
+            )));
+        };
+        let value = fields
+            .get(column)
+            .and_then(|v| v.parse::<f64>().ok())
+            .ok_or_else(|| {
+                SimError::Map(format!("{}:{}: no numeric column {}", path.display(), i + 1, column))
+            })?;
+        rows.push((at, value.round().clamp(0.0, f64::from(u16::MAX)) as u16));
+    }
+    if rows.is_empty() {
+        return Err(SimError::Map(format!("{}: no rows", path.display())));
+    }
+    rows.sort_by_key(|(at, _)| *at);
+    Ok(rows)
+}
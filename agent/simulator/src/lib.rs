@@ -0,0 +1,37 @@
+//! Modbus TCP slave simulator. Serves coils, discrete inputs, holding and
+//! input registers from a register map, drives values with generators and
+//! injects faults, either from the map file or at runtime through
+//! [`Simulator`]. It can also replay traffic captured by the agent on site.
+//! Used by the `simulator` binary and embedded in tests.
+
+pub mod fault;
+pub mod generator;
+pub mod map;
+pub mod replay;
+pub mod server;
+
+pub use fault::{Fault, FaultRule};
+pub use generator::GeneratorConfig;
+pub use map::{Point, RegisterMap, Table};
+pub use replay::ReplayConfig;
+pub use server::Simulator;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SimError {
+    #[error("Invalid register map: {0}")]
+    Map(String),
+
+    #[error("Failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to listen on {addr}: {source}")]
+    Listen {
+        addr: String,
+        source: std::io::Error,
+    },
+}
@@ -0,0 +1,146 @@
+use simulator::{Fault, FaultRule, RegisterMap, ReplayConfig, Simulator};
+use std::env;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str =
+    "usage: simulator [MAP.toml] [--listen ADDR] [--replay CAPTURE.jsonl [--speed X] [--once]]";
+
+const COMMANDS: &str = "commands: latency MS | exception CODE | noresponse | disconnect | drop | clear";
+
+struct CliArgs {
+    map: Option<PathBuf>,
+    listen: Option<String>,
+    replay: Option<ReplayConfig>,
+}
+
+fn parse_args() -> Result<CliArgs, String> {
+    let mut map = None;
+    let mut listen = None;
+    let mut replay: Option<ReplayConfig> = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" | "-l" => listen = Some(args.next().ok_or("--listen needs an address")?),
+            "--replay" | "-r" => {
+                let file = args.next().ok_or("--replay needs a capture file")?;
+                replay = Some(ReplayConfig::new(file));
+            }
+            "--speed" => {
+                let speed = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or("--speed needs a number")?;
+                replay.as_mut().ok_or("--speed needs --replay first")?.speed = speed;
+            }
+            "--once" => replay.as_mut().ok_or("--once needs --replay first")?.repeat = false,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            other if map.is_none() && !other.starts_with('-') => map = Some(PathBuf::from(other)),
+            other => return Err(format!("unknown argument {}\n{}", other, USAGE)),
+        }
+    }
+    Ok(CliArgs { map, listen, replay })
+}
+
+/// Turns one stdin line into a fault, or runs it directly.
+fn command(simulator: &Simulator, line: &str) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(());
+    };
+    let number = |words: &mut std::str::SplitWhitespace| {
+        words
+            .next()
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or(format!("{} needs a number", name))
+    };
+    let fault = match name {
+        "latency" => Fault::Latency {
+            ms: number(&mut words)?,
+        },
+        "exception" => Fault::Exception {
+            code: u8::try_from(number(&mut words)?).map_err(|e| e.to_string())?,
+        },
+        "noresponse" => Fault::NoResponse,
+        "disconnect" => Fault::Disconnect,
+        "drop" => {
+            simulator.disconnect_all();
+            return Ok(());
+        }
+        "clear" => {
+            simulator.clear_faults();
+            info!("Cleared all faults");
+            return Ok(());
+        }
+        other => return Err(format!("unknown command {}; {}", other, COMMANDS)),
+    };
+    simulator.inject(FaultRule::new(fault));
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut map = match &args.map {
+        Some(path) => match RegisterMap::load(path) {
+            Ok(map) => map,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => RegisterMap::default(),
+    };
+    if let Some(listen) = args.listen {
+        map.listen = listen;
+    }
+    if let Some(replay) = args.replay {
+        // A bare capture replays permissively, like the site's PLC would
+        // answer addresses the agent never asked for.
+        if args.map.is_none() {
+            map.strict = false;
+        }
+        map.replay = Some(replay);
+    }
+
+    let simulator = match Simulator::start(map).await {
+        Ok(simulator) => simulator,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("{}", COMMANDS);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    if let Err(e) = command(&simulator, line.trim()) {
+                        warn!("{}", e);
+                    }
+                }
+                // Without a terminal keep serving until interrupted.
+                Ok(None) | Err(_) => {
+                    let _ = tokio::signal::ctrl_c().await;
+                    break;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    info!("Simulator stopped");
+}
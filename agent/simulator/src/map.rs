@@ -0,0 +1,162 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::fault::FaultRule;
+use crate::generator::GeneratorConfig;
+use crate::replay::ReplayConfig;
+use crate::SimError;
+
+/// The four Modbus data tables.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    HoldingRegisters,
+    InputRegisters,
+}
+
+impl Table {
+    pub fn is_bit(&self) -> bool {
+        matches!(self, Table::Coils | Table::DiscreteInputs)
+    }
+}
+
+/// One address, or a block of `count` addresses sharing a value source.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Point {
+    pub table: Table,
+    pub address: u16,
+    #[serde(default = "default_count")]
+    pub count: u16,
+    /// Initial value; any non-zero value is `true` for bit tables.
+    #[serde(default)]
+    pub value: u16,
+    #[serde(default)]
+    pub generator: Option<GeneratorConfig>,
+}
+
+fn default_count() -> u16 {
+    1
+}
+
+impl Point {
+    pub fn new(table: Table, address: u16, value: u16) -> Self {
+        Self {
+            table,
+            address,
+            count: 1,
+            value,
+            generator: None,
+        }
+    }
+
+    pub fn with_generator(mut self, generator: GeneratorConfig) -> Self {
+        self.generator = Some(generator);
+        self
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RegisterMap {
+    pub listen: String,
+    /// Only this unit id is served; others get a gateway-target exception.
+    pub unit_id: Option<u8>,
+    /// How often generators produce a new value, in milliseconds.
+    pub tick_ms: u64,
+    /// Unmapped addresses answer illegal-data-address instead of zero.
+    pub strict: bool,
+    /// Seed for random generators and fault probabilities.
+    pub seed: Option<u64>,
+    pub points: Vec<Point>,
+    pub faults: Vec<FaultRule>,
+    /// Answers recorded requests from an agent capture; anything not in
+    /// the capture falls through to `points`.
+    pub replay: Option<ReplayConfig>,
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:5020".to_string(),
+            unit_id: None,
+            tick_ms: 100,
+            strict: true,
+            seed: None,
+            points: Vec::new(),
+            faults: Vec::new(),
+            replay: None,
+        }
+    }
+}
+
+impl RegisterMap {
+    /// Reads a TOML map. Relative CSV and capture paths resolve against
+    /// the map's directory.
+    pub fn load(path: &Path) -> Result<Self, SimError> {
+        let raw = fs::read_to_string(path).map_err(|source| SimError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        let mut map = Self::parse(&raw)?;
+        if let Some(dir) = path.parent() {
+            for point in &mut map.points {
+                if let Some(GeneratorConfig::Csv { file, .. }) = &mut point.generator {
+                    if file.is_relative() {
+                        *file = dir.join(&*file);
+                    }
+                }
+            }
+            if let Some(replay) = &mut map.replay {
+                if replay.file.is_relative() {
+                    replay.file = dir.join(&replay.file);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self, SimError> {
+        let map: Self = toml::from_str(raw).map_err(|e| SimError::Map(e.to_string()))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    /// A map listening on an ephemeral localhost port, for tests.
+    pub fn local() -> Self {
+        Self {
+            listen: "127.0.0.1:0".to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn point(mut self, point: Point) -> Self {
+        self.points.push(point);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), SimError> {
+        if self.tick_ms == 0 {
+            return Err(SimError::Map("tick_ms must be greater than 0".to_string()));
+        }
+        for point in &self.points {
+            if point.count == 0 || point.address.checked_add(point.count - 1).is_none() {
+                return Err(SimError::Map(format!(
+                    "{:?} {}: count must be between 1 and the end of the table",
+                    point.table, point.address
+                )));
+            }
+        }
+        for rule in &self.faults {
+            if !(0.0..=1.0).contains(&rule.probability) {
+                return Err(SimError::Map(format!(
+                    "fault probability {} is not between 0 and 1",
+                    rule.probability
+                )));
+            }
+        }
+        Ok(())
+    }
+}
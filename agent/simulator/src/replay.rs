@@ -0,0 +1,151 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::SimError;
+
+/// Serves the responses recorded in an agent capture file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReplayConfig {
+    pub file: PathBuf,
+    /// Playback rate; 2.0 replays an hour of traffic in 30 minutes.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Starts over after the last record instead of holding it.
+    #[serde(default = "default_repeat")]
+    pub repeat: bool,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+impl ReplayConfig {
+    pub fn new(file: impl Into<PathBuf>) -> Self {
+        Self {
+            file: file.into(),
+            speed: 1.0,
+            repeat: true,
+        }
+    }
+}
+
+/// A line of the capture file, as written by the agent.
+#[derive(Deserialize)]
+struct Record {
+    ts_ms: i64,
+    function: u8,
+    address: u16,
+    #[serde(default)]
+    value: Option<u16>,
+    #[serde(default)]
+    exception: Option<u8>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    round_trip_ms: u64,
+}
+
+/// What the device did with a recorded request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recorded {
+    Value(u16),
+    Exception(u8),
+    /// The agent gave up waiting.
+    Timeout,
+    /// The link failed mid-request.
+    Failed,
+}
+
+struct Entry {
+    at_ms: u64,
+    recorded: Recorded,
+    round_trip: Duration,
+}
+
+pub struct Replay {
+    /// Entries per function code and address, oldest first.
+    timelines: HashMap<(u8, u16), Vec<Entry>>,
+    length_ms: u64,
+    speed: f64,
+    repeat: bool,
+}
+
+impl Replay {
+    pub fn load(config: &ReplayConfig) -> Result<Self, SimError> {
+        if config.speed.is_nan() || config.speed <= 0.0 {
+            return Err(SimError::Map("replay speed must be greater than 0".to_string()));
+        }
+        let path = &config.file;
+        let raw = fs::read_to_string(path).map_err(|source| SimError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        let mut records = Vec::new();
+        for (i, line) in raw.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(line).map_err(|e| {
+                SimError::Map(format!("{}:{}: {}", path.display(), i + 1, e))
+            })?;
+            records.push(record);
+        }
+        let Some(first) = records.iter().map(|r| r.ts_ms).min() else {
+            return Err(SimError::Map(format!("{}: no records", path.display())));
+        };
+
+        let mut timelines: HashMap<(u8, u16), Vec<Entry>> = HashMap::new();
+        let mut length_ms = 0;
+        for record in records {
+            let at_ms = (record.ts_ms - first) as u64;
+            length_ms = length_ms.max(at_ms);
+            let recorded = match (record.value, record.exception, record.error.as_deref()) {
+                (_, Some(code), _) => Recorded::Exception(code),
+                (_, _, Some("request_timeout")) => Recorded::Timeout,
+                (_, _, Some(_)) => Recorded::Failed,
+                (Some(value), _, _) => Recorded::Value(value),
+                (None, None, None) => Recorded::Failed,
+            };
+            timelines
+                .entry((record.function, record.address))
+                .or_default()
+                .push(Entry {
+                    at_ms,
+                    recorded,
+                    round_trip: Duration::from_millis(record.round_trip_ms),
+                });
+        }
+        for timeline in timelines.values_mut() {
+            timeline.sort_by_key(|entry| entry.at_ms);
+        }
+
+        Ok(Self {
+            timelines,
+            length_ms,
+            speed: config.speed,
+            repeat: config.repeat,
+        })
+    }
+
+    /// The latest recorded answer to this request at `elapsed` into the
+    /// playback, and how long the device took to give it. Requests that
+    /// were never recorded return `None`.
+    pub fn lookup(&self, function: u8, address: u16, elapsed: Duration) -> Option<(Recorded, Duration)> {
+        let timeline = self.timelines.get(&(function, address))?;
+        let mut at = (elapsed.as_millis() as f64 * self.speed) as u64;
+        if self.repeat {
+            at %= self.length_ms + 1;
+        }
+        let index = timeline.partition_point(|entry| entry.at_ms <= at);
+        let entry = &timeline[index.saturating_sub(1)];
+        Some((entry.recorded, entry.round_trip.div_f64(self.speed)))
+    }
+}
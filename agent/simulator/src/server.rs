@@ -0,0 +1,475 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::fault::{self, Action, FaultRule};
+use crate::generator::Generator;
+use crate::map::{RegisterMap, Table};
+use crate::replay::{Recorded, Replay};
+use crate::SimError;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Largest PDU a Modbus TCP frame can carry.
+const MAX_PDU: usize = 253;
+
+struct Cell {
+    value: u16,
+    generator: Option<Generator>,
+}
+
+struct Shared {
+    tables: HashMap<Table, BTreeMap<u16, Cell>>,
+    faults: Vec<FaultRule>,
+    rng: StdRng,
+    strict: bool,
+    unit_id: Option<u8>,
+    requests: u64,
+    replay: Option<Replay>,
+    started: Instant,
+}
+
+/// A running simulated slave. Dropping it stops serving and closes every
+/// connection.
+pub struct Simulator {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    disconnect: watch::Sender<u64>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Simulator {
+    /// Binds the map's listen address and starts serving and ticking
+    /// generators.
+    pub async fn start(map: RegisterMap) -> Result<Self, SimError> {
+        map.validate()?;
+        let started = Instant::now();
+        let mut rng = match map.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let mut tables: HashMap<Table, BTreeMap<u16, Cell>> = HashMap::new();
+        for point in &map.points {
+            let table = tables.entry(point.table).or_default();
+            for address in point.address..=point.address + (point.count - 1) {
+                let mut generator = point.generator.as_ref().map(Generator::new).transpose()?;
+                let value = match &mut generator {
+                    Some(generator) => generator.sample(Duration::ZERO, &mut rng),
+                    None => point.value,
+                };
+                table.insert(
+                    address,
+                    Cell {
+                        value: normalize(point.table, value),
+                        generator,
+                    },
+                );
+            }
+        }
+
+        let replay = map.replay.as_ref().map(Replay::load).transpose()?;
+
+        let listener = TcpListener::bind(&map.listen)
+            .await
+            .map_err(|source| SimError::Listen {
+                addr: map.listen.clone(),
+                source,
+            })?;
+        let addr = listener.local_addr().map_err(|source| SimError::Listen {
+            addr: map.listen.clone(),
+            source,
+        })?;
+
+        let shared = Arc::new(Mutex::new(Shared {
+            tables,
+            faults: map.faults,
+            rng,
+            strict: map.strict,
+            unit_id: map.unit_id,
+            requests: 0,
+            replay,
+            started,
+        }));
+        let (disconnect, _) = watch::channel(0);
+
+        let tick = tokio::spawn(tick(
+            shared.clone(),
+            Duration::from_millis(map.tick_ms),
+            started,
+        ));
+        let accept = tokio::spawn(accept(listener, shared.clone(), disconnect.subscribe()));
+        info!(%addr, "Simulator listening");
+
+        Ok(Self {
+            addr,
+            shared,
+            disconnect,
+            tasks: vec![tick, accept],
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Adds a fault rule on top of those from the map.
+    pub fn inject(&self, rule: FaultRule) {
+        info!(fault = ?rule.fault, "Injecting fault");
+        self.lock().faults.push(rule);
+    }
+
+    pub fn clear_faults(&self) {
+        self.lock().faults.clear();
+    }
+
+    /// Sets a value and stops its generator, as a write from a client does.
+    pub fn set(&self, table: Table, address: u16, value: u16) {
+        self.lock().tables.entry(table).or_default().insert(
+            address,
+            Cell {
+                value: normalize(table, value),
+                generator: None,
+            },
+        );
+    }
+
+    pub fn get(&self, table: Table, address: u16) -> Option<u16> {
+        self.lock()
+            .tables
+            .get(&table)
+            .and_then(|t| t.get(&address))
+            .map(|cell| cell.value)
+    }
+
+    /// Requests received so far, including those answered with a fault.
+    pub fn request_count(&self) -> u64 {
+        self.lock().requests
+    }
+
+    /// Closes every open connection; new ones are still accepted.
+    pub fn disconnect_all(&self) {
+        info!("Disconnecting all clients");
+        self.disconnect.send_modify(|generation| *generation += 1);
+    }
+
+    pub fn shutdown(self) {}
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.disconnect.send_modify(|generation| *generation += 1);
+    }
+}
+
+fn normalize(table: Table, value: u16) -> u16 {
+    if table.is_bit() {
+        u16::from(value != 0)
+    } else {
+        value
+    }
+}
+
+async fn tick(shared: Arc<Mutex<Shared>>, period: Duration, started: Instant) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let elapsed = started.elapsed();
+        let mut guard = shared.lock().unwrap_or_else(|e| e.into_inner());
+        let Shared { tables, rng, .. } = &mut *guard;
+        for (table, cells) in tables.iter_mut() {
+            for cell in cells.values_mut() {
+                if let Some(generator) = &mut cell.generator {
+                    cell.value = normalize(*table, generator.sample(elapsed, rng));
+                }
+            }
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Mutex<Shared>>, disconnect: watch::Receiver<u64>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!(%peer, "Client connected");
+                let mut disconnect = disconnect.clone();
+                disconnect.mark_unchanged();
+                tokio::spawn(serve(stream, peer, shared.clone(), disconnect));
+            }
+            Err(e) => warn!("Failed to accept a connection: {}", e),
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    mut disconnect: watch::Receiver<u64>,
+) {
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut stream) => frame,
+            _ = disconnect.changed() => break,
+        };
+        let Some((header, pdu)) = frame else {
+            break;
+        };
+
+        let (delay, action, reply) = {
+            let mut guard = shared.lock().unwrap_or_else(|e| e.into_inner());
+            respond(&mut guard, header[6], &pdu)
+        };
+        if !delay.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = disconnect.changed() => break,
+            }
+        }
+        let reply = match action {
+            Action::Reply => reply,
+            Action::Exception(code) => exception(pdu[0], code),
+            Action::NoResponse => {
+                debug!(%peer, "Dropping request without a response");
+                continue;
+            }
+            Action::Disconnect => {
+                debug!(%peer, "Closing connection as injected");
+                break;
+            }
+        };
+
+        let mut frame = Vec::with_capacity(7 + reply.len());
+        frame.extend_from_slice(&header[..4]);
+        frame.extend_from_slice(&(reply.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&reply);
+        if stream.write_all(&frame).await.is_err() {
+            break;
+        }
+    }
+    debug!(%peer, "Client disconnected");
+}
+
+/// Reads one MBAP header and its PDU; `None` once the peer hangs up or
+/// sends something that is not Modbus TCP.
+async fn read_frame(stream: &mut TcpStream) -> Option<([u8; 7], Vec<u8>)> {
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header).await.ok()?;
+    let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+    if header[2..4] != [0, 0] || !(2..=MAX_PDU + 1).contains(&length) {
+        return None;
+    }
+    let mut pdu = vec![0u8; length - 1];
+    stream.read_exact(&mut pdu).await.ok()?;
+    Some((header, pdu))
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+fn word(pdu: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*pdu.get(at)?, *pdu.get(at + 1)?]))
+}
+
+/// Decides the fault and builds the normal reply for one request.
+fn respond(shared: &mut Shared, unit: u8, pdu: &[u8]) -> (Duration, Action, Vec<u8>) {
+    shared.requests += 1;
+    let function = pdu[0];
+    let first = word(pdu, 1).unwrap_or(0);
+    let last = match function {
+        1..=4 | 15 | 16 => first.saturating_add(word(pdu, 3).unwrap_or(1).saturating_sub(1)),
+        _ => first,
+    };
+    let Shared { faults, rng, .. } = shared;
+    let (delay, action) = fault::resolve(faults, function, first, last, rng);
+
+    if shared.unit_id.is_some_and(|id| id != unit) {
+        return (delay, action, exception(function, GATEWAY_TARGET_FAILED));
+    }
+    let elapsed = shared.started.elapsed();
+    let recorded = shared
+        .replay
+        .as_ref()
+        .and_then(|replay| replay.lookup(function, first, elapsed));
+    if let Some((recorded, round_trip)) = recorded {
+        // Injected faults still win over the recording.
+        let action = match (action, recorded) {
+            (Action::Reply, Recorded::Value(value)) => {
+                return (delay + round_trip, Action::Reply, replayed(pdu, value));
+            }
+            (Action::Reply, Recorded::Exception(code)) => Action::Exception(code),
+            (Action::Reply, Recorded::Timeout) => Action::NoResponse,
+            (Action::Reply, Recorded::Failed) => Action::Disconnect,
+            (action, _) => action,
+        };
+        return (delay + round_trip, action, Vec::new());
+    }
+    let reply = match handle(shared, pdu) {
+        Ok(reply) => reply,
+        Err(code) => exception(function, code),
+    };
+    (delay, action, reply)
+}
+
+/// Answers with a recorded value at the first address. Recordings only
+/// keep the first value of a read, so the rest of the range reads as zero.
+fn replayed(pdu: &[u8], value: u16) -> Vec<u8> {
+    let function = pdu[0];
+    let count = usize::from(word(pdu, 3).unwrap_or(1).clamp(1, 125));
+    match function {
+        1 | 2 => {
+            let mut reply = vec![function, count.div_ceil(8) as u8];
+            reply.extend(std::iter::repeat_n(0, count.div_ceil(8)));
+            reply[2] = u8::from(value != 0);
+            reply
+        }
+        3 | 4 => {
+            let mut reply = vec![function, (count * 2) as u8];
+            reply.extend(value.to_be_bytes());
+            reply.extend(std::iter::repeat_n(0, (count - 1) * 2));
+            reply
+        }
+        _ => pdu[..pdu.len().min(5)].to_vec(),
+    }
+}
+
+fn handle(shared: &mut Shared, pdu: &[u8]) -> Result<Vec<u8>, u8> {
+    let function = pdu[0];
+    let table = match function {
+        1 | 5 | 15 => Table::Coils,
+        2 => Table::DiscreteInputs,
+        3 | 6 | 16 => Table::HoldingRegisters,
+        4 => Table::InputRegisters,
+        _ => return Err(ILLEGAL_FUNCTION),
+    };
+    let address = word(pdu, 1).ok_or(ILLEGAL_DATA_VALUE)?;
+
+    match function {
+        1 | 2 => {
+            let count = word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)?;
+            if !(1..=2000).contains(&count) {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            let values = shared.read(table, address, count)?;
+            let mut bytes = vec![0u8; values.len().div_ceil(8)];
+            for (i, value) in values.iter().enumerate() {
+                if *value != 0 {
+                    bytes[i / 8] |= 1 << (i % 8);
+                }
+            }
+            let mut reply = vec![function, bytes.len() as u8];
+            reply.extend(bytes);
+            Ok(reply)
+        }
+        3 | 4 => {
+            let count = word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)?;
+            if !(1..=125).contains(&count) {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            let values = shared.read(table, address, count)?;
+            let mut reply = vec![function, (values.len() * 2) as u8];
+            reply.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+            Ok(reply)
+        }
+        5 => {
+            let value = match word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)? {
+                0xFF00 => 1,
+                0x0000 => 0,
+                _ => return Err(ILLEGAL_DATA_VALUE),
+            };
+            shared.write(table, address, &[value])?;
+            Ok(pdu[..5].to_vec())
+        }
+        6 => {
+            let value = word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)?;
+            shared.write(table, address, &[value])?;
+            Ok(pdu[..5].to_vec())
+        }
+        15 => {
+            let count = word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)?;
+            let bytes = pdu.get(6..).ok_or(ILLEGAL_DATA_VALUE)?;
+            if !(1..=1968).contains(&count) || bytes.len() < usize::from(count).div_ceil(8) {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            let values: Vec<u16> = (0..usize::from(count))
+                .map(|i| u16::from(bytes[i / 8] & (1 << (i % 8)) != 0))
+                .collect();
+            shared.write(table, address, &values)?;
+            Ok(pdu[..5].to_vec())
+        }
+        16 => {
+            let count = word(pdu, 3).ok_or(ILLEGAL_DATA_VALUE)?;
+            let bytes = pdu.get(6..).ok_or(ILLEGAL_DATA_VALUE)?;
+            if !(1..=123).contains(&count) || bytes.len() < usize::from(count) * 2 {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            let values: Vec<u16> = bytes
+                .chunks_exact(2)
+                .take(usize::from(count))
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            shared.write(table, address, &values)?;
+            Ok(pdu[..5].to_vec())
+        }
+        _ => Err(ILLEGAL_FUNCTION),
+    }
+}
+
+impl Shared {
+    fn addresses(address: u16, count: u16) -> Result<std::ops::RangeInclusive<u16>, u8> {
+        let last = address
+            .checked_add(count - 1)
+            .ok_or(ILLEGAL_DATA_ADDRESS)?;
+        Ok(address..=last)
+    }
+
+    fn read(&self, table: Table, address: u16, count: u16) -> Result<Vec<u16>, u8> {
+        let cells = self.tables.get(&table);
+        Self::addresses(address, count)?
+            .map(|a| match cells.and_then(|c| c.get(&a)) {
+                Some(cell) => Ok(cell.value),
+                None if self.strict => Err(ILLEGAL_DATA_ADDRESS),
+                None => Ok(0),
+            })
+            .collect()
+    }
+
+    /// Written values stay until the next write; their generators stop.
+    fn write(&mut self, table: Table, address: u16, values: &[u16]) -> Result<(), u8> {
+        let range = Self::addresses(address, values.len() as u16)?;
+        let cells = self.tables.entry(table).or_default();
+        if self.strict && range.clone().any(|a| !cells.contains_key(&a)) {
+            return Err(ILLEGAL_DATA_ADDRESS);
+        }
+        for (a, value) in range.zip(values) {
+            cells.insert(
+                a,
+                Cell {
+                    value: normalize(table, *value),
+                    generator: None,
+                },
+            );
+        }
+        Ok(())
+    }
+}
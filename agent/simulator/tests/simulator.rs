@@ -0,0 +1,156 @@
+use simulator::{Fault, FaultRule, GeneratorConfig, Point, RegisterMap, Simulator, Table};
+use std::time::Duration;
+use tokio_modbus::client::{tcp, Context, Reader, Writer};
+use tokio_modbus::ExceptionCode;
+
+async fn connect(simulator: &Simulator) -> Context {
+    tcp::connect(simulator.local_addr()).await.expect("simulator accepts connections")
+}
+
+fn map() -> RegisterMap {
+    RegisterMap::local()
+        .point(Point::new(Table::Coils, 0, 1))
+        .point(Point::new(Table::DiscreteInputs, 0, 0))
+        .point(Point {
+            count: 4,
+            ..Point::new(Table::HoldingRegisters, 10, 7)
+        })
+        .point(Point::new(Table::InputRegisters, 3, 42))
+}
+
+#[tokio::test]
+async fn serves_all_four_tables() {
+    let simulator = Simulator::start(map()).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    assert_eq!(ctx.read_coils(0, 1).await.unwrap().unwrap(), vec![true]);
+    assert_eq!(ctx.read_discrete_inputs(0, 1).await.unwrap().unwrap(), vec![false]);
+    assert_eq!(ctx.read_holding_registers(10, 4).await.unwrap().unwrap(), vec![7; 4]);
+    assert_eq!(ctx.read_input_registers(3, 1).await.unwrap().unwrap(), vec![42]);
+
+    ctx.write_single_register(11, 99).await.unwrap().unwrap();
+    ctx.write_multiple_registers(12, &[1, 2]).await.unwrap().unwrap();
+    ctx.write_single_coil(0, false).await.unwrap().unwrap();
+    assert_eq!(ctx.read_holding_registers(10, 4).await.unwrap().unwrap(), vec![7, 99, 1, 2]);
+    assert_eq!(simulator.get(Table::Coils, 0), Some(0));
+}
+
+#[tokio::test]
+async fn strict_map_rejects_unmapped_addresses() {
+    let simulator = Simulator::start(map()).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    let result = ctx.read_holding_registers(12, 4).await.unwrap();
+    assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+    let result = ctx.write_single_register(500, 1).await.unwrap();
+    assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+}
+
+#[tokio::test]
+async fn writes_pin_generated_values() {
+    let map = RegisterMap {
+        tick_ms: 10,
+        ..RegisterMap::local()
+    }
+    .point(Point::new(Table::HoldingRegisters, 0, 0).with_generator(GeneratorConfig::Ramp {
+        min: 0,
+        max: 1000,
+        period_ms: 50,
+    }));
+    let simulator = Simulator::start(map).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    ctx.write_single_register(0, 1234).await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(ctx.read_holding_registers(0, 1).await.unwrap().unwrap(), vec![1234]);
+}
+
+#[tokio::test]
+async fn injected_exception_applies_to_matching_requests() {
+    let simulator = Simulator::start(map()).await.unwrap();
+    simulator.inject(
+        FaultRule::new(Fault::Exception { code: 6 })
+            .function(3)
+            .addresses(10, 10)
+            .times(1),
+    );
+    let mut ctx = connect(&simulator).await;
+
+    assert_eq!(ctx.read_input_registers(3, 1).await.unwrap(), Ok(vec![42]));
+    let result = ctx.read_holding_registers(10, 1).await.unwrap();
+    assert_eq!(result, Err(ExceptionCode::ServerDeviceBusy));
+    assert_eq!(ctx.read_holding_registers(10, 1).await.unwrap(), Ok(vec![7]));
+}
+
+#[tokio::test]
+async fn latency_and_no_response_delay_the_client() {
+    let simulator = Simulator::start(map()).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    simulator.inject(FaultRule::new(Fault::Latency { ms: 200 }).times(1));
+    let late = tokio::time::timeout(Duration::from_millis(50), ctx.read_coils(0, 1)).await;
+    assert!(late.is_err());
+
+    let mut ctx = connect(&simulator).await;
+    simulator.inject(FaultRule::new(Fault::NoResponse).times(1));
+    let lost = tokio::time::timeout(Duration::from_millis(200), ctx.read_coils(0, 1)).await;
+    assert!(lost.is_err());
+}
+
+#[tokio::test]
+async fn disconnect_fault_closes_the_connection() {
+    let simulator = Simulator::start(map()).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    simulator.inject(FaultRule::new(Fault::Disconnect).times(1));
+    assert!(ctx.read_coils(0, 1).await.is_err());
+
+    let mut ctx = connect(&simulator).await;
+    assert_eq!(ctx.read_coils(0, 1).await.unwrap(), Ok(vec![true]));
+    assert_eq!(simulator.request_count(), 2);
+    simulator.disconnect_all();
+    assert!(ctx.read_coils(0, 1).await.is_err());
+}
+
+#[test]
+fn parses_the_bundled_maps() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("maps");
+    for name in ["default.toml", "field.toml"] {
+        RegisterMap::load(&dir.join(name)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+    }
+}
+
+#[tokio::test]
+async fn replays_a_capture_in_time() {
+    let capture = std::env::temp_dir().join(format!("sim-replay-{}.jsonl", std::process::id()));
+    std::fs::write(
+        &capture,
+        concat!(
+            r#"{"ts_ms":1000,"device":"plc","unit_id":1,"function":3,"address":5,"count":1,"value":10,"round_trip_ms":2}"#,
+            "\n",
+            r#"{"ts_ms":1300,"device":"plc","unit_id":1,"function":3,"address":5,"count":1,"exception":2,"round_trip_ms":2}"#,
+            "\n",
+            r#"{"ts_ms":1600,"device":"plc","unit_id":1,"function":3,"address":5,"count":1,"error":"request_timeout","round_trip_ms":1000}"#,
+            "\n",
+        ),
+    )
+    .unwrap();
+    let map = RegisterMap {
+        replay: Some(simulator::ReplayConfig {
+            repeat: false,
+            ..simulator::ReplayConfig::new(&capture)
+        }),
+        ..RegisterMap::local()
+    };
+    let simulator = Simulator::start(map).await.unwrap();
+    let mut ctx = connect(&simulator).await;
+
+    assert_eq!(ctx.read_holding_registers(5, 2).await.unwrap(), Ok(vec![10, 0]));
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    let result = ctx.read_holding_registers(5, 1).await.unwrap();
+    assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let lost = tokio::time::timeout(Duration::from_millis(200), ctx.read_holding_registers(5, 1)).await;
+    assert!(lost.is_err());
+    let _ = std::fs::remove_file(&capture);
+}
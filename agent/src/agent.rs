@@ -1,81 +1,336 @@
-use rust_socketio::asynchronous::Client;
 use serde::Serialize;
-use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio_modbus::client::Context;
+use tracing::{debug, error, info, warn};
 
-use crate::config::{SensorConfig, MONITOR_INTERVAL_MS};
+use crate::config::{AgentConfig, DeviceConfig, SensorConfig};
+use crate::device::{DeviceCommand, DeviceHandle, Pending};
+use crate::health::{self, HostMonitor};
 use crate::helper::AppError;
-use crate::monitoring::monitor_plc_loop;
-use crate::plc_io;
+use crate::logging::{self, LogHandle};
+use crate::metrics::metrics;
+use crate::reload::{self, Reloads};
 use crate::state::SharedState;
+use crate::uplink::Uplink;
 use crate::ChEvent;
 
+/// A write from a local client, such as the OPC UA server, answered once the
+/// PLC has acknowledged it.
+#[derive(Debug)]
+pub struct LocalWrite {
+    pub device: String,
+    pub reg: u16,
+    pub val: u16,
+    pub r_type: String,
+    pub reply: oneshot::Sender<WriteOutcome>,
+}
+
+#[derive(Debug)]
+pub enum WriteOutcome {
+    Written,
+    /// The agent is paused.
+    Locked,
+    /// The write ACL does not allow it.
+    Denied,
+    Failed(AppError),
+}
+
+/// Command dispatcher. Owned by the main loop; PLC I/O happens in the device
+/// actors and outbound events go through the uplink, so nothing here is
+/// ever locked across a Modbus round trip.
 pub struct Agent {
-    pub slave_ctx: Arc<Mutex<tokio_modbus::client::Context>>,
+    /// Device actors keyed by device name.
+    pub devices: HashMap<String, DeviceHandle>,
+    /// Running actor per device, awaited on removal and shutdown.
+    pub device_tasks: HashMap<String, JoinHandle<()>>,
     pub event: ChEvent,
-    pub socket_io: Client,
+    pub uplink: Uplink,
     pub state: Arc<Mutex<SharedState>>,
+    pub log_handle: LogHandle,
+    pub config: AgentConfig,
+    /// Writes from local clients, checked like backend writes.
+    pub local_writes: Option<mpsc::Receiver<LocalWrite>>,
+    /// Configuration reloads whose devices are being connected.
+    pub reloads: Reloads,
+    host: HostMonitor,
 }
 
 impl Agent {
     pub fn new(
-        slave_ctx: Arc<Mutex<tokio_modbus::client::Context>>,
+        mut connections: HashMap<String, Context>,
         event: ChEvent,
-        socket_io: Client,
+        uplink: Uplink,
         state: Arc<Mutex<SharedState>>,
+        log_handle: LogHandle,
+        config: AgentConfig,
     ) -> Self {
-        Self {
-            slave_ctx,
+        let mut agent = Self {
+            devices: HashMap::new(),
+            device_tasks: HashMap::new(),
             event,
-            socket_io,
+            uplink,
             state,
+            log_handle,
+            config,
+            local_writes: None,
+            reloads: Reloads::new(),
+            host: HostMonitor::new(),
+        };
+        for device in agent.config.devices.clone() {
+            if let Some(ctx) = connections.remove(&device.name) {
+                agent.spawn_device(device, ctx);
+            }
         }
+        agent
     }
 
-    pub async fn handle_master_event(&mut self) -> Result<(), AppError> {
-        match &self.event {
-            ChEvent::CleanUp => {
-                let mut state = self.state.lock().await;
-                if state.paused_agent {
-                    self.send_message("agent_locked", "Agent is locked").await?;
-                    return Ok(());
+    /// Also takes writes from `rx`.
+    pub fn with_local_writes(mut self, rx: mpsc::Receiver<LocalWrite>) -> Self {
+        self.local_writes = Some(rx);
+        self
+    }
+
+    /// Starts the actor that owns `ctx` and polls its sensors.
+    pub fn spawn_device(&mut self, device: DeviceConfig, ctx: Context) {
+        info!(device = %device.name, "Starting device actor");
+        let name = device.name.clone();
+        let is_default = self.config.default_device() == name;
+        let (handle, task) = DeviceHandle::spawn(
+            device,
+            ctx,
+            self.config.poll.clone(),
+            is_default,
+            self.state.clone(),
+            self.uplink.clone(),
+        );
+        self.devices.insert(name.clone(), handle);
+        self.device_tasks.insert(name, task);
+    }
+
+    /// Pushes the current poll settings and default device to every actor,
+    /// and the default device to the shared state.
+    pub async fn configure_devices(&self) {
+        self.state.lock().await.default_device = self.config.default_device().to_string();
+        for (name, device) in &self.devices {
+            let command = DeviceCommand::Configure {
+                settings: self.config.poll.clone(),
+                is_default: self.config.default_device() == name,
+            };
+            if let Err(e) = device.send(command).await {
+                error!(device = %name, "Failed to configure device: {}", e);
+            }
+        }
+    }
+
+    /// Stops the actor after the commands already queued to it.
+    pub async fn remove_device(&mut self, name: &str) {
+        if let Some(device) = self.devices.remove(name) {
+            device.shutdown().await;
+        }
+        if let Some(task) = self.device_tasks.remove(name) {
+            let _ = task.await;
+        }
+        self.state.lock().await.devices.remove(name);
+    }
+
+    /// Handles events until `shutdown` resolves or the channel closes, and
+    /// returns the reason. Events are handled outside the select so a
+    /// signal never interrupts a write halfway through.
+    pub async fn run(
+        &mut self,
+        rx: &mut mpsc::Receiver<ChEvent>,
+        config_path: &Path,
+        shutdown: impl Future<Output = &'static str>,
+    ) -> &'static str {
+        tokio::pin!(shutdown);
+        loop {
+            // A signal wins over queued events; shutdown still runs the
+            // queued PLC commands.
+            let event = tokio::select! {
+                biased;
+                reason = &mut shutdown => return reason,
+                Some(write) = next_local_write(&mut self.local_writes) => {
+                    self.local_write(write).await;
+                    continue;
                 }
-                state.cleanup_sensors();
+                Some(prepared) = self.reloads.prepared() => {
+                    reload::finish(self, config_path, prepared).await;
+                    continue;
+                }
+                event = rx.recv() => event,
+            };
+            let Some(event) = event else {
+                return "event channel closed";
+            };
+            metrics().event_queue_depth.set(rx.len() as i64);
+            if matches!(event, ChEvent::ReloadConfig | ChEvent::ApplyConfig { .. }) {
+                reload::request(self, config_path, event).await;
+                continue;
             }
-            ChEvent::HealthCheck => {
-                let state = self.state.lock().await;
-                println!("reciebved helath signal");
-                self.send_json("health_check", &state.registered_sensors)
-                    .await?;
+
+            self.event = event;
+            debug!("Processing event: {:?}", self.event);
+
+            if let Err(e) = self.handle_master_event().await {
+                error!(code = ?e.code(), "Error handling event: {}", e);
+                self.uplink.command_failed(self.event.name(), &e).await;
+            }
+        }
+    }
+
+    /// Applies the pause lock and the running write ACL, then queues the write
+    /// and answers once the PLC has.
+    pub async fn local_write(&self, write: LocalWrite) {
+        let LocalWrite {
+            device,
+            reg,
+            val,
+            r_type,
+            reply,
+        } = write;
+        info!(device = %device, reg, val, r_type = %r_type, "Received local write");
+        if self.state.lock().await.paused_agent {
+            let _ = reply.send(WriteOutcome::Locked);
+            return;
+        }
+        if !self.config.acl.allows_write(&device, &r_type, reg) {
+            warn!(device = %device, reg, r_type = %r_type, "Local write is not allowed by the write ACL");
+            let _ = reply.send(WriteOutcome::Denied);
+            return;
+        }
+
+        let pending = match self.device(Some(&device)) {
+            Ok((_, handle)) => handle.write(reg, val, r_type).await,
+            Err(e) => Err(e),
+        };
+        match pending {
+            Ok(pending) => {
+                tokio::spawn(async move {
+                    let outcome = match pending.result().await {
+                        Ok(()) => WriteOutcome::Written,
+                        Err(e) => {
+                            error!(device = %device, reg, "Failed to write to PLC: {}", e);
+                            WriteOutcome::Failed(e)
+                        }
+                    };
+                    let _ = reply.send(outcome);
+                });
+            }
+            Err(e) => {
+                let _ = reply.send(WriteOutcome::Failed(e));
             }
+        }
+    }
+
+    /// Resolves an optional device name, falling back to the default device.
+    pub fn device(&self, device: Option<&str>) -> Result<(String, DeviceHandle), AppError> {
+        let name = device.unwrap_or(self.config.default_device());
+        self.devices
+            .get(name)
+            .map(|handle| (name.to_string(), handle.clone()))
+            .ok_or_else(|| AppError::ValidationError(format!("unknown device {}", name)))
+    }
+
+    /// Checks the current Stop or Write event against the pause lock and the
+    /// write ACL and queues it on its device. Returns `None` when it was
+    /// refused (the backend has been told why) or is not a PLC command.
+    pub async fn queue_command(&self) -> Result<Option<(String, Pending)>, AppError> {
+        match &self.event {
             ChEvent::Stop => {
-                println!("Received STOP event -> Stopping PLC.");
+                info!("Received STOP event -> Stopping PLC.");
                 if self.state.lock().await.paused_agent {
                     self.send_message("agent_locked", "Agent is locked").await?;
-                    return Ok(());
+                    return Ok(None);
                 }
 
-                let mut slave_ctx = self.slave_ctx.lock().await;
-                plc_io::stop_plc(&mut slave_ctx)
-                    .await
-                    .map_err(|e| AppError::PlcError(e.to_string()))?;
+                if !self.config.acl.allow_stop {
+                    self.send_message("write_denied", "Stop is not allowed by the write ACL")
+                        .await?;
+                    return Ok(None);
+                }
+
+                let (device, handle) = self.device(None)?;
+                let pending = handle.stop().await?;
+                Ok(Some((device, pending)))
             }
-            ChEvent::Write { reg, val, r_type } => {
-                println!(
-                    "Received WRITE event -> Writing {} to register {}.",
-                    val, reg
-                );
+            ChEvent::Write {
+                reg,
+                val,
+                r_type,
+                device,
+            } => {
+                info!(reg, val, r_type = %r_type, "Received WRITE event");
                 if self.state.lock().await.paused_agent {
                     self.send_message("agent_locked", "Agent is locked").await?;
-                    return Ok(());
+                    return Ok(None);
+                }
+
+                let (device, handle) = self.device(device.as_deref())?;
+                if !self.config.acl.allows_write(&device, r_type, *reg) {
+                    let message = format!("Write to {} {} on {} is not allowed", r_type, reg, device);
+                    self.send_message("write_denied", &message).await?;
+                    return Ok(None);
                 }
 
-                let mut slave_ctx = self.slave_ctx.lock().await;
-                plc_io::write_to_plc(&mut slave_ctx, *reg, *val, r_type.to_string())
-                    .await
-                    .map_err(|e| AppError::PlcError(e.to_string()))?;
+                let pending = handle.write(*reg, *val, r_type.to_string()).await?;
+                Ok(Some((device, pending)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn handle_master_event(&mut self) -> Result<(), AppError> {
+        match &self.event {
+            ChEvent::CleanUp => {
+                let mut state = self.state.lock().await;
+                if state.paused_agent {
+                    self.send_message("agent_locked", "Agent is locked").await?;
+                    return Ok(());
+                }
+                state.cleanup_sensors();
+            }
+            ChEvent::HealthCheck => {
+                debug!("Received health check");
+                // Answered from a task, so sampling the host never holds up
+                // the commands behind it.
+                let host = self.host.clone();
+                let state = self.state.clone();
+                let uplink = self.uplink.clone();
+                let config_version = self.config.version;
+                tokio::spawn(async move {
+                    let sent = match host.sample().await {
+                        Ok(host) => {
+                            let report =
+                                health::build_report(host, &*state.lock().await, config_version);
+                            uplink.send_json("health_check", &report).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
+                        error!("Failed to answer health check: {}", e);
+                        uplink.command_failed("HealthCheck", &e).await;
+                    }
+                });
+            }
+            ChEvent::Stop | ChEvent::Write { .. } => {
+                // Queued here so commands reach the actor in order; the actor
+                // runs them before its next read. Waiting for the result
+                // would hold up commands for other devices.
+                if let Some((device, pending)) = self.queue_command().await? {
+                    let command = self.event.name();
+                    let uplink = self.uplink.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = pending.result().await {
+                            error!(device = %device, "{} failed on the PLC: {}", command, e);
+                            uplink.command_failed(command, &e).await;
+                        }
+                    });
+                }
             }
             ChEvent::Wait => {}
             ChEvent::AddSensor {
@@ -85,9 +340,11 @@ impl Agent {
                 register,
                 start_register,
                 s_type,
-                r_type
+                r_type,
+                device,
             } => {
-                println!("Processing AddSensor: {}", id);
+                debug!(sensor_id = %id, "Processing AddSensor");
+                self.device(device.as_deref())?;
                 let new_sensor = SensorConfig {
                     id: id.to_string(),
                     label: label.to_string(),
@@ -96,6 +353,7 @@ impl Agent {
                     end_register: *end_register,
                     s_type: s_type.to_string(),
                     r_type: r_type.to_string(),
+                    device: device.clone(),
                 };
 
                 let mut state = self.state.lock().await;
@@ -120,8 +378,10 @@ impl Agent {
                 end_register,
                 register,
                 s_type,
-                r_type
+                r_type,
+                device,
             } => {
+                self.device(device.as_deref())?;
                 let mut state = self.state.lock().await;
                 if state.paused_agent {
                     self.send_message("agent_locked", "Agent is locked").await?;
@@ -135,7 +395,8 @@ impl Agent {
                     end_register: *end_register,
                     register: register.to_string(),
                     s_type: s_type.to_string(),
-                    r_type: r_type.to_string()
+                    r_type: r_type.to_string(),
+                    device: device.clone(),
                 });
             }
             ChEvent::PauseAgent => {
@@ -146,45 +407,35 @@ impl Agent {
                 } else {
                     "resumed"
                 };
-                println!("Agent {}", status);
+                info!("Agent {}", status);
                 self.send_message("agent_status", &format!("Agent {}", status))
                     .await?;
             }
+            // Need the configuration path, so the main loop routes them to
+            // `reload` instead.
+            ChEvent::ReloadConfig | ChEvent::ApplyConfig { .. } => {}
+            ChEvent::SetLogLevel { filter } => {
+                logging::set_filter(&self.log_handle, filter)?;
+                info!(filter = %filter, "Log filter changed");
+                self.send_message("log_level", filter).await?;
+            }
         }
         Ok(())
     }
 
     async fn send_message(&self, event: &str, message: &str) -> Result<(), AppError> {
-        self.socket_io
-            .emit(event, json!({ "message": message }))
-            .await
-            .map_err(|e| AppError::SocketIoError(e.to_string()))?;
-        Ok(())
+        self.uplink.send_message(event, message).await
     }
 
     pub async fn send_json<T: Serialize>(&self, event: &str, data: &T) -> Result<(), AppError> {
-        self.socket_io
-            .emit(event, serde_json::to_value(data).unwrap())
-            .await
-            .map_err(|e| AppError::SocketIoError(e.to_string()))?;
-        Ok(())
+        self.uplink.send_json(event, data).await
     }
+}
 
-    pub async fn start_monitoring(agent: Arc<Mutex<Self>>) -> Result<(), AppError> {
-        sleep(tokio::time::Duration::from_millis(MONITOR_INTERVAL_MS)).await;
-
-        println!("Starting PLC monitoring...");
-
-        // Clone the agent for the monitoring task
-        let monitoring_agent = agent.clone();
-
-        // Spawn a task for continuous monitoring
-        tokio::spawn(async move {
-            if let Err(e) = monitor_plc_loop(monitoring_agent).await {
-                eprintln!("Monitoring error: {}", e);
-            }
-        });
-
-        Ok(())
+/// Waits forever when there is no local writer.
+async fn next_local_write(rx: &mut Option<mpsc::Receiver<LocalWrite>>) -> Option<LocalWrite> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
     }
 }
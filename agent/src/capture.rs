@@ -0,0 +1,185 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::helper::{AppError, ErrorCode};
+use crate::plc_io::{PlcFailure, Request};
+
+/// One Modbus transaction as written to a capture file, one JSON object per
+/// line. The simulator's replay mode reads the same format.
+#[derive(Serialize, Debug)]
+pub struct CaptureRecord<'a> {
+    /// When the response (or timeout) arrived, in Unix milliseconds.
+    pub ts_ms: i64,
+    pub device: &'a str,
+    pub unit_id: u8,
+    pub function: u8,
+    pub address: u16,
+    pub count: u16,
+    /// Value read, or value written for writes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u16>,
+    /// Exception code the PLC answered with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exception: Option<u8>,
+    /// Set when no usable response arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+    pub round_trip_ms: u64,
+}
+
+/// How long written records may sit in the buffer before reaching the disk.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appends the traffic of one device to its capture file. Records reach the
+/// disk within `FLUSH_INTERVAL`, as long as the owner calls `flush_if_due`
+/// that often, and when the recorder is dropped; the file is rotated once
+/// it reaches `max_bytes`.
+pub struct Recorder {
+    path: PathBuf,
+    max_bytes: u64,
+    size: u64,
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    pub fn open(path: &Path, max_bytes: u64) -> Result<Self, AppError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| {
+                AppError::InternalError(format!("Failed to create {}: {}", dir.display(), e))
+            })?;
+        }
+        let file = open_append(path).map_err(|e| {
+            AppError::InternalError(format!("Failed to open {}: {}", path.display(), e))
+        })?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        info!(path = %path.display(), max_bytes, "Capturing PLC traffic");
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            size,
+            writer: BufWriter::new(file),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Writes one transaction. Capture failures are logged, never returned,
+    /// so a full disk does not take the device down.
+    pub fn record(
+        &mut self,
+        device: &str,
+        unit_id: u8,
+        request: &Request,
+        result: &Result<u16, PlcFailure>,
+        round_trip: Duration,
+    ) {
+        let (address, count) = request.range();
+        let (value, exception, error) = match result {
+            Ok(value) => (Some(*value), None, None),
+            Err(failure) => match failure.exception {
+                Some(exception) => (None, Some(exception.code()), None),
+                None => (None, None, Some(failure.error.code())),
+            },
+        };
+        let record = CaptureRecord {
+            ts_ms: Utc::now().timestamp_millis(),
+            device,
+            unit_id,
+            function: request.function(),
+            address,
+            count,
+            value,
+            exception,
+            error,
+            round_trip_ms: round_trip.as_millis() as u64,
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(path = %self.path.display(), "Failed to encode capture record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = self.write(&line) {
+            warn!(path = %self.path.display(), "Failed to write capture record: {}", e);
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(line)?;
+        self.size += line.len() as u64;
+        self.flush_if_due();
+        Ok(())
+    }
+
+    /// Writes buffered records out once they have waited `FLUSH_INTERVAL`,
+    /// so they reach the disk even when traffic stops.
+    pub fn flush_if_due(&mut self) {
+        if self.writer.buffer().is_empty() || self.last_flush.elapsed() < FLUSH_INTERVAL {
+            return;
+        }
+        if let Err(e) = self.writer.flush() {
+            warn!(path = %self.path.display(), "Failed to flush capture: {}", e);
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Moves the full file to `<path>.1`, replacing the previous one, and
+    /// starts a new file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, &rotated)?;
+        self.writer = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read() -> Request {
+        Request::Read {
+            start_register: 512,
+            end_register: 1,
+            r_type: "REG".to_string(),
+        }
+    }
+
+    #[test]
+    fn buffered_records_are_flushed_once_due() {
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut recorder = Recorder::open(&path, 1 << 20).unwrap();
+
+        recorder.record("plc", 1, &read(), &Ok(321), Duration::from_millis(4));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        recorder.flush_if_due();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        recorder.last_flush = Instant::now().checked_sub(FLUSH_INTERVAL).unwrap();
+        recorder.flush_if_due();
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains(r#""value":321"#), "{}", written);
+        assert!(written.ends_with('\n'));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,91 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::time::MissedTickBehavior;
+use url::Url;
+
+use crate::helper::AppError;
+use crate::logging::LogOptions;
+use crate::mqtt::MqttConfig;
+use crate::opcua_server::OpcUaConfig;
+use crate::tls::TlsSettings;
 
 pub const MONITOR_INTERVAL_MS: u64 = 1000;
 pub const CONNECTION_RETRY_MS: u64 = 2000;
 pub const MESSAGE_CHANNEL_SIZE: usize = 32;
+pub const UPLINK_CHANNEL_SIZE: usize = 256;
+pub const DEFAULT_CONFIG_PATH: &str = "agent.toml";
+/// Longest credential rotation period, ten years.
+pub const MAX_ROTATE_DAYS: i64 = 3650;
+
+/// What the poll loop does when a cycle runs past its tick.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedTickPolicy {
+    /// Fire the missed ticks back to back to catch up.
+    Burst,
+    /// Restart the schedule from the end of the late cycle.
+    Delay,
+    /// Drop missed ticks and wait for the next aligned one.
+    Skip,
+}
+
+impl FromStr for MissedTickPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "burst" => Ok(MissedTickPolicy::Burst),
+            "delay" => Ok(MissedTickPolicy::Delay),
+            "skip" => Ok(MissedTickPolicy::Skip),
+            other => Err(AppError::ValidationError(format!(
+                "unknown missed tick policy {}, expected burst, delay or skip",
+                other
+            ))),
+        }
+    }
+}
+
+impl From<MissedTickPolicy> for MissedTickBehavior {
+    fn from(policy: MissedTickPolicy) -> Self {
+        match policy {
+            MissedTickPolicy::Burst => MissedTickBehavior::Burst,
+            MissedTickPolicy::Delay => MissedTickBehavior::Delay,
+            MissedTickPolicy::Skip => MissedTickBehavior::Skip,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct PollSettings {
+    pub interval_ms: u64,
+    pub missed_tick: MissedTickPolicy,
+    /// Back off sensors whose reads take longer than `slow_read_ms`.
+    pub adaptive: bool,
+    pub slow_read_ms: u64,
+    /// Upper bound on the number of cycles a slow sensor is skipped for.
+    pub max_backoff_cycles: u32,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Default for PollSettings {
+    fn default() -> Self {
+        Self {
+            interval_ms: MONITOR_INTERVAL_MS,
+            missed_tick: MissedTickPolicy::Skip,
+            adaptive: false,
+            slow_read_ms: MONITOR_INTERVAL_MS / 2,
+            max_backoff_cycles: 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct SensorConfig {
     pub id: String,
     pub label: String,
@@ -13,9 +94,76 @@ pub struct SensorConfig {
     pub start_register: u16,
     pub register: String,
     pub end_register: u16,
+    /// Device the sensor is read from; the first configured device when unset.
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl SensorConfig {
+    /// Sensors without a device belong to the default one.
+    pub fn belongs_to(&self, device: &str, is_default: bool) -> bool {
+        self.device.as_deref().map_or(is_default, |d| d == device)
+    }
+
+    /// Rejects sensors that can never be read, so they report `config-error`
+    /// instead of hitting the PLC.
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.end_register == 0 {
+            return Err(AppError::ValidationError(format!(
+                "sensor {} reads 0 registers",
+                self.id
+            )));
+        }
+        if self.s_type != "sensor" && self.s_type != "general" {
+            return Err(AppError::ValidationError(format!(
+                "sensor {} has unknown s_type {}",
+                self.id, self.s_type
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ChEvent {
+    /// Commands that change what the agent does; these must arrive in a
+    /// signed envelope once a command key is configured. Only the read-only
+    /// health check may come unsigned.
+    pub fn requires_signature(&self) -> bool {
+        matches!(
+            self,
+            ChEvent::Stop
+                | ChEvent::Write { .. }
+                | ChEvent::AddSensor { .. }
+                | ChEvent::RemoveSensor { .. }
+                | ChEvent::EditSensor { .. }
+                | ChEvent::CleanUp
+                | ChEvent::ApplyConfig { .. }
+                | ChEvent::PauseAgent
+                | ChEvent::SetLogLevel { .. }
+                | ChEvent::ReloadConfig
+        )
+    }
+
+    /// Variant name as the backend sends it, used in command results.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChEvent::Wait => "Wait",
+            ChEvent::Stop => "Stop",
+            ChEvent::Write { .. } => "Write",
+            ChEvent::AddSensor { .. } => "AddSensor",
+            ChEvent::RemoveSensor { .. } => "RemoveSensor",
+            ChEvent::EditSensor { .. } => "EditSensor",
+            ChEvent::PauseAgent => "PauseAgent",
+            ChEvent::HealthCheck => "HealthCheck",
+            ChEvent::SetLogLevel { .. } => "SetLogLevel",
+            ChEvent::ReloadConfig => "ReloadConfig",
+            ChEvent::ApplyConfig { .. } => "ApplyConfig",
+            ChEvent::CleanUp => "CleanUp",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub enum ChEvent {
     Wait,
     Stop,
@@ -23,6 +171,8 @@ pub enum ChEvent {
         reg: u16,
         val: u16,
         r_type: String,
+        #[serde(default)]
+        device: Option<String>,
     },
     AddSensor {
         id: String,
@@ -32,6 +182,8 @@ pub enum ChEvent {
         end_register: u16,
         s_type: String,
         r_type: String,
+        #[serde(default)]
+        device: Option<String>,
     },
     RemoveSensor {
         id: String,
@@ -44,8 +196,630 @@ pub enum ChEvent {
         end_register: u16,
         s_type: String,
         r_type: String,
+        #[serde(default)]
+        device: Option<String>,
     },
     PauseAgent,
     HealthCheck,
+    /// Replaces the log filter, e.g. `info,agent::plc_io=trace`.
+    SetLogLevel {
+        filter: String,
+    },
+    /// Re-reads the configuration file and applies what changed.
+    ReloadConfig,
+    /// Full configuration pushed by the backend. The uplink section is
+    /// always kept from the configuration file.
+    ApplyConfig {
+        version: u64,
+        config: Box<AgentConfig>,
+    },
     CleanUp,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+}
+
+/// A PLC the agent talks to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct DeviceConfig {
+    pub name: String,
+    #[serde(default)]
+    pub transport: Transport,
+    /// `host:port` of the Modbus TCP server.
+    pub address: String,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+    /// Limit for connecting and for every request, in milliseconds.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Extra read attempts after a timeout or dropped link, each on a fresh
+    /// connection. Exceptions from the PLC, writes and stops are never
+    /// retried.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Minimum gap between two requests, for PLCs and gateways that need one.
+    #[serde(default)]
+    pub request_delay_ms: u64,
+    /// Appends every request and response to this file, for replay with
+    /// the simulator.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture: Option<PathBuf>,
+    /// Size at which the capture file is moved to `<capture>.1`, replacing
+    /// the previous one, and a new file started.
+    #[serde(default = "default_capture_max_bytes")]
+    pub capture_max_bytes: u64,
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+fn default_capture_max_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+/// How the agent reaches the backend.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UplinkTransport {
+    #[default]
+    SocketIo,
+    Mqtt,
+}
+
+impl FromStr for UplinkTransport {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "socketio" => Ok(UplinkTransport::SocketIo),
+            "mqtt" => Ok(UplinkTransport::Mqtt),
+            other => Err(AppError::ValidationError(format!(
+                "unknown uplink transport {}, expected socketio or mqtt",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+#[serde(default)]
+pub struct UplinkConfig {
+    pub transport: UplinkTransport,
+    /// Socket.IO backend; unused with the MQTT transport.
+    pub url: String,
+    /// Legacy shared token, used only until the agent is enrolled.
+    pub fingerprint: Option<String>,
+    pub enrollment_code: Option<String>,
+    pub identity_dir: PathBuf,
+    pub rotate_days: i64,
+    /// Backend public key used to verify signed commands.
+    pub command_key_file: Option<String>,
+    /// Applied to `https://` backends and `mqtts://` brokers.
+    pub tls: TlsSettings,
+    pub mqtt: MqttConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct BufferConfig {
+    /// Capacity of the inbound command channel.
+    pub event_channel: usize,
+    /// Capacity of the outbound queue in front of the Socket.IO emitter.
+    pub uplink_channel: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            event_channel: MESSAGE_CHANNEL_SIZE,
+            uplink_channel: UPLINK_CHANNEL_SIZE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "127.0.0.1:9898".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AclDefault {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// An inclusive register range writes are allowed to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct WriteRule {
+    #[serde(default)]
+    pub device: Option<String>,
+    pub r_type: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct WriteAcl {
+    /// Applied to writes no rule matches.
+    pub default: AclDefault,
+    pub allow_stop: bool,
+    pub writes: Vec<WriteRule>,
+}
+
+impl Default for WriteAcl {
+    fn default() -> Self {
+        Self {
+            default: AclDefault::Allow,
+            allow_stop: true,
+            writes: Vec::new(),
+        }
+    }
+}
+
+impl WriteAcl {
+    pub fn allows_write(&self, device: &str, r_type: &str, register: u16) -> bool {
+        let matched = self.writes.iter().any(|rule| {
+            rule.device.as_deref().is_none_or(|d| d == device)
+                && rule.r_type == r_type
+                && (rule.start..=rule.end).contains(&register)
+        });
+        matched || self.default == AclDefault::Allow
+    }
+}
+
+/// Everything the agent needs at start-up, loaded from a TOML file with
+/// environment variable overrides.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Bumped by the backend on every pushed configuration.
+    pub version: u64,
+    pub uplink: UplinkConfig,
+    pub devices: Vec<DeviceConfig>,
+    pub poll: PollSettings,
+    pub buffers: BufferConfig,
+    pub logging: LogOptions,
+    pub metrics: MetricsConfig,
+    pub acl: WriteAcl,
+    pub opcua: OpcUaConfig,
+}
+
+impl AgentConfig {
+    /// Reads `path` (if it exists), applies environment overrides and
+    /// validates the result.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        Self::read_file(path)?.with_env()
+    }
+
+    /// Reads `path` as written, without environment overrides. A missing
+    /// file reads as the defaults.
+    pub fn read_file(path: &Path) -> Result<Self, AppError> {
+        if !path.exists() {
+            let mut config = Self::default();
+            config.fill_defaults();
+            return Ok(config);
+        }
+        let raw = fs::read_to_string(path).map_err(|e| {
+            AppError::ValidationError(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&raw)
+    }
+
+    /// Applies environment overrides and validates the result, giving what
+    /// `load` would return for a file holding this configuration.
+    pub fn with_env(mut self) -> Result<Self, AppError> {
+        self.apply_env()?;
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self, AppError> {
+        let mut config: Self = toml::from_str(raw)
+            .map_err(|e| AppError::ValidationError(format!("Invalid configuration: {}", e)))?;
+        config.fill_defaults();
+        Ok(config)
+    }
+
+    fn fill_defaults(&mut self) {
+        if self.uplink.identity_dir.as_os_str().is_empty() {
+            self.uplink.identity_dir = PathBuf::from("identity");
+        }
+        if self.uplink.rotate_days == 0 {
+            self.uplink.rotate_days = 30;
+        }
+    }
+
+    /// Environment variables override the file. `.env` files from before
+    /// the config file need `HOSTNAME` renamed to `PLC_ADDRESS`.
+    fn apply_env(&mut self) -> Result<(), AppError> {
+        self.fill_defaults();
+        let mut errors = Vec::new();
+
+        // Only fills in a device when the file has none, so an env file left
+        // over from before the config file cannot redirect a configured PLC.
+        if let Ok(address) = env::var("PLC_ADDRESS") {
+            if self.devices.is_empty() {
+                self.devices.push(DeviceConfig {
+                    name: "plc".to_string(),
+                    transport: Transport::Tcp,
+                    address,
+                    unit_id: default_unit_id(),
+                    timeout_ms: default_timeout_ms(),
+                    retries: default_retries(),
+                    request_delay_ms: 0,
+                    capture: None,
+                    capture_max_bytes: default_capture_max_bytes(),
+                });
+            }
+        }
+        override_parsed("UPLINK_TRANSPORT", &mut self.uplink.transport, &mut errors);
+        override_string("WS_URL", &mut self.uplink.url);
+        override_option("FINGERPRINT", &mut self.uplink.fingerprint);
+        override_option("ENROLLMENT_CODE", &mut self.uplink.enrollment_code);
+        override_parsed("IDENTITY_DIR", &mut self.uplink.identity_dir, &mut errors);
+        override_parsed("IDENTITY_ROTATE_DAYS", &mut self.uplink.rotate_days, &mut errors);
+        override_option("COMMAND_PUBKEY_FILE", &mut self.uplink.command_key_file);
+        override_option("TLS_CA_FILE", &mut self.uplink.tls.ca_file);
+        if let Ok(pins) = env::var("TLS_PIN_SHA256") {
+            self.uplink.tls.pins = pins.split(',').map(|p| p.trim().to_string()).collect();
+        }
+        override_option("TLS_CLIENT_CERT", &mut self.uplink.tls.client_cert);
+        override_option("TLS_CLIENT_KEY", &mut self.uplink.tls.client_key);
+        override_string("MQTT_URL", &mut self.uplink.mqtt.url);
+        override_option("MQTT_CLIENT_ID", &mut self.uplink.mqtt.client_id);
+        override_option("MQTT_USERNAME", &mut self.uplink.mqtt.username);
+        override_option("MQTT_PASSWORD", &mut self.uplink.mqtt.password);
+        override_parsed("SPARKPLUG_ENABLED", &mut self.uplink.mqtt.sparkplug.enabled, &mut errors);
+        override_string("SPARKPLUG_GROUP_ID", &mut self.uplink.mqtt.sparkplug.group_id);
+        override_option("SPARKPLUG_EDGE_NODE", &mut self.uplink.mqtt.sparkplug.edge_node);
+        override_parsed(
+            "SPARKPLUG_DEVICE_WRITES",
+            &mut self.uplink.mqtt.sparkplug.device_writes,
+            &mut errors,
+        );
+
+        override_string("LOG_LEVEL", &mut self.logging.filter);
+        override_parsed("LOG_FORMAT", &mut self.logging.format, &mut errors);
+        override_option("LOG_DIR", &mut self.logging.dir);
+        override_parsed("LOG_MAX_FILES", &mut self.logging.max_files, &mut errors);
+        override_string("METRICS_ADDR", &mut self.metrics.listen);
+        override_parsed("OPCUA_ENABLED", &mut self.opcua.enabled, &mut errors);
+        override_string("OPCUA_LISTEN", &mut self.opcua.listen);
+
+        override_parsed("POLL_INTERVAL_MS", &mut self.poll.interval_ms, &mut errors);
+        override_parsed("POLL_MISSED_TICK", &mut self.poll.missed_tick, &mut errors);
+        override_parsed("POLL_ADAPTIVE", &mut self.poll.adaptive, &mut errors);
+        override_parsed("POLL_SLOW_READ_MS", &mut self.poll.slow_read_ms, &mut errors);
+        override_parsed("POLL_MAX_BACKOFF_CYCLES", &mut self.poll.max_backoff_cycles, &mut errors);
+        override_parsed("EVENT_CHANNEL_SIZE", &mut self.buffers.event_channel, &mut errors);
+        override_parsed("UPLINK_CHANNEL_SIZE", &mut self.buffers.uplink_channel, &mut errors);
+
+        validation_result(errors)
+    }
+
+    /// Checks the whole configuration and reports every problem at once.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let mut errors = Vec::new();
+
+        match self.uplink.transport {
+            UplinkTransport::SocketIo => match Url::parse(&self.uplink.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https" | "ws" | "wss") => {}
+                Ok(url) => errors.push(format!("uplink.url: unsupported scheme {}", url.scheme())),
+                Err(e) if self.uplink.url.is_empty() => {
+                    errors.push(format!("uplink.url: required (or set WS_URL): {}", e))
+                }
+                Err(e) => errors.push(format!("uplink.url: {}", e)),
+            },
+            UplinkTransport::Mqtt => {
+                self.uplink.mqtt.validate(&mut errors);
+                let sparkplug = &self.uplink.mqtt.sparkplug;
+                if sparkplug.enabled && sparkplug.device_writes && self.uplink.command_key_file.is_some() {
+                    // Signed writes would be refused one by one at run time.
+                    errors.push(
+                        "uplink.mqtt.sparkplug.device_writes: DCMD writes cannot be signed, \
+                         turn them off when uplink.command_key_file is set"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        if !(1..=MAX_ROTATE_DAYS).contains(&self.uplink.rotate_days) {
+            errors.push(format!("uplink.rotate_days: must be between 1 and {}", MAX_ROTATE_DAYS));
+        }
+        check_file("uplink.command_key_file", &self.uplink.command_key_file, &mut errors);
+        check_file("uplink.tls.ca_file", &self.uplink.tls.ca_file, &mut errors);
+        check_file("uplink.tls.client_cert", &self.uplink.tls.client_cert, &mut errors);
+        check_file("uplink.tls.client_key", &self.uplink.tls.client_key, &mut errors);
+        self.uplink.tls.validate(&mut errors);
+        if self.uplink.tls.client_cert.is_some() != self.uplink.tls.client_key.is_some() {
+            errors.push("uplink.tls: client_cert and client_key must be set together".to_string());
+        }
+
+        if self.devices.is_empty() {
+            // Catches old `.env` files that still name the PLC in `HOSTNAME`,
+            // which is no longer read.
+            errors.push("devices: at least one device is required (or set PLC_ADDRESS)".to_string());
+        }
+        let mut names = HashSet::new();
+        for (i, device) in self.devices.iter().enumerate() {
+            if device.name.is_empty() {
+                errors.push(format!("devices[{}].name: must not be empty", i));
+            } else if !names.insert(device.name.as_str()) {
+                errors.push(format!("devices[{}].name: duplicate name {}", i, device.name));
+            }
+            if device.address.rsplit_once(':').is_none_or(|(host, port)| {
+                host.is_empty() || port.parse::<u16>().is_err()
+            }) {
+                errors.push(format!(
+                    "devices[{}].address: expected host:port, got {:?}",
+                    i, device.address
+                ));
+            }
+            if device.timeout_ms == 0 {
+                errors.push(format!("devices[{}].timeout_ms: must be greater than 0", i));
+            }
+            if device.capture_max_bytes == 0 {
+                errors.push(format!("devices[{}].capture_max_bytes: must be greater than 0", i));
+            }
+        }
+
+        if self.poll.interval_ms == 0 {
+            errors.push("poll.interval_ms: must be greater than 0".to_string());
+        }
+        if self.buffers.event_channel == 0 {
+            errors.push("buffers.event_channel: must be greater than 0".to_string());
+        }
+        if self.buffers.uplink_channel == 0 {
+            errors.push("buffers.uplink_channel: must be greater than 0".to_string());
+        }
+
+        if let Err(e) = crate::logging::validate_filter(&self.logging.filter) {
+            errors.push(format!("logging.filter: {}", e));
+        }
+        if self.logging.max_files == 0 {
+            errors.push("logging.max_files: must be greater than 0".to_string());
+        }
+        if self.metrics.enabled && self.metrics.listen.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "metrics.listen: expected ip:port, got {:?}",
+                self.metrics.listen
+            ));
+        }
+        self.opcua.validate(&mut errors);
+
+        for (i, rule) in self.acl.writes.iter().enumerate() {
+            if rule.r_type != "REG" && rule.r_type != "COIL" {
+                errors.push(format!("acl.writes[{}].r_type: expected REG or COIL", i));
+            }
+            if rule.start > rule.end {
+                errors.push(format!("acl.writes[{}]: start is after end", i));
+            }
+            if let Some(device) = &rule.device {
+                if !names.contains(device.as_str()) {
+                    errors.push(format!("acl.writes[{}].device: unknown device {}", i, device));
+                }
+            }
+        }
+
+        validation_result(errors)
+    }
+
+    /// Writes the configuration through a temp file so a crash never leaves
+    /// a truncated file behind.
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        let raw = toml::to_string_pretty(self)
+            .map_err(|e| AppError::InternalError(format!("Failed to encode configuration: {}", e)))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, raw)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| {
+                AppError::InternalError(format!("Failed to write {}: {}", path.display(), e))
+            })
+    }
+
+    pub fn default_device(&self) -> &str {
+        self.devices.first().map(|d| d.name.as_str()).unwrap_or_default()
+    }
+}
+
+fn validation_result(errors: Vec<String>) -> Result<(), AppError> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::ValidationError(format!(
+            "invalid configuration:\n  - {}",
+            errors.join("\n  - ")
+        )))
+    }
+}
+
+fn check_file(field: &str, path: &Option<String>, errors: &mut Vec<String>) {
+    if let Some(path) = path {
+        if !Path::new(path).is_file() {
+            errors.push(format!("{}: {} does not exist", field, path));
+        }
+    }
+}
+
+fn override_string(name: &str, target: &mut String) {
+    if let Ok(value) = env::var(name) {
+        *target = value;
+    }
+}
+
+fn override_option(name: &str, target: &mut Option<String>) {
+    if let Ok(value) = env::var(name) {
+        *target = Some(value);
+    }
+}
+
+fn override_parsed<T>(name: &str, target: &mut T, errors: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(name) {
+        match raw.parse() {
+            Ok(value) => *target = value,
+            Err(e) => errors.push(format!("{}={}: {}", name, raw, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(s_type: &str, end_register: u16) -> SensorConfig {
+        SensorConfig {
+            id: "level".to_string(),
+            label: "Level".to_string(),
+            s_type: s_type.to_string(),
+            r_type: "REG".to_string(),
+            start_register: 512,
+            register: "512".to_string(),
+            end_register,
+            device: None,
+        }
+    }
+
+    #[test]
+    fn sensors_that_cannot_be_read_fail_validation() {
+        assert!(sensor("sensor", 1).validate().is_ok());
+        assert!(sensor("general", 2).validate().is_ok());
+        assert!(sensor("sensor", 0).validate().is_err());
+        assert!(sensor("gauge", 1).validate().is_err());
+    }
+
+    fn config() -> AgentConfig {
+        AgentConfig::parse(
+            r#"
+            [uplink]
+            url = "https://backend.example:8000"
+
+            [[devices]]
+            name = "plc"
+            address = "127.0.0.1:502"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn rule(device: Option<&str>, r_type: &str, start: u16, end: u16) -> WriteRule {
+        WriteRule {
+            device: device.map(str::to_string),
+            r_type: r_type.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn a_config_without_devices_is_rejected() {
+        assert!(config().validate().is_ok());
+
+        let mut config = config();
+        config.devices.clear();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("PLC_ADDRESS"), "{}", err);
+    }
+
+    #[test]
+    fn devices_need_unique_names_and_host_port_addresses() {
+        for address in ["127.0.0.1", ":502", "plc:modbus", "plc:70000"] {
+            let mut config = config();
+            config.devices[0].address = address.to_string();
+            assert!(config.validate().is_err(), "{}", address);
+        }
+
+        let mut config = config();
+        config.devices.push(config.devices[0].clone());
+        assert!(config.validate().is_err());
+        config.devices[1].name = "line2".to_string();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn write_rules_need_a_known_device_and_an_ordered_range() {
+        let mut config = config();
+        config.acl.writes = vec![rule(Some("plc"), "REG", 10, 20), rule(None, "COIL", 0, 0)];
+        assert!(config.validate().is_ok());
+
+        for bad in [
+            rule(Some("line2"), "REG", 10, 20),
+            rule(None, "REG", 20, 10),
+            rule(None, "INPUT", 10, 20),
+        ] {
+            let mut config = config.clone();
+            config.acl.writes.push(bad.clone());
+            assert!(config.validate().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn writes_outside_every_rule_fall_back_to_the_default() {
+        let mut acl = WriteAcl {
+            default: AclDefault::Deny,
+            allow_stop: true,
+            writes: vec![rule(Some("plc"), "REG", 10, 20), rule(None, "COIL", 5, 5)],
+        };
+        assert!(acl.allows_write("plc", "REG", 10));
+        assert!(acl.allows_write("plc", "REG", 20));
+        assert!(!acl.allows_write("plc", "REG", 21));
+        assert!(!acl.allows_write("line2", "REG", 15));
+        assert!(!acl.allows_write("plc", "COIL", 15));
+        assert!(acl.allows_write("line2", "COIL", 5));
+
+        acl.default = AclDefault::Allow;
+        assert!(acl.allows_write("line2", "REG", 15));
+    }
+
+    #[test]
+    fn sparkplug_device_writes_are_refused_with_a_command_key() {
+        let mut config = config();
+        config.uplink.transport = UplinkTransport::Mqtt;
+        config.uplink.mqtt.url = "mqtt://broker.example:1883".to_string();
+        config.uplink.mqtt.sparkplug.enabled = true;
+        config.uplink.command_key_file = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("uplink.mqtt.sparkplug.device_writes"), "{}", err);
+
+        config.uplink.mqtt.sparkplug.device_writes = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn missed_tick_policies_parse_from_their_config_names() {
+        assert_eq!("burst".parse::<MissedTickPolicy>().unwrap(), MissedTickPolicy::Burst);
+        assert_eq!("delay".parse::<MissedTickPolicy>().unwrap(), MissedTickPolicy::Delay);
+        assert_eq!("skip".parse::<MissedTickPolicy>().unwrap(), MissedTickPolicy::Skip);
+        assert!("catch-up".parse::<MissedTickPolicy>().is_err());
+    }
+}
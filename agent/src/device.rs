@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior};
+use tokio_modbus::client::{Client, Context};
+use tracing::{debug, error, info, warn};
+
+use crate::capture::{Recorder, FLUSH_INTERVAL};
+use crate::config::{DeviceConfig, PollSettings};
+use crate::helper::AppError;
+use crate::mdb_client;
+use crate::monitoring::{self, Backoff};
+use crate::plc_io::{self, PlcFailure, Request};
+use crate::queue::{CommandQueue, Priority};
+use crate::state::SharedState;
+use crate::uplink::Uplink;
+
+const DEVICE_CHANNEL_SIZE: usize = 16;
+
+type Reply = oneshot::Sender<Result<(), AppError>>;
+
+pub enum DeviceCommand {
+    Stop { reply: Reply },
+    Write {
+        reg: u16,
+        val: u16,
+        r_type: String,
+        reply: Reply,
+    },
+    /// New poll settings, and whether sensors without a device belong here.
+    Configure {
+        settings: PollSettings,
+        is_default: bool,
+    },
+    /// Swaps in changed device settings and a connection opened with them.
+    Reconnect(DeviceConfig, Context),
+    /// Runs after every command queued before it, then disconnects.
+    Shutdown(oneshot::Sender<()>),
+}
+
+impl DeviceCommand {
+    pub fn priority(&self) -> Priority {
+        match self {
+            DeviceCommand::Stop { .. } => Priority::Emergency,
+            DeviceCommand::Write { .. } => Priority::Operator,
+            DeviceCommand::Configure { .. }
+            | DeviceCommand::Reconnect(..)
+            | DeviceCommand::Shutdown(_) => Priority::Control,
+        }
+    }
+}
+
+/// Handle to the actor that owns one PLC connection.
+#[derive(Clone)]
+pub struct DeviceHandle {
+    tx: mpsc::Sender<DeviceCommand>,
+}
+
+impl DeviceHandle {
+    pub fn spawn(
+        device: DeviceConfig,
+        ctx: Context,
+        settings: PollSettings,
+        is_default: bool,
+        state: Arc<Mutex<SharedState>>,
+        uplink: Uplink,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(DEVICE_CHANNEL_SIZE);
+        let actor = DeviceActor {
+            name: device.name.clone(),
+            recorder: open_recorder(&device),
+            device,
+            ctx,
+            reconnect_pending: false,
+            last_request: None,
+            rx,
+            queue: CommandQueue::default(),
+            settings,
+            is_default,
+            state,
+            uplink,
+            backoff: HashMap::new(),
+            poll_start: 0,
+            reschedule: false,
+            stopping: false,
+        };
+        (Self { tx }, tokio::spawn(actor.run()))
+    }
+
+    /// Queues a stop ahead of everything but other stops.
+    pub async fn stop(&self) -> Result<Pending, AppError> {
+        self.request(|reply| DeviceCommand::Stop { reply }).await
+    }
+
+    pub async fn write(&self, reg: u16, val: u16, r_type: String) -> Result<Pending, AppError> {
+        self.request(|reply| DeviceCommand::Write {
+            reg,
+            val,
+            r_type,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn send(&self, command: DeviceCommand) -> Result<(), AppError> {
+        self.tx
+            .send(command)
+            .await
+            .map_err(|_| AppError::InternalError("device actor has stopped".to_string()))
+    }
+
+    pub async fn shutdown(&self) {
+        let (reply, done) = oneshot::channel();
+        if self.send(DeviceCommand::Shutdown(reply)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+
+    async fn request(&self, command: impl FnOnce(Reply) -> DeviceCommand) -> Result<Pending, AppError> {
+        let (reply, result) = oneshot::channel();
+        self.send(command(reply)).await?;
+        Ok(Pending(result))
+    }
+}
+
+/// A queued stop or write, resolving once the actor has run it.
+pub struct Pending(oneshot::Receiver<Result<(), AppError>>);
+
+impl Pending {
+    pub async fn result(self) -> Result<(), AppError> {
+        self.0
+            .await
+            .map_err(|_| AppError::InternalError("device actor dropped the request".to_string()))?
+    }
+}
+
+/// Owns the Modbus connection of one PLC. Queued commands run by priority
+/// before every read, and a failed read gives up its retries once a command
+/// is waiting, so a stop waits for at most one attempt of the read in
+/// flight: its request spacing and timeout.
+pub struct DeviceActor {
+    pub name: String,
+    device: DeviceConfig,
+    ctx: Context,
+    /// Set after a timeout or dropped link; the next request reconnects.
+    reconnect_pending: bool,
+    last_request: Option<Instant>,
+    recorder: Option<Recorder>,
+    rx: mpsc::Receiver<DeviceCommand>,
+    queue: CommandQueue,
+    pub settings: PollSettings,
+    pub is_default: bool,
+    pub state: Arc<Mutex<SharedState>>,
+    pub uplink: Uplink,
+    pub backoff: HashMap<String, Backoff>,
+    /// Sensor the next poll cycle starts with: the first one whose read
+    /// the last cycle had to drop.
+    pub poll_start: usize,
+    reschedule: bool,
+    stopping: bool,
+}
+
+impl DeviceActor {
+    async fn run(mut self) {
+        let mut interval = self.interval(true);
+        self.state
+            .lock()
+            .await
+            .devices
+            .entry(self.name.clone())
+            .or_default()
+            .poll
+            .interval_ms = self.settings.interval_ms;
+
+        // Captured traffic reaches the disk even while the device is idle.
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        while !self.stopping {
+            tokio::select! {
+                biased;
+                command = self.rx.recv() => match command {
+                    Some(command) => {
+                        self.queue.push(command);
+                        self.drain_commands().await;
+                    }
+                    None => break,
+                },
+                _ = interval.tick() => monitoring::poll_cycle(&mut self).await,
+                _ = flush.tick(), if self.recorder.is_some() => {
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.flush_if_due();
+                    }
+                }
+            }
+            if self.reschedule {
+                self.reschedule = false;
+                interval = self.interval(false);
+            }
+        }
+        debug!(device = %self.name, "Device actor stopped");
+    }
+
+    /// The first cycle starts one interval after spawning, giving the
+    /// backend time to register sensors.
+    fn interval(&self, delay_start: bool) -> Interval {
+        let period = Duration::from_millis(self.settings.interval_ms);
+        let start = if delay_start {
+            Instant::now() + period
+        } else {
+            Instant::now()
+        };
+        let mut interval = tokio::time::interval_at(start, period);
+        interval.set_missed_tick_behavior(self.settings.missed_tick.into());
+        interval
+    }
+
+    /// Runs every pending command, highest priority first. The channel is
+    /// re-read after each one so a stop overtakes writes still queued.
+    /// Returns true once the actor is shutting down and the current cycle
+    /// should be abandoned.
+    pub async fn drain_commands(&mut self) -> bool {
+        while !self.stopping {
+            while let Ok(command) = self.rx.try_recv() {
+                self.queue.push(command);
+            }
+            match self.queue.pop() {
+                Some(command) => self.handle(command).await,
+                None => break,
+            }
+        }
+        self.stopping
+    }
+
+    async fn handle(&mut self, command: DeviceCommand) {
+        match command {
+            DeviceCommand::Stop { reply } => {
+                info!(device = %self.name, "Stopping PLC");
+                let result = self.transact(&Request::Stop).await;
+                let _ = reply.send(result.map(|_| ()).map_err(|f| f.error));
+            }
+            DeviceCommand::Write {
+                reg,
+                val,
+                r_type,
+                reply,
+            } => {
+                let request = Request::Write {
+                    register: reg,
+                    value: val,
+                    r_type,
+                };
+                let result = self.transact(&request).await;
+                let _ = reply.send(result.map(|_| ()).map_err(|f| f.error));
+            }
+            DeviceCommand::Configure {
+                settings,
+                is_default,
+            } => {
+                if settings != self.settings {
+                    self.state
+                        .lock()
+                        .await
+                        .devices
+                        .entry(self.name.clone())
+                        .or_default()
+                        .poll
+                        .interval_ms = settings.interval_ms;
+                    self.backoff.clear();
+                    self.settings = settings;
+                    self.reschedule = true;
+                }
+                self.is_default = is_default;
+            }
+            DeviceCommand::Reconnect(device, ctx) => {
+                if (&device.capture, device.capture_max_bytes)
+                    != (&self.device.capture, self.device.capture_max_bytes)
+                {
+                    self.recorder = open_recorder(&device);
+                }
+                self.device = device;
+                self.reconnect_pending = false;
+                let mut old = std::mem::replace(&mut self.ctx, ctx);
+                if let Err(e) = old.disconnect().await {
+                    debug!(device = %self.name, "Closing the previous connection failed: {}", e);
+                }
+                info!(device = %self.name, "Switched to the new PLC connection");
+            }
+            DeviceCommand::Shutdown(reply) => {
+                match self.ctx.disconnect().await {
+                    Ok(()) => info!(device = %self.name, "Disconnected from PLC"),
+                    Err(e) => warn!(device = %self.name, "Failed to disconnect from PLC: {}", e),
+                }
+                self.stopping = true;
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    /// Runs one transaction under the device's timeout, retry count and
+    /// request spacing. Only reads are retried, after a timeout or dropped
+    /// link and on a fresh connection: a write or stop that timed out may
+    /// still have reached the PLC. A read is not retried while a command is
+    /// waiting, so the command goes first.
+    pub async fn transact(&mut self, request: &Request) -> Result<u16, PlcFailure> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(request).await {
+                Err(failure)
+                    if failure.link_down
+                        && request.is_read()
+                        && attempt < self.device.retries
+                        && !self.command_waiting() =>
+                {
+                    attempt += 1;
+                    debug!(device = %self.name, attempt, "Retrying {}: {}", request, failure.error);
+                    self.state.lock().await.record_retry(&self.name);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn command_waiting(&mut self) -> bool {
+        while let Ok(command) = self.rx.try_recv() {
+            self.queue.push(command);
+        }
+        !self.queue.is_empty()
+    }
+
+    async fn attempt(&mut self, request: &Request) -> Result<u16, PlcFailure> {
+        if self.reconnect_pending {
+            self.ctx = mdb_client::connect(&self.device)
+                .await
+                .map_err(PlcFailure::disconnected)?;
+            self.reconnect_pending = false;
+            info!(device = %self.name, "Reconnected to PLC");
+        }
+
+        if let Some(last) = self.last_request {
+            let delay = Duration::from_millis(self.device.request_delay_ms);
+            tokio::time::sleep_until(last + delay).await;
+        }
+
+        let timeout = Duration::from_millis(self.device.timeout_ms);
+        let started = Instant::now();
+        let outcome = tokio::time::timeout(timeout, plc_io::execute(&mut self.ctx, request)).await;
+        self.last_request = Some(Instant::now());
+
+        let result = match outcome {
+            Ok(result) => result,
+            Err(_) => {
+                self.state.lock().await.record_timeout(&self.name);
+                Err(PlcFailure::timeout(format!(
+                    "{} after {} ms",
+                    request, self.device.timeout_ms
+                )))
+            }
+        };
+        if let Some(recorder) = &mut self.recorder {
+            let round_trip = started.elapsed();
+            recorder.record(&self.name, self.device.unit_id, request, &result, round_trip);
+        }
+        // A timed-out request may still be answered later, so the
+        // connection is not reused after one.
+        if let Err(failure) = &result {
+            self.reconnect_pending |= failure.link_down;
+        }
+        result
+    }
+}
+
+fn open_recorder(device: &DeviceConfig) -> Option<Recorder> {
+    let path = device.capture.as_ref()?;
+    Recorder::open(path, device.capture_max_bytes)
+        .map_err(|e| error!(device = %device.name, "Not capturing PLC traffic: {}", e))
+        .ok()
+}
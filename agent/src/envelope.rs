@@ -0,0 +1,266 @@
+use chrono::Utc;
+use openssl::base64;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::helper::AppError;
+use crate::wire::parse_command;
+use crate::ChEvent;
+
+/// Envelopes valid for longer than this are rejected, which also bounds how
+/// long nonces have to be remembered.
+const MAX_ENVELOPE_TTL_SECS: i64 = 300;
+
+/// A command signed by the backend:
+/// `{"envelope": {"agent_id", "command", "payload": "<command json>", "nonce", "expires_at", "signature"}}`.
+///
+/// The signature is Ed25519 over `agent_id|command|nonce|expires_at|payload`,
+/// base64 encoded, so an envelope only opens on the agent it was addressed
+/// to and only as the command type it names. `payload` is kept as a string
+/// so both sides sign the exact same bytes.
+#[derive(Deserialize, Debug)]
+pub struct Envelope {
+    pub agent_id: String,
+    /// Variant name of the command in `payload`, e.g. `Write`.
+    pub command: String,
+    pub payload: String,
+    pub nonce: String,
+    pub expires_at: i64,
+    pub signature: String,
+}
+
+/// Applies the command signing policy. The backend key covers commands
+/// that arrive over the uplink: with a key configured, safety-relevant
+/// commands must come in a valid envelope. Local write sources are not
+/// checked here: OPC UA writes are authorized by the `[[opcua.users]]`
+/// accounts and the write ACL, and Sparkplug writes go through [`Self::admit`].
+pub struct CommandVerifier {
+    backend_key: Option<PKey<Public>>,
+    /// Envelopes addressed to any other agent are rejected.
+    agent_id: String,
+    /// Nonces seen so far, with the expiry of the envelope that carried them.
+    seen_nonces: Mutex<HashMap<String, i64>>,
+}
+
+impl CommandVerifier {
+    /// Loads the backend's Ed25519 public key for the agent known to the
+    /// backend as `agent_id`. Without a key, commands are accepted unsigned.
+    pub fn from_key_file(path: Option<&str>, agent_id: &str) -> Result<Self, AppError> {
+        let backend_key = match path {
+            Some(path) => {
+                let pem = fs::read(path).map_err(|e| {
+                    AppError::ValidationError(format!("Failed to read {}: {}", path, e))
+                })?;
+                Some(PKey::public_key_from_pem(&pem).map_err(|e| {
+                    AppError::ValidationError(format!("Invalid command key {}: {}", path, e))
+                })?)
+            }
+            None => {
+                warn!("No command signing key configured, accepting unsigned commands");
+                None
+            }
+        };
+        Ok(Self {
+            backend_key,
+            agent_id: agent_id.to_string(),
+            seen_nonces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Turns an inbound message into an event, verifying its envelope.
+    /// Safety-relevant commands must be signed once a key is configured.
+    pub fn open(&self, message: &Value) -> Result<ChEvent, AppError> {
+        let Some(envelope) = message.get("envelope") else {
+            return self.admit(parse_command(message)?);
+        };
+
+        let envelope: Envelope = serde_json::from_value(envelope.clone()).map_err(|e| {
+            AppError::DeserializationError {
+                message: "invalid envelope".to_string(),
+                source: e,
+            }
+        })?;
+        if let Some(key) = &self.backend_key {
+            self.verify(key, &envelope)?;
+        }
+
+        let payload: Value = serde_json::from_str(&envelope.payload).map_err(|e| {
+            AppError::DeserializationError {
+                message: "invalid envelope payload".to_string(),
+                source: e,
+            }
+        })?;
+        let event = parse_command(&payload)?;
+        if self.backend_key.is_some() && event.name() != envelope.command {
+            return Err(AppError::ValidationError(format!(
+                "Envelope for {} carries a {} command",
+                envelope.command,
+                event.name()
+            )));
+        }
+        Ok(event)
+    }
+
+    /// Checks an event that arrived without an envelope, such as a Sparkplug
+    /// write, against the signing policy: once a key is configured, only
+    /// commands that need no signature are admitted.
+    pub fn admit(&self, event: ChEvent) -> Result<ChEvent, AppError> {
+        if self.backend_key.is_some() && event.requires_signature() {
+            return Err(AppError::ValidationError(
+                "Command must be signed".to_string(),
+            ));
+        }
+        Ok(event)
+    }
+
+    fn verify(&self, key: &PKey<Public>, envelope: &Envelope) -> Result<(), AppError> {
+        if envelope.agent_id != self.agent_id {
+            return Err(AppError::ValidationError(format!(
+                "Command is addressed to agent {}",
+                envelope.agent_id
+            )));
+        }
+        let now = Utc::now().timestamp();
+        if envelope.expires_at <= now {
+            return Err(AppError::ValidationError("Command has expired".to_string()));
+        }
+        if envelope.expires_at - now > MAX_ENVELOPE_TTL_SECS {
+            return Err(AppError::ValidationError(
+                "Command expiry is too far in the future".to_string(),
+            ));
+        }
+
+        let signature = base64::decode_block(&envelope.signature).map_err(|e| {
+            AppError::ValidationError(format!("Invalid signature encoding: {}", e))
+        })?;
+        let message = format!(
+            "{}|{}|{}|{}|{}",
+            envelope.agent_id, envelope.command, envelope.nonce, envelope.expires_at, envelope.payload
+        );
+        let valid = Verifier::new_without_digest(key)
+            .and_then(|mut verifier| verifier.verify_oneshot(&signature, message.as_bytes()))
+            .unwrap_or(false);
+        if !valid {
+            return Err(AppError::ValidationError(
+                "Invalid command signature".to_string(),
+            ));
+        }
+
+        // Only remember nonces of authentic envelopes, so forged traffic
+        // cannot grow the cache.
+        let mut seen = self.seen_nonces.lock().expect("nonce cache lock");
+        seen.retain(|_, expires_at| *expires_at > now);
+        if seen.contains_key(&envelope.nonce) {
+            return Err(AppError::ValidationError(
+                "Replayed command rejected".to_string(),
+            ));
+        }
+        seen.insert(envelope.nonce.clone(), envelope.expires_at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    fn verifier(key: &PKey<Private>) -> CommandVerifier {
+        let public = key.public_key_to_pem().unwrap();
+        CommandVerifier {
+            backend_key: Some(PKey::public_key_from_pem(&public).unwrap()),
+            agent_id: "agent-1".to_string(),
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn envelope(key: &PKey<Private>, agent_id: &str, nonce: &str, expires_at: i64) -> Envelope {
+        let payload = r#"{"Stop":null}"#.to_string();
+        let message = format!("{}|Stop|{}|{}|{}", agent_id, nonce, expires_at, payload);
+        let mut signer = Signer::new_without_digest(key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(message.as_bytes()).unwrap();
+        Envelope {
+            agent_id: agent_id.to_string(),
+            command: "Stop".to_string(),
+            payload,
+            nonce: nonce.to_string(),
+            expires_at,
+            signature: base64::encode_block(&signature),
+        }
+    }
+
+    fn check(verifier: &CommandVerifier, envelope: &Envelope) -> Result<(), AppError> {
+        verifier.verify(verifier.backend_key.as_ref().unwrap(), envelope)
+    }
+
+    #[test]
+    fn envelopes_are_only_valid_until_they_expire() {
+        let key = PKey::generate_ed25519().unwrap();
+        let verifier = verifier(&key);
+        let now = Utc::now().timestamp();
+
+        assert!(check(&verifier, &envelope(&key, "agent-1", "a", now + 60)).is_ok());
+        assert!(check(&verifier, &envelope(&key, "agent-1", "b", now - 1)).is_err());
+        let too_long = now + MAX_ENVELOPE_TTL_SECS + 60;
+        assert!(check(&verifier, &envelope(&key, "agent-1", "c", too_long)).is_err());
+    }
+
+    #[test]
+    fn replayed_nonces_are_rejected() {
+        let key = PKey::generate_ed25519().unwrap();
+        let verifier = verifier(&key);
+        let expires_at = Utc::now().timestamp() + 60;
+
+        assert!(check(&verifier, &envelope(&key, "agent-1", "a", expires_at)).is_ok());
+        assert!(check(&verifier, &envelope(&key, "agent-1", "a", expires_at)).is_err());
+        assert!(check(&verifier, &envelope(&key, "agent-1", "b", expires_at)).is_ok());
+    }
+
+    #[test]
+    fn envelopes_for_another_agent_are_rejected() {
+        let key = PKey::generate_ed25519().unwrap();
+        let verifier = verifier(&key);
+        let expires_at = Utc::now().timestamp() + 60;
+
+        assert!(check(&verifier, &envelope(&key, "agent-2", "a", expires_at)).is_err());
+    }
+
+    #[test]
+    fn envelopes_signed_by_another_key_are_rejected() {
+        let key = PKey::generate_ed25519().unwrap();
+        let forger = PKey::generate_ed25519().unwrap();
+        let verifier = verifier(&key);
+        let expires_at = Utc::now().timestamp() + 60;
+
+        let mut forged = envelope(&forger, "agent-1", "a", expires_at);
+        assert!(check(&verifier, &forged).is_err());
+        forged.signature = "not base64!".to_string();
+        assert!(check(&verifier, &forged).is_err());
+        // Rejected envelopes do not burn the nonce.
+        assert!(check(&verifier, &envelope(&key, "agent-1", "a", expires_at)).is_ok());
+    }
+
+    #[test]
+    fn unsigned_commands_need_no_signature_once_a_key_is_set() {
+        let key = PKey::generate_ed25519().unwrap();
+        let verifier = verifier(&key);
+        let write = || ChEvent::Write {
+            reg: 1,
+            val: 2,
+            r_type: "REG".to_string(),
+            device: None,
+        };
+
+        assert!(verifier.admit(ChEvent::HealthCheck).is_ok());
+        assert!(verifier.admit(write()).is_err());
+        let unsigned = CommandVerifier::from_key_file(None, "agent-1").unwrap();
+        assert!(unsigned.admit(write()).is_ok());
+    }
+}
@@ -0,0 +1,135 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use sysinfo::{Components, Disks, System};
+
+use crate::config::SensorConfig;
+use crate::helper::AppError;
+use crate::metrics::metrics;
+use crate::protocol;
+use crate::state::{DeviceHealth, SharedState};
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct HostStats {
+    pub cpu_percent: f32,
+    pub memory_used_bytes: u64,
+    pub memory_total_bytes: u64,
+    pub disk_used_bytes: u64,
+    pub disk_total_bytes: u64,
+    pub temperature_c: Option<f32>,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct HealthReport {
+    pub agent_version: &'static str,
+    pub config_version: u64,
+    pub uptime_secs: u64,
+    pub host: HostStats,
+    pub devices: HashMap<String, DeviceHealth>,
+    /// Commands queued in the event channel, waiting for the agent.
+    pub backlog: i64,
+    pub paused: bool,
+    pub sensors: Vec<SensorConfig>,
+}
+
+/// Samples host resources. CPU usage is measured between two calls, so the
+/// first report after start-up shows 0%.
+#[derive(Clone, Default)]
+pub struct HostMonitor {
+    system: Arc<Mutex<System>>,
+}
+
+impl HostMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs on the blocking pool, since refreshing disks and temperature
+    /// sensors reads from the file system.
+    pub async fn sample(&self) -> Result<HostStats, AppError> {
+        let system = self.system.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut system = system.lock().unwrap_or_else(|e| e.into_inner());
+            sample(&mut system)
+        })
+        .await
+        .map_err(|e| AppError::InternalError(format!("Host sampling failed: {}", e)))
+    }
+}
+
+fn sample(system: &mut System) -> HostStats {
+    system.refresh_cpu_usage();
+    system.refresh_memory();
+
+    let disks = Disks::new_with_refreshed_list();
+    let root = disks
+        .list()
+        .iter()
+        .find(|d| d.mount_point() == Path::new("/"));
+    let (disk_total_bytes, disk_available) = match root {
+        Some(disk) => (disk.total_space(), disk.available_space()),
+        None => disks.list().iter().fold((0, 0), |(total, available), d| {
+            (total + d.total_space(), available + d.available_space())
+        }),
+    };
+
+    let temperature_c = Components::new_with_refreshed_list()
+        .list()
+        .iter()
+        .filter_map(|c| c.temperature())
+        .reduce(f32::max);
+
+    HostStats {
+        cpu_percent: system.global_cpu_usage(),
+        memory_used_bytes: system.used_memory(),
+        memory_total_bytes: system.total_memory(),
+        disk_used_bytes: disk_total_bytes.saturating_sub(disk_available),
+        disk_total_bytes,
+        temperature_c,
+    }
+}
+
+pub fn build_report(host: HostStats, state: &SharedState, config_version: u64) -> HealthReport {
+    HealthReport {
+        agent_version: protocol::AGENT_VERSION,
+        config_version,
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        host,
+        devices: state.devices.clone(),
+        backlog: metrics().event_queue_depth.get(),
+        paused: state.paused_agent,
+        sensors: state.registered_sensors.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_state_of_every_device() {
+        let shared = SharedState::new("plc");
+        let mut state = shared.try_lock().unwrap();
+        state.paused_agent = true;
+        state.record_read_failure("plc", "timed out".to_string(), true);
+        let host = HostStats {
+            cpu_percent: 0.0,
+            memory_used_bytes: 0,
+            memory_total_bytes: 0,
+            disk_used_bytes: 0,
+            disk_total_bytes: 0,
+            temperature_c: None,
+        };
+
+        let report = build_report(host, &state, 3);
+        assert_eq!(report.agent_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.config_version, 3);
+        assert!(report.paused);
+        let plc = &report.devices["plc"];
+        assert!(!plc.link_up);
+        assert_eq!(plc.consecutive_failures, 1);
+        assert_eq!(plc.last_error.as_deref(), Some("timed out"));
+    }
+}
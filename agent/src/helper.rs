@@ -1,34 +1,325 @@
+use chrono::Local;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::error::Error as StdError;
 use thiserror::Error;
 
+use crate::plc_io::ModbusException;
 use crate::ChEvent;
 
-#[derive(Debug, PartialEq, Error)]
+/// Underlying error kept for `source()` chaining.
+pub type ErrorSource = Box<dyn StdError + Send + Sync>;
+
+#[derive(Debug, Error)]
 pub enum AppError {
     #[error("Validation failed: {0}")]
     ValidationError(String),
 
-    #[error("Deserialization failed: {0}")]
-    DeserializationError(String),
+    #[error("Deserialization failed: {message}")]
+    DeserializationError {
+        message: String,
+        source: serde_json::Error,
+    },
+
+    #[error("PLC communication error: {message}")]
+    PlcError {
+        message: String,
+        source: Option<ErrorSource>,
+    },
+
+    #[error("PLC answered with {0}: {1}")]
+    ModbusException(ModbusException, String),
+
+    #[error("Timed out connecting to PLC: {0}")]
+    ConnectTimeout(String),
+
+    #[error("Modbus request timed out: {0}")]
+    RequestTimeout(String),
+
+    #[error("Socket.IO error: {message}")]
+    SocketIoError {
+        message: String,
+        source: Option<ErrorSource>,
+    },
 
-    #[error("PLC communication error: {0}")]
-    PlcError(String),
+    #[error("MQTT error: {message}")]
+    MqttError {
+        message: String,
+        source: Option<ErrorSource>,
+    },
 
-    #[error("Socket.IO error: {0}")]
-    SocketIoError(String),
+    /// Building the uplink's TLS configuration or a pinned handshake failed.
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    /// The Engine.IO transport under the Socket.IO session failed.
+    #[error("Socket.IO transport lost")]
+    TransportLost { source: Box<rust_socketio::Error> },
+
+    /// A message stamped with a wire schema version this build does not know.
+    #[error("Unsupported wire version {0}")]
+    UnsupportedVersion(u64),
 
     #[error("Internal error: {0}")]
     InternalError(String),
 }
 
+/// Stable, machine-readable error codes sent to the backend. Never renamed;
+/// new failure modes get new codes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Validation,
+    Deserialization,
+    PlcComm,
+    ModbusException,
+    ConnectTimeout,
+    RequestTimeout,
+    Uplink,
+    UplinkTransportLost,
+    Tls,
+    UnsupportedVersion,
+    Internal,
+}
+
+/// Which part of the system an error comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorDomain {
+    Device,
+    Uplink,
+    Config,
+    Agent,
+}
+
+/// Wire form of an `AppError`, attached to events and command results.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ErrorReport {
+    pub code: ErrorCode,
+    pub domain: ErrorDomain,
+    /// Retrying the same operation later may succeed.
+    pub transient: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exception: Option<ModbusException>,
+    /// Messages of the underlying errors, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+}
+
+impl AppError {
+    pub fn plc(message: String) -> Self {
+        AppError::PlcError {
+            message,
+            source: None,
+        }
+    }
+
+    pub fn mqtt(message: String) -> Self {
+        AppError::MqttError {
+            message,
+            source: None,
+        }
+    }
+
+    pub fn socket_io(message: String) -> Self {
+        AppError::SocketIoError {
+            message,
+            source: None,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::ValidationError(_) => ErrorCode::Validation,
+            AppError::DeserializationError { .. } => ErrorCode::Deserialization,
+            AppError::PlcError { .. } => ErrorCode::PlcComm,
+            AppError::ModbusException(..) => ErrorCode::ModbusException,
+            AppError::ConnectTimeout(_) => ErrorCode::ConnectTimeout,
+            AppError::RequestTimeout(_) => ErrorCode::RequestTimeout,
+            AppError::SocketIoError { .. } | AppError::MqttError { .. } => ErrorCode::Uplink,
+            AppError::TransportLost { .. } => ErrorCode::UplinkTransportLost,
+            AppError::TlsError(_) => ErrorCode::Tls,
+            AppError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            AppError::InternalError(_) => ErrorCode::Internal,
+        }
+    }
+
+    pub fn domain(&self) -> ErrorDomain {
+        match self {
+            AppError::ValidationError(_) => ErrorDomain::Config,
+            AppError::PlcError { .. }
+            | AppError::ModbusException(..)
+            | AppError::ConnectTimeout(_)
+            | AppError::RequestTimeout(_) => ErrorDomain::Device,
+            AppError::DeserializationError { .. }
+            | AppError::SocketIoError { .. }
+            | AppError::MqttError { .. }
+            | AppError::TransportLost { .. }
+            | AppError::TlsError(_)
+            | AppError::UnsupportedVersion(_) => ErrorDomain::Uplink,
+            AppError::InternalError(_) => ErrorDomain::Agent,
+        }
+    }
+
+    /// Whether the failure can clear up on its own, as opposed to needing a
+    /// change of configuration or command.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::PlcError { .. }
+            | AppError::ConnectTimeout(_)
+            | AppError::RequestTimeout(_)
+            | AppError::SocketIoError { .. }
+            | AppError::MqttError { .. }
+            | AppError::TransportLost { .. } => true,
+            AppError::ModbusException(exception, _) => exception.is_transient(),
+            AppError::ValidationError(_)
+            | AppError::DeserializationError { .. }
+            | AppError::TlsError(_)
+            | AppError::UnsupportedVersion(_)
+            | AppError::InternalError(_) => false,
+        }
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        let mut causes = Vec::new();
+        let mut source = self.source();
+        while let Some(err) = source {
+            causes.push(err.to_string());
+            source = err.source();
+        }
+        ErrorReport {
+            code: self.code(),
+            domain: self.domain(),
+            transient: self.is_transient(),
+            message: self.to_string(),
+            exception: match self {
+                AppError::ModbusException(exception, _) => Some(*exception),
+                _ => None,
+            },
+            causes,
+        }
+    }
+}
+
+impl From<rust_socketio::Error> for AppError {
+    fn from(error: rust_socketio::Error) -> Self {
+        match error {
+            rust_socketio::Error::IncompleteResponseFromEngineIo(_) => {
+                AppError::TransportLost {
+                    source: Box::new(error),
+                }
+            }
+            other => AppError::SocketIoError {
+                message: other.to_string(),
+                source: Some(Box::new(other)),
+            },
+        }
+    }
+}
+
 impl From<Box<dyn StdError>> for AppError {
     fn from(error: Box<dyn StdError>) -> Self {
         AppError::InternalError(error.to_string())
     }
 }
 
+/// Timestamp format shared by samples and health reports.
+pub fn now_timestamp() -> String {
+    Local::now().format("%y/%m/%d %H:%M:%S").to_string()
+}
+
 pub fn parse_message_to_event(data: &Value) -> Result<ChEvent, AppError> {
     serde_json::from_value(data.clone())
-        .map_err(|e| AppError::DeserializationError(format!("Failed to parse event: {}", e)))
+        .map_err(|e| AppError::DeserializationError {
+            message: "failed to parse event".to_string(),
+            source: e,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn errors_are_classified_by_variant() {
+        let cases = [
+            (
+                AppError::ValidationError("bad".to_string()),
+                ErrorCode::Validation,
+                ErrorDomain::Config,
+                false,
+            ),
+            (
+                AppError::plc("dropped".to_string()),
+                ErrorCode::PlcComm,
+                ErrorDomain::Device,
+                true,
+            ),
+            (
+                AppError::RequestTimeout("read".to_string()),
+                ErrorCode::RequestTimeout,
+                ErrorDomain::Device,
+                true,
+            ),
+            (
+                AppError::ModbusException(ModbusException::IllegalDataAddress, String::new()),
+                ErrorCode::ModbusException,
+                ErrorDomain::Device,
+                false,
+            ),
+            (
+                AppError::ModbusException(ModbusException::SlaveDeviceBusy, String::new()),
+                ErrorCode::ModbusException,
+                ErrorDomain::Device,
+                true,
+            ),
+            (
+                AppError::mqtt("offline".to_string()),
+                ErrorCode::Uplink,
+                ErrorDomain::Uplink,
+                true,
+            ),
+            (
+                AppError::TlsError("pin mismatch".to_string()),
+                ErrorCode::Tls,
+                ErrorDomain::Uplink,
+                false,
+            ),
+            (
+                AppError::InternalError("bug".to_string()),
+                ErrorCode::Internal,
+                ErrorDomain::Agent,
+                false,
+            ),
+        ];
+        for (error, code, domain, transient) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+            assert_eq!(error.domain(), domain, "{}", error);
+            assert_eq!(error.is_transient(), transient, "{}", error);
+        }
+    }
+
+    #[test]
+    fn reports_carry_the_chain_of_causes() {
+        let error = AppError::PlcError {
+            message: "read of register 512 failed".to_string(),
+            source: Some(Box::new(io::Error::other("connection reset"))),
+        };
+        let report = error.report();
+        assert_eq!(report.code, ErrorCode::PlcComm);
+        assert!(report.transient);
+        assert_eq!(report.message, "PLC communication error: read of register 512 failed");
+        assert_eq!(report.causes, ["connection reset"]);
+        assert!(report.exception.is_none());
+
+        let exception = AppError::ModbusException(ModbusException::IllegalFunction, String::new());
+        assert_eq!(exception.report().exception, Some(ModbusException::IllegalFunction));
+        let json = serde_json::to_value(exception.report()).unwrap();
+        assert_eq!(json["code"], "modbus_exception");
+        assert_eq!(json["exception"], "illegal_function");
+        assert!(json.get("causes").is_none());
+    }
 }
@@ -0,0 +1,491 @@
+use chrono::Utc;
+use openssl::base64;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{error, info, warn};
+
+use crate::helper::AppError;
+use crate::protocol;
+
+const KEY_FILE: &str = "agent.key";
+/// New key of a rotation the backend has not confirmed yet.
+const PENDING_KEY_FILE: &str = "agent.key.pending";
+const CREDENTIAL_FILE: &str = "credential.json";
+const ROTATION_CHECK_SECS: u64 = 3600;
+
+/// Credential issued by the backend on enrollment or rotation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Credential {
+    pub agent_id: String,
+    /// Unix seconds; set locally when the credential is stored.
+    #[serde(default)]
+    pub issued_at: i64,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct EnrollRequest<'a> {
+    code: &'a str,
+    public_key: String,
+}
+
+#[derive(Serialize)]
+struct RotateRequest {
+    agent_id: String,
+    public_key: String,
+    timestamp: i64,
+    nonce: String,
+    signature: String,
+}
+
+/// Keypair and credential persisted in the identity directory.
+pub struct Identity {
+    dir: PathBuf,
+    backend_url: String,
+    http: reqwest::Client,
+    key: RwLock<PKey<Private>>,
+    credential: RwLock<Credential>,
+}
+
+/// How the agent authenticates its Socket.IO handshake.
+#[derive(Clone)]
+pub enum AgentAuth {
+    /// Shared `FINGERPRINT` token, kept for agents that are not enrolled yet.
+    Legacy(String),
+    Signed(Arc<Identity>),
+}
+
+impl AgentAuth {
+    /// The id the backend knows this agent by.
+    pub fn agent_id(&self) -> String {
+        match self {
+            AgentAuth::Legacy(token) => token.clone(),
+            AgentAuth::Signed(identity) => identity.agent_id(),
+        }
+    }
+
+    /// Builds a fresh auth payload; signed payloads carry a new nonce each time.
+    /// Both schemes announce the agent's version and capabilities.
+    pub fn payload(&self) -> Result<Value, AppError> {
+        let mut payload = match self {
+            AgentAuth::Legacy(token) => json!({
+                "token": token,
+                "type": "agent"
+            }),
+            AgentAuth::Signed(identity) => identity.auth_payload()?,
+        };
+        if let (Some(payload), Value::Object(handshake)) =
+            (payload.as_object_mut(), protocol::handshake())
+        {
+            payload.extend(handshake);
+        }
+        Ok(payload)
+    }
+}
+
+impl Identity {
+    /// Loads the stored identity, enrolling with `enrollment_code` on first boot.
+    pub async fn load_or_enroll(
+        dir: &Path,
+        backend_url: &str,
+        http: reqwest::Client,
+        enrollment_code: Option<&str>,
+    ) -> Result<Option<Self>, AppError> {
+        let key_path = dir.join(KEY_FILE);
+        let credential_path = dir.join(CREDENTIAL_FILE);
+
+        if credential_path.exists() {
+            let key = read_key(&key_path)?;
+            let credential: Credential = serde_json::from_slice(&read(&credential_path)?)
+                .map_err(|e| identity_error(format!("Corrupt credential file: {}", e)))?;
+            info!(agent_id = %credential.agent_id, "Loaded agent identity");
+            let identity = Self::new(dir, backend_url, http, key, credential);
+            if identity.rotation_pending() {
+                info!("Finishing an interrupted credential rotation");
+                if let Err(e) = identity.rotate().await {
+                    warn!("Credential rotation is still pending, will retry: {}", e);
+                }
+            }
+            return Ok(Some(identity));
+        }
+
+        let Some(code) = enrollment_code else {
+            return Ok(None);
+        };
+
+        fs::create_dir_all(dir)
+            .map_err(|e| identity_error(format!("Failed to create {}: {}", dir.display(), e)))?;
+        let key = if key_path.exists() {
+            read_key(&key_path)?
+        } else {
+            let key = PKey::generate_ed25519().map_err(|e| identity_error(e.to_string()))?;
+            write_private(&key_path, &key.private_key_to_pem_pkcs8().map_err(|e| identity_error(e.to_string()))?)?;
+            key
+        };
+
+        let request = EnrollRequest {
+            code,
+            public_key: public_pem(&key)?,
+        };
+        let mut credential: Credential = post(&http, &format!("{}/agent/enroll", backend_url), &request).await?;
+        credential.issued_at = Utc::now().timestamp();
+        write_private(&credential_path, &to_json(&credential)?)?;
+        info!(agent_id = %credential.agent_id, "Enrolled agent");
+
+        Ok(Some(Self::new(dir, backend_url, http, key, credential)))
+    }
+
+    fn new(
+        dir: &Path,
+        backend_url: &str,
+        http: reqwest::Client,
+        key: PKey<Private>,
+        credential: Credential,
+    ) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            backend_url: backend_url.to_string(),
+            http,
+            key: RwLock::new(key),
+            credential: RwLock::new(credential),
+        }
+    }
+
+    pub fn agent_id(&self) -> String {
+        self.credential.read().expect("credential lock").agent_id.clone()
+    }
+
+    fn auth_payload(&self) -> Result<Value, AppError> {
+        let agent_id = self.agent_id();
+        let (timestamp, nonce, signature) = self.sign_now(&agent_id, "")?;
+        Ok(json!({
+            "type": "agent",
+            "token": agent_id,
+            "timestamp": timestamp,
+            "nonce": nonce,
+            "signature": signature
+        }))
+    }
+
+    /// Signs `agent_id|timestamp|nonce|extra` with the current key.
+    fn sign_now(&self, agent_id: &str, extra: &str) -> Result<(i64, String, String), AppError> {
+        sign(&self.key.read().expect("key lock"), agent_id, extra)
+    }
+
+    fn rotation_due(&self, rotate_after_secs: i64) -> bool {
+        let credential = self.credential.read().expect("credential lock");
+        let now = Utc::now().timestamp();
+        let expiring = credential
+            .expires_at
+            .is_some_and(|expires_at| expires_at - now < rotate_after_secs / 4);
+        now - credential.issued_at >= rotate_after_secs || expiring
+    }
+
+    fn rotation_pending(&self) -> bool {
+        self.dir.join(PENDING_KEY_FILE).exists()
+    }
+
+    /// Replaces the keypair: the new public key is sent signed by the old one.
+    /// The new key is saved as pending first and promoted once the backend
+    /// accepts it, so a crash in between cannot lock the agent out.
+    pub async fn rotate(&self) -> Result<(), AppError> {
+        let pending_path = self.dir.join(PENDING_KEY_FILE);
+        if pending_path.exists() {
+            return self.resume_rotation(read_key(&pending_path)?).await;
+        }
+
+        let new_key = PKey::generate_ed25519().map_err(|e| identity_error(e.to_string()))?;
+        write_private(
+            &pending_path,
+            &new_key.private_key_to_pem_pkcs8().map_err(|e| identity_error(e.to_string()))?,
+        )?;
+        let old_key = self.key.read().expect("key lock").clone();
+        let credential = self.register(&new_key, &old_key).await?;
+        self.promote(new_key, credential)
+    }
+
+    /// Finishes a rotation whose outcome is unknown. The backend holds either
+    /// the old key or the pending one, so the pending key is sent signed by
+    /// the old key and, if that is refused, by itself.
+    async fn resume_rotation(&self, pending: PKey<Private>) -> Result<(), AppError> {
+        let old_key = self.key.read().expect("key lock").clone();
+        let credential = match self.register(&pending, &old_key).await {
+            Ok(credential) => credential,
+            Err(e) => {
+                warn!("Old key was refused, retrying with the pending key: {}", e);
+                self.register(&pending, &pending).await?
+            }
+        };
+        self.promote(pending, credential)
+    }
+
+    /// Sends `new_key` to the backend, signed by `signing_key`.
+    async fn register(
+        &self,
+        new_key: &PKey<Private>,
+        signing_key: &PKey<Private>,
+    ) -> Result<Credential, AppError> {
+        let public_key = public_pem(new_key)?;
+        let agent_id = self.agent_id();
+        let (timestamp, nonce, signature) = sign(signing_key, &agent_id, &public_key)?;
+
+        let request = RotateRequest {
+            agent_id,
+            public_key,
+            timestamp,
+            nonce,
+            signature,
+        };
+        let mut credential: Credential =
+            post(&self.http, &format!("{}/agent/rotate", self.backend_url), &request).await?;
+        credential.issued_at = Utc::now().timestamp();
+        Ok(credential)
+    }
+
+    /// Makes the accepted pending key the agent's key.
+    fn promote(&self, key: PKey<Private>, credential: Credential) -> Result<(), AppError> {
+        let key_path = self.dir.join(KEY_FILE);
+        fs::rename(self.dir.join(PENDING_KEY_FILE), &key_path)
+            .map_err(|e| identity_error(format!("Failed to write {}: {}", key_path.display(), e)))?;
+        write_private(&self.dir.join(CREDENTIAL_FILE), &to_json(&credential)?)?;
+
+        *self.key.write().expect("key lock") = key;
+        *self.credential.write().expect("credential lock") = credential;
+        info!("Rotated agent credential");
+        Ok(())
+    }
+
+    /// Periodically rotates the credential once it is `rotate_after_secs` old.
+    pub fn spawn_rotation(self: Arc<Self>, rotate_after_secs: i64) {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_secs(ROTATION_CHECK_SECS));
+            loop {
+                interval.tick().await;
+                if !self.rotation_due(rotate_after_secs) && !self.rotation_pending() {
+                    continue;
+                }
+                if let Err(e) = self.rotate().await {
+                    error!("Credential rotation failed, will retry: {}", e);
+                }
+            }
+        });
+    }
+}
+
+async fn post<B: Serialize, R: for<'de> Deserialize<'de>>(
+    http: &reqwest::Client,
+    url: &str,
+    body: &B,
+) -> Result<R, AppError> {
+    let response = http
+        .post(url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| identity_error(format!("Request to {} failed: {}", url, e)))?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        warn!(%status, "Backend rejected identity request");
+        return Err(identity_error(format!("{} returned {}: {}", url, status, text)));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| identity_error(format!("Invalid response from {}: {}", url, e)))
+}
+
+/// Signs `agent_id|timestamp|nonce|extra` with `key` and a fresh nonce.
+fn sign(key: &PKey<Private>, agent_id: &str, extra: &str) -> Result<(i64, String, String), AppError> {
+    let timestamp = Utc::now().timestamp();
+    let mut nonce = [0u8; 16];
+    rand_bytes(&mut nonce).map_err(|e| identity_error(e.to_string()))?;
+    let nonce: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let message = format!("{}|{}|{}|{}", agent_id, timestamp, nonce, extra);
+    let signature = Signer::new_without_digest(key)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(message.as_bytes()))
+        .map_err(|e| identity_error(format!("Failed to sign: {}", e)))?;
+    Ok((timestamp, nonce, base64::encode_block(&signature)))
+}
+
+fn public_pem(key: &PKey<Private>) -> Result<String, AppError> {
+    let pem = key
+        .public_key_to_pem()
+        .map_err(|e| identity_error(e.to_string()))?;
+    String::from_utf8(pem).map_err(|e| identity_error(e.to_string()))
+}
+
+fn read_key(path: &Path) -> Result<PKey<Private>, AppError> {
+    PKey::private_key_from_pem(&read(path)?)
+        .map_err(|e| identity_error(format!("Invalid key {}: {}", path.display(), e)))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, AppError> {
+    fs::read(path).map_err(|e| identity_error(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn to_json(credential: &Credential) -> Result<Vec<u8>, AppError> {
+    serde_json::to_vec_pretty(credential).map_err(|e| identity_error(e.to_string()))
+}
+
+/// Writes through a temp file so a crash never leaves a half-written key.
+/// The file is created readable by the agent user only, never wider.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), AppError> {
+    let tmp = path.with_extension("tmp");
+    let write_error = |e: std::io::Error| identity_error(format!("Failed to write {}: {}", tmp.display(), e));
+    // Left behind by a crash; create_new below must not reuse its mode.
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp).map_err(write_error)?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .map_err(write_error)?;
+    fs::rename(&tmp, path)
+        .map_err(|e| identity_error(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+fn identity_error(message: String) -> AppError {
+    AppError::InternalError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use openssl::pkey::Public;
+    use openssl::sign::Verifier;
+    use std::sync::Mutex;
+
+    type Registered = Arc<Mutex<PKey<Public>>>;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-identity-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_pem(public_pem(key).unwrap().as_bytes()).unwrap()
+    }
+
+    /// Stand-in for the backend's rotate endpoint, holding one agent's key.
+    async fn backend(key: &PKey<Private>) -> (String, Registered) {
+        let registered = Arc::new(Mutex::new(public(key)));
+        let app = Router::new()
+            .route("/agent/rotate", post(rotate))
+            .with_state(registered.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, registered)
+    }
+
+    async fn rotate(
+        State(registered): State<Registered>,
+        Json(request): Json<Value>,
+    ) -> Result<Json<Value>, StatusCode> {
+        let field = |name: &str| request[name].as_str().unwrap_or_default().to_string();
+        let message = format!(
+            "{}|{}|{}|{}",
+            field("agent_id"),
+            request["timestamp"],
+            field("nonce"),
+            field("public_key")
+        );
+        let signature = base64::decode_block(&field("signature")).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let mut registered = registered.lock().unwrap();
+        let valid = Verifier::new_without_digest(&registered)
+            .and_then(|mut verifier| verifier.verify_oneshot(&signature, message.as_bytes()))
+            .unwrap_or(false);
+        if !valid {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        *registered = PKey::public_key_from_pem(field("public_key").as_bytes()).unwrap();
+        Ok(Json(json!({ "agent_id": field("agent_id") })))
+    }
+
+    fn identity(dir: &Path, url: &str, key: PKey<Private>) -> Identity {
+        let credential = Credential {
+            agent_id: "agent-1".to_string(),
+            issued_at: 0,
+            expires_at: None,
+        };
+        Identity::new(dir, url, reqwest::Client::new(), key, credential)
+    }
+
+    fn stored_key(dir: &Path) -> PKey<Private> {
+        read_key(&dir.join(KEY_FILE)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotation_promotes_the_new_key_once_the_backend_accepts_it() {
+        let dir = scratch_dir("rotate");
+        let old_key = PKey::generate_ed25519().unwrap();
+        let (url, registered) = backend(&old_key).await;
+        let identity = identity(&dir, &url, old_key.clone());
+
+        identity.rotate().await.unwrap();
+        let key = identity.key.read().unwrap().clone();
+        assert!(!key.public_eq(&old_key));
+        assert!(registered.lock().unwrap().public_eq(&key));
+        assert!(stored_key(&dir).public_eq(&key));
+        assert!(!identity.rotation_pending());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_rotation_is_finished_with_the_pending_key() {
+        let dir = scratch_dir("resume");
+        let old_key = PKey::generate_ed25519().unwrap();
+        let pending = PKey::generate_ed25519().unwrap();
+        write_private(&dir.join(PENDING_KEY_FILE), &pending.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        // The crash came after the backend switched to the pending key.
+        let (url, registered) = backend(&pending).await;
+        let identity = identity(&dir, &url, old_key);
+
+        identity.rotate().await.unwrap();
+        assert!(identity.key.read().unwrap().public_eq(&pending));
+        assert!(registered.lock().unwrap().public_eq(&pending));
+        assert!(stored_key(&dir).public_eq(&pending));
+        assert!(!identity.rotation_pending());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_only_ever_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("private");
+        let path = dir.join(KEY_FILE);
+        let stale = path.with_extension("tmp");
+        fs::write(&stale, b"left over").unwrap();
+        fs::set_permissions(&stale, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!stale.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,31 @@
+//! Edge agent bridging Modbus PLCs and the backend's Socket.IO uplink. The
+//! `agent` binary wires these modules together; integration tests drive
+//! them against a simulated PLC and an in-process backend.
+
+pub mod plc_io;
+pub mod mdb_client;
+pub mod state;
+pub mod agent;
+pub mod monitoring;
+pub mod ws;
+pub mod helper;
+pub mod config;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod tls;
+pub mod identity;
+pub mod envelope;
+pub mod reload;
+pub mod device;
+pub mod queue;
+pub mod uplink;
+pub mod shutdown;
+pub mod capture;
+pub mod protocol;
+pub mod wire;
+pub mod mqtt;
+pub mod sparkplug;
+pub mod opcua_server;
+
+pub use config::ChEvent;
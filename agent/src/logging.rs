@@ -0,0 +1,136 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::helper::AppError;
+
+/// Handle used to swap the active filter at runtime (see `ChEvent::SetLogLevel`).
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(AppError::ValidationError(format!(
+                "unknown log format {}, expected text or json",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct LogOptions {
+    /// `RUST_LOG`-style directives, e.g. `info,agent::plc_io=trace`.
+    pub filter: String,
+    pub format: LogFormat,
+    /// When set, logs go to a daily-rotated file in this directory instead of stdout.
+    pub dir: Option<String>,
+    pub max_files: usize,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+            dir: None,
+            max_files: 7,
+        }
+    }
+}
+
+/// Installs the global subscriber. The returned guard must be kept alive
+/// for the lifetime of the process so buffered file output is flushed.
+pub fn init(options: &LogOptions) -> Result<(LogHandle, Option<WorkerGuard>), AppError> {
+    let filter = parse_filter(&options.filter)?;
+    let (filter_layer, handle) = reload::Layer::new(filter);
+
+    let (writer, guard) = match &options.dir {
+        Some(dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("agent")
+                .filename_suffix("log")
+                .max_log_files(options.max_files)
+                .build(dir)
+                .map_err(|e| AppError::InternalError(format!("Failed to open log dir: {}", e)))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let fmt_layer = if options.format == LogFormat::Json {
+        fmt::layer().json().with_writer(writer).boxed()
+    } else {
+        fmt::layer()
+            .with_ansi(options.dir.is_none())
+            .with_writer(writer)
+            .boxed()
+    };
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt_layer)
+        .try_init()
+        .map_err(|e| AppError::InternalError(format!("Failed to install logger: {}", e)))?;
+
+    Ok((handle, guard))
+}
+
+pub fn set_filter(handle: &LogHandle, directives: &str) -> Result<(), AppError> {
+    let filter = parse_filter(directives)?;
+    handle
+        .reload(filter)
+        .map_err(|e| AppError::InternalError(format!("Failed to reload log filter: {}", e)))
+}
+
+pub fn validate_filter(directives: &str) -> Result<(), AppError> {
+    parse_filter(directives).map(|_| ())
+}
+
+fn parse_filter(directives: &str) -> Result<EnvFilter, AppError> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| AppError::ValidationError(format!("Invalid log filter {}: {}", directives, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_are_checked_before_they_are_applied() {
+        assert!(validate_filter("info").is_ok());
+        assert!(validate_filter("warn,agent::plc_io=trace").is_ok());
+        assert!(matches!(
+            validate_filter("agent=loud"),
+            Err(AppError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn formats_parse_from_their_config_names() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,64 +1,220 @@
-use agent::Agent;
-use config::{ChEvent, MESSAGE_CHANNEL_SIZE};
-use state::SharedState;
-use ws::setup_socket_io;
+use agent::agent::Agent;
+use agent::config::{AgentConfig, ChEvent, UplinkTransport, DEFAULT_CONFIG_PATH};
+use agent::envelope::CommandVerifier;
+use agent::identity::{AgentAuth, Identity};
+use agent::state::SharedState;
+use agent::mqtt::MqttLink;
+use agent::opcua_server::OpcUaServer;
+use agent::protocol::{Negotiated, PROTOCOL_VERSION};
+use agent::tls::Route;
+use agent::uplink::{Link, Uplink};
+use agent::ws::setup_socket_io;
+use agent::{logging, mdb_client, metrics, reload, shutdown, tls, wire};
+use reqwest::header::{HeaderMap, HeaderValue, HOST};
+use std::collections::HashMap;
 use std::env;
 use std::error::Error as StdError;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc;
 use dotenv::dotenv;
+use tracing::{info, warn};
 
-mod plc_io;
-mod mdb_client;
-mod state;
-mod agent;
-mod monitoring;
-mod ws;
-mod helper;
-mod config;
+struct CliArgs {
+    config_path: PathBuf,
+    check_config: bool,
+    print_schema: bool,
+}
+
+fn parse_args() -> Result<CliArgs, String> {
+    let mut config_path = env::var("AGENT_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let mut check_config = false;
+    let mut print_schema = false;
 
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => {
+                config_path = args.next().map(PathBuf::from).ok_or("--config needs a path")?;
+            }
+            "--check-config" => check_config = true,
+            "--print-schema" => print_schema = true,
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(CliArgs {
+        config_path,
+        check_config,
+        print_schema,
+    })
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn StdError>> {
     dotenv().ok();
-    
-    let hostname = env::var("HOSTNAME").expect("HOSTNAME environment variable is required");
-    let fingerprint= env::var("FINGERPRINT").expect("environment variable is required");
-    let socket_io_url = env::var("WS_URL").expect("WS_URL environment variable is required");
-    
-    println!("Starting agent, connecting to PLC at {} and Socket.IO at {}", hostname, socket_io_url);
-    
-    let ctx = Arc::new(Mutex::new(mdb_client::create_mdb_client(&hostname).await?));
+
+    let args = parse_args()?;
+    if args.print_schema {
+        println!("{}", serde_json::to_string_pretty(&wire::schema())?);
+        return Ok(());
+    }
+    let config = match AgentConfig::load(&args.config_path) {
+        Ok(config) => config,
+        Err(e) if args.check_config => {
+            eprintln!("{}: {}", args.config_path.display(), e);
+            std::process::exit(1);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if args.check_config {
+        println!(
+            "{}: configuration OK ({} device(s), uplink {})",
+            args.config_path.display(),
+            config.devices.len(),
+            uplink_target(&config)
+        );
+        return Ok(());
+    }
+
+    let (log_handle, _log_guard) = logging::init(&config.logging)?;
+
+    info!("Starting agent, connecting to {} PLC(s) and {}", config.devices.len(), uplink_target(&config));
+
+    if config.metrics.enabled {
+        metrics::serve(&config.metrics.listen).await?;
+    }
+
+    if config.devices.is_empty() {
+        warn!("No devices configured, waiting for the backend to push a configuration");
+    }
+
+    let mut devices = HashMap::new();
+    for device in &config.devices {
+        info!(device = %device.name, "Connecting to PLC at {}", device.address);
+        let ctx = mdb_client::connect(device).await?;
+        devices.insert(device.name.clone(), ctx);
+    }
     
     // Channel For event dispathing
-    let (tx, mut rx) = mpsc::channel::<ChEvent>(MESSAGE_CHANNEL_SIZE);
-    
-    let socket = setup_socket_io(&socket_io_url, tx.clone(), &fingerprint).await?;
+    let (tx, mut rx) = mpsc::channel::<ChEvent>(config.buffers.event_channel);
     
     // Create shared state
-    let shared_state = SharedState::new();
-    
-    // Create agent
-    let agent = Agent::new(ctx, ChEvent::Wait, socket, shared_state);
-    let agent_arc = Arc::new(Mutex::new(agent));
-    
-    Agent::start_monitoring(agent_arc.clone()).await
-        .map_err(|e| Box::new(e) as Box<dyn StdError>)?;
+    let shared_state = SharedState::new(config.default_device());
+
+    let (auth, agent_id) = match config.uplink.transport {
+        UplinkTransport::SocketIo => {
+            // Enrollment and the socket share one route, and so one tunnel.
+            let route = config.uplink.tls.route(&tls::http_url(&config.uplink.url)).await?;
+            let auth = socket_io_auth(&config, &route).await?;
+            let agent_id = auth.agent_id();
+            (Some((auth, route)), agent_id)
+        }
+        UplinkTransport::Mqtt => (None, config.uplink.mqtt.client_id()),
+    };
+    let verifier = Arc::new(CommandVerifier::from_key_file(
+        config.uplink.command_key_file.as_deref(),
+        &agent_id,
+    )?);
+
+    let (link, protocol) = match auth {
+        Some((auth, route)) => {
+            let protocol = Negotiated::default();
+            let socket = setup_socket_io(
+                &route,
+                tx.clone(),
+                auth,
+                &config.uplink.tls,
+                verifier,
+                protocol.clone(),
+            )
+            .await?;
+            (Link::SocketIo(socket), protocol)
+        }
+        None => {
+            let link = MqttLink::connect(&config, tx.clone(), verifier, shared_state.clone()).await?;
+            (Link::Mqtt(link), Negotiated::fixed(PROTOCOL_VERSION))
+        }
+    };
+
+    let uplink = Uplink::spawn(link, shared_state.clone(), protocol, config.buffers.uplink_channel);
+
+    let (writes_tx, writes_rx) = mpsc::channel(config.buffers.event_channel);
+    let opcua = if config.opcua.enabled {
+        let server = OpcUaServer::start(
+            &config.opcua,
+            shared_state.clone(),
+            uplink.samples(),
+            writes_tx,
+        )
+        .await?;
+        Some(server)
+    } else {
+        None
+    };
+
+    // Create agent, which starts one actor per device
+    let mut agent = Agent::new(devices, ChEvent::Wait, uplink, shared_state, log_handle, config)
+        .with_local_writes(writes_rx);
     
-    println!("Agent initialized successfully");
+    reload::spawn_watchers(args.config_path.clone(), tx.clone());
+
+    info!("Agent initialized successfully");
     
-    // Main event loop
-    loop {
-        if let Some(event) = rx.recv().await {
-            let mut agent_lock = agent_arc.lock().await;
-            agent_lock.event = event;
-            println!("Processing event: {:?}", agent_lock.event);
-            
-            if let Err(e) = agent_lock.handle_master_event().await {
-                eprintln!("Error handling event: {}", e);
-            }
-        }
+    let reason = agent.run(&mut rx, &args.config_path, shutdown::signal()).await;
+    shutdown::run(&mut agent, &mut rx, reason).await;
+    if let Some(server) = opcua {
+        server.stop();
+    }
+    Ok(())
+}
+
+fn uplink_target(config: &AgentConfig) -> String {
+    match config.uplink.transport {
+        UplinkTransport::SocketIo => format!("Socket.IO at {}", config.uplink.url),
+        UplinkTransport::Mqtt => format!("MQTT broker at {}", config.uplink.mqtt.url),
     }
 }
 
+/// Enrolls with the backend if needed and picks the Socket.IO credentials.
+async fn socket_io_auth(config: &AgentConfig, route: &Route) -> Result<AgentAuth, Box<dyn StdError>> {
+    let mut http = reqwest::Client::builder();
+    if let Some(host) = &route.host {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_str(host)?);
+        http = http.default_headers(headers);
+    } else if tls::is_secure(&route.url) {
+        http = http.use_preconfigured_tls(config.uplink.tls.connector()?);
+    }
+    let http = http.build()?;
 
+    let identity = Identity::load_or_enroll(
+        &config.uplink.identity_dir,
+        &route.url,
+        http,
+        config.uplink.enrollment_code.as_deref(),
+    )
+    .await?;
+    match identity {
+        Some(identity) => {
+            let identity = Arc::new(identity);
+            let rotate_after_secs = config
+                .uplink
+                .rotate_days
+                .checked_mul(24 * 3600)
+                .ok_or("uplink.rotate_days is too large")?;
+            identity.clone().spawn_rotation(rotate_after_secs);
+            Ok(AgentAuth::Signed(identity))
+        }
+        None => {
+            let fingerprint = config
+                .uplink
+                .fingerprint
+                .clone()
+                .ok_or("agent is not enrolled: set uplink.enrollment_code (or the legacy FINGERPRINT)")?;
+            warn!("Agent is not enrolled, authenticating with the shared FINGERPRINT token");
+            Ok(AgentAuth::Legacy(fingerprint))
+        }
+    }
+}
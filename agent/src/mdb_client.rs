@@ -1,13 +1,31 @@
 use anyhow::Result;
+use std::time::Duration;
 use tokio_modbus::client::{tcp, Context};
 use tokio::net::lookup_host;
 use tokio_modbus::prelude::{*};
 
-pub async fn create_mdb_client(hostname: &str) -> Result<Context> {
+use crate::config::DeviceConfig;
+use crate::helper::AppError;
+
+pub async fn create_mdb_client(hostname: &str, unit_id: u8) -> Result<Context> {
     let mut addrs = lookup_host(hostname).await?;
     let addr = addrs.next().ok_or_else(|| anyhow::anyhow!("DNS resolution failed"))?;
 
-    let ctx = tcp::connect_slave(addr, Slave(1)).await?;
+    let ctx = tcp::connect_slave(addr, Slave(unit_id)).await?;
     Ok(ctx)
 }
 
+/// Connects to a configured device within its `timeout_ms`.
+pub async fn connect(device: &DeviceConfig) -> Result<Context, AppError> {
+    let timeout = Duration::from_millis(device.timeout_ms);
+    match tokio::time::timeout(timeout, create_mdb_client(&device.address, device.unit_id)).await {
+        Ok(result) => result.map_err(|e| AppError::PlcError {
+            message: format!("failed to connect to {}", device.name),
+            source: Some(e.into()),
+        }),
+        Err(_) => Err(AppError::ConnectTimeout(format!(
+            "{} at {} after {} ms",
+            device.name, device.address, device.timeout_ms
+        ))),
+    }
+}
@@ -0,0 +1,169 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+use crate::helper::AppError;
+
+pub struct Metrics {
+    registry: Registry,
+    pub sensor_reads: IntCounterVec,
+    pub sensor_read_failures: IntCounterVec,
+    pub modbus_round_trip: HistogramVec,
+    pub modbus_exceptions: IntCounterVec,
+    pub poll_cycle_duration: HistogramVec,
+    pub poll_cycle_overruns: IntCounterVec,
+    pub poll_reads_dropped: IntCounterVec,
+    pub event_queue_depth: IntGauge,
+    pub socketio_connected: IntGauge,
+    pub socketio_reconnects: IntCounter,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Process-wide metrics, registered on first use.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("agent".to_string()), None)?;
+
+        let sensor_reads = IntCounterVec::new(
+            Opts::new("sensor_reads_total", "Sensor reads attempted"),
+            &["device", "sensor"],
+        )?;
+        let sensor_read_failures = IntCounterVec::new(
+            Opts::new("sensor_read_failures_total", "Sensor reads that produced a bad-quality sample"),
+            &["device", "sensor", "quality"],
+        )?;
+        let modbus_round_trip = HistogramVec::new(
+            HistogramOpts::new("modbus_round_trip_seconds", "Modbus request round-trip time")
+                .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["device", "sensor"],
+        )?;
+        let modbus_exceptions = IntCounterVec::new(
+            Opts::new("modbus_exceptions_total", "Exception responses returned by the PLC"),
+            &["device", "sensor", "exception"],
+        )?;
+        let poll_cycle_duration = HistogramVec::new(
+            HistogramOpts::new("poll_cycle_duration_seconds", "Time taken by one poll cycle")
+                .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0]),
+            &["device"],
+        )?;
+        let poll_cycle_overruns = IntCounterVec::new(
+            Opts::new("poll_cycle_overruns_total", "Poll cycles that took longer than the interval"),
+            &["device"],
+        )?;
+        let poll_reads_dropped = IntCounterVec::new(
+            Opts::new("poll_reads_dropped_total", "Poll reads dropped after missing their deadline"),
+            &["device"],
+        )?;
+        let event_queue_depth = IntGauge::new("event_queue_depth", "Commands waiting in the event channel")?;
+        let socketio_connected = IntGauge::new("socketio_connected", "1 while the Socket.IO link is up")?;
+        let socketio_reconnects = IntCounter::new("socketio_reconnects_total", "Socket.IO reconnect attempts")?;
+
+        registry.register(Box::new(sensor_reads.clone()))?;
+        registry.register(Box::new(sensor_read_failures.clone()))?;
+        registry.register(Box::new(modbus_round_trip.clone()))?;
+        registry.register(Box::new(modbus_exceptions.clone()))?;
+        registry.register(Box::new(poll_cycle_duration.clone()))?;
+        registry.register(Box::new(poll_cycle_overruns.clone()))?;
+        registry.register(Box::new(poll_reads_dropped.clone()))?;
+        registry.register(Box::new(event_queue_depth.clone()))?;
+        registry.register(Box::new(socketio_connected.clone()))?;
+        registry.register(Box::new(socketio_reconnects.clone()))?;
+
+        Ok(Self {
+            registry,
+            sensor_reads,
+            sensor_read_failures,
+            modbus_round_trip,
+            modbus_exceptions,
+            poll_cycle_duration,
+            poll_cycle_overruns,
+            poll_reads_dropped,
+            event_queue_depth,
+            socketio_connected,
+            socketio_reconnects,
+        })
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        buffer
+    }
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+pub async fn serve(addr: &str) -> Result<(), AppError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| AppError::InternalError(format!("Failed to bind metrics on {}: {}", addr, e)))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Metrics listener error: {}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = match stream.read(&mut buf).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!(%peer, "Metrics request failed: {}", e);
+                        return;
+                    }
+                };
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let response = if request.starts_with("GET /metrics ") {
+                    let body = metrics().render();
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(&body);
+                    response
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                };
+                if let Err(e) = stream.write_all(&response).await {
+                    debug!(%peer, "Failed to write metrics response: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_with_the_agent_prefix_and_labels() {
+        let metrics = Metrics::new().unwrap();
+        metrics.sensor_reads.with_label_values(&["plc", "level"]).inc();
+        metrics.socketio_connected.set(1);
+
+        let text = String::from_utf8(metrics.render()).unwrap();
+        assert!(text.contains(r#"agent_sensor_reads_total{device="plc",sensor="level"} 1"#));
+        assert!(text.contains("agent_socketio_connected 1"));
+    }
+}
@@ -1,108 +1,325 @@
-use chrono::Local;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
-use crate::agent::Agent;
-use crate::config::{SensorConfig, MONITOR_INTERVAL_MS};
-use crate::helper::AppError;
-use crate::plc_io;
+use crate::config::SensorConfig;
+use crate::device::DeviceActor;
+use crate::helper::{now_timestamp, AppError, ErrorReport};
+use crate::metrics::metrics;
+use crate::plc_io::{self, Quality, Request};
+use crate::queue::PollQueue;
 
-pub async fn monitor_plc_loop(agent: Arc<Mutex<Agent>>) -> Result<(), AppError> {
-    let mut interval =
-        tokio::time::interval(tokio::time::Duration::from_millis(MONITOR_INTERVAL_MS));
+/// Cycles a slow sensor is currently skipped for, doubled on every slow read.
+#[derive(Default)]
+pub struct Backoff {
+    cycles: u32,
+    skip_remaining: u32,
+}
 
-    loop {
-        interval.tick().await;
+impl Backoff {
+    /// Uses up one skipped cycle; false once the sensor is due again.
+    fn skip(&mut self) -> bool {
+        if self.skip_remaining == 0 {
+            return false;
+        }
+        self.skip_remaining -= 1;
+        true
+    }
 
-        // Get a snapshot of current sensors
-        let sensors = {
-            let agent_lock = agent.lock().await;
-            let state_lock = agent_lock.state.lock().await;
+    /// Doubles the back-off after a slow read, up to `max_cycles`, and
+    /// returns the number of cycles the sensor is now skipped for.
+    fn slow_read(&mut self, max_cycles: u32) -> u32 {
+        self.cycles = (self.cycles * 2).max(1).min(max_cycles);
+        self.skip_remaining = self.cycles;
+        self.cycles
+    }
+}
 
-            if state_lock.paused_agent {
-                println!("im locekdd");
-                continue;
-            }
+/// Sent when a poll cycle takes longer than the device's interval.
+#[derive(Serialize, JsonSchema)]
+pub struct PollOverrun {
+    pub device: String,
+    pub cycle_ms: u64,
+    pub interval_ms: u64,
+    pub slow_sensors: Vec<String>,
+}
 
-            state_lock.registered_sensors.clone()
-        };
+/// Polls every sensor of the actor's device once and records the cycle.
+pub async fn poll_cycle(actor: &mut DeviceActor) {
+    // Get a snapshot of this device's sensors
+    let sensors: Vec<SensorConfig> = {
+        let state = actor.state.lock().await;
+        if state.paused_agent {
+            debug!("Agent paused, skipping poll cycle");
+            return;
+        }
+        state
+            .registered_sensors
+            .iter()
+            .filter(|s| s.belongs_to(&actor.name, actor.is_default))
+            .cloned()
+            .collect()
+    };
+    if sensors.is_empty() {
+        return;
+    }
+    actor.backoff.retain(|id, _| sensors.iter().any(|s| &s.id == id));
+
+    let started = Instant::now();
+    let interval = Duration::from_millis(actor.settings.interval_ms);
+    let count = sensors.len();
+    let start = actor.poll_start % count;
+    let mut requests = PollQueue::new(sensors, start, started + interval);
+    let Some(slow_sensors) = process_all_sensors(actor, &mut requests).await else {
+        return;
+    };
+    // Unchanged when every read was served, otherwise the next cycle starts
+    // with the first dropped sensor.
+    actor.poll_start = (start + requests.served()) % count;
 
-        if !sensors.is_empty() {
-            // println!("Processing sensors: {:?}", sensors);
-            process_all_sensors(agent.clone(), sensors).await?;
+    let elapsed = started.elapsed();
+    let interval_ms = actor.settings.interval_ms;
+    let overrun = elapsed > interval;
+    metrics()
+        .poll_cycle_duration
+        .with_label_values(&[&actor.name])
+        .observe(elapsed.as_secs_f64());
+    actor
+        .state
+        .lock()
+        .await
+        .record_cycle(&actor.name, elapsed, overrun);
+
+    if overrun {
+        metrics()
+            .poll_cycle_overruns
+            .with_label_values(&[&actor.name])
+            .inc();
+        warn!(
+            cycle_ms = elapsed.as_millis() as u64,
+            interval_ms,
+            ?slow_sensors,
+            "Poll cycle overran its interval"
+        );
+        let report = PollOverrun {
+            device: actor.name.clone(),
+            cycle_ms: elapsed.as_millis() as u64,
+            interval_ms,
+            slow_sensors,
+        };
+        if let Err(e) = actor.uplink.send_json("poll_overrun", &report).await {
+            error!("Failed to report poll overrun: {}", e);
         }
     }
 }
 
+/// Polls every sensor once, running queued commands before each read and
+/// dropping reads that missed their deadline with a stale sample. Returns
+/// the ids of sensors whose read exceeded `slow_read_ms`, or `None` if the
+/// actor started shutting down mid-cycle.
 async fn process_all_sensors(
-    agent: Arc<Mutex<Agent>>,
-    sensors: Vec<SensorConfig>,
-) -> Result<(), AppError> {
-    for sensor in sensors {
-        let agent_clone = agent.clone();
-        let agentlock = agent.clone();
-
-        // Process each sensor in its own task
-        tokio::spawn(async move {
-            if let Err(err) = process_single_sensor(agent_clone, sensor.clone()).await {
-                println!("Socket.IO error: {:?}", err);
-                if err == AppError::SocketIoError("EngineIO Error".to_string()) {
-                    let agent_guard = agentlock.lock().await;
-                    let mut state_lock = agent_guard.state.lock().await;
-                    state_lock.registered_sensors.clear();
+    actor: &mut DeviceActor,
+    requests: &mut PollQueue,
+) -> Option<Vec<String>> {
+    let slow_read = Duration::from_millis(actor.settings.slow_read_ms);
+    let mut slow_sensors = Vec::new();
+
+    // Once the link drops, the rest of the cycle reports stale samples
+    // instead of waiting on a dead connection for every sensor.
+    let mut link_down = false;
+
+    loop {
+        if actor.drain_commands().await {
+            return None;
+        }
+
+        let (request, dropped) = requests.next(Instant::now());
+        if !dropped.is_empty() {
+            warn!(dropped = dropped.len(), "Dropping poll reads that missed their deadline");
+            metrics()
+                .poll_reads_dropped
+                .with_label_values(&[&actor.name])
+                .inc_by(dropped.len() as u64);
+            actor
+                .state
+                .lock()
+                .await
+                .record_dropped_reads(&actor.name, dropped.len() as u64);
+            for request in dropped {
+                if let Err(e) = send_dropped(actor, request.sensor).await {
+                    error!("Failed to send sample: {}", e);
                 }
             }
-        })
-        .await
-        .expect("Error");
+        }
+        let Some(request) = request else {
+            break;
+        };
+        let sensor = request.sensor;
+
+        let adaptive = actor.settings.adaptive;
+        if adaptive && actor.backoff.get_mut(&sensor.id).is_some_and(Backoff::skip) {
+            continue;
+        }
+
+        let sensor_id = sensor.id.clone();
+        let started = Instant::now();
+
+        link_down = match process_single_sensor(actor, sensor, link_down).await {
+            Ok(link_down) => link_down,
+            Err(err) => {
+                error!("Failed to send sample: {}", err);
+                link_down
+            }
+        };
+
+        if started.elapsed() > slow_read {
+            if adaptive {
+                let max_backoff_cycles = actor.settings.max_backoff_cycles;
+                let entry = actor.backoff.entry(sensor_id.clone()).or_default();
+                let skip_cycles = entry.slow_read(max_backoff_cycles);
+                info!(
+                    sensor_id = %sensor_id,
+                    skip_cycles,
+                    "Backing off slow sensor"
+                );
+            }
+            slow_sensors.push(sensor_id);
+        } else {
+            actor.backoff.remove(&sensor_id);
+        }
     }
-    Ok(())
+    Some(slow_sensors)
 }
 
+/// Reads one sensor and emits its sample. Returns whether the PLC link is
+/// down after this read.
 async fn process_single_sensor(
-    agent: Arc<Mutex<Agent>>,
+    actor: &mut DeviceActor,
     sensor: SensorConfig,
+    link_down: bool,
+) -> Result<bool, AppError> {
+    if let Err(e) = sensor.validate() {
+        warn!("Skipping read: {}", e);
+        let last_value = last_value(actor, &sensor.id).await;
+        send_sample(actor, sensor, last_value, Quality::ConfigError, Some(e.report())).await?;
+        return Ok(link_down);
+    }
+
+    if link_down {
+        let last_value = last_value(actor, &sensor.id).await;
+        send_sample(actor, sensor, last_value, Quality::Stale, None).await?;
+        return Ok(true);
+    }
+
+    let request = Request::Read {
+        start_register: sensor.start_register,
+        end_register: sensor.end_register,
+        r_type: sensor.r_type.clone(),
+    };
+    let read = {
+        let device = actor.name.clone();
+        let labels = [device.as_str(), sensor.id.as_str()];
+        metrics().sensor_reads.with_label_values(&labels).inc();
+        let _timer = metrics()
+            .modbus_round_trip
+            .with_label_values(&labels)
+            .start_timer();
+        actor.transact(&request).await
+    };
+
+    match read {
+        Ok(value) => {
+            {
+                let mut state = actor.state.lock().await;
+                state.last_values.insert(sensor.id.clone(), value);
+                state.record_read_success(&actor.name);
+            }
+            send_sample(actor, sensor, value, Quality::Good, None).await?;
+            Ok(false)
+        }
+        Err(failure) => {
+            warn!(sensor_id = %sensor.id, "Failed to read sensor: {}", failure.error);
+            if let Some(exception) = failure.exception {
+                metrics()
+                    .modbus_exceptions
+                    .with_label_values(&[&actor.name, &sensor.id, exception.as_str()])
+                    .inc();
+            }
+            actor.state.lock().await.record_read_failure(
+                &actor.name,
+                failure.error.to_string(),
+                failure.link_down,
+            );
+            let last_value = last_value(actor, &sensor.id).await;
+            let error = failure.error.report();
+            send_sample(actor, sensor, last_value, failure.quality, Some(error)).await?;
+            Ok(failure.link_down)
+        }
+    }
+}
+
+/// Reports the last value of a sensor whose read was dropped as stale.
+async fn send_dropped(actor: &mut DeviceActor, sensor: SensorConfig) -> Result<(), AppError> {
+    let last_value = last_value(actor, &sensor.id).await;
+    let error = AppError::RequestTimeout(format!(
+        "read of sensor {} dropped, the poll cycle overran its interval",
+        sensor.id
+    ));
+    send_sample(actor, sensor, last_value, Quality::Stale, Some(error.report())).await
+}
+
+async fn last_value(actor: &mut DeviceActor, sensor_id: &str) -> u16 {
+    let state = actor.state.lock().await;
+    state.last_values.get(sensor_id).copied().unwrap_or(0)
+}
+
+async fn send_sample(
+    actor: &mut DeviceActor,
+    sensor: SensorConfig,
+    value: u16,
+    quality: Quality,
+    error: Option<ErrorReport>,
 ) -> Result<(), AppError> {
-    let agent_guard = agent.lock().await;
-    let mut slave_ctx = agent_guard.slave_ctx.lock().await;
-
-    let sensor_value = plc_io::read_from_plc(
-        &mut slave_ctx,
-        sensor.start_register,
-        sensor.end_register,
-        sensor.r_type.clone(),
-    )
-    .await
-    .map_err(|e| AppError::PlcError(e.to_string()))?;
-
-    let timestamp = Local::now().format("%y/%m/%d %H:%M:%S").to_string();
-
-    if sensor.s_type == "sensor" {
-        let modbus_data = plc_io::ModbusData {
-            sensor_id: sensor.id.clone(),
-            time: timestamp.clone(),
-            value: sensor_value,
-            key: sensor.label.clone(),
-            register: sensor.register.clone(),
-            s_type: String::from("sensor"),
-            r_type: sensor.r_type,
-        };
-        agent_guard
-            .send_json("monitoring_streamline", &modbus_data)
-            .await?;
-    } else if sensor.s_type == "general" {
-        let modbus_data = plc_io::ModbusData {
-            sensor_id: sensor.id,
-            time: timestamp,
-            value: sensor_value,
-            key: sensor.label,
-            s_type: String::from("general"),
-            register: sensor.register,
-            r_type: sensor.r_type,
-        };
-        agent_guard
-            .send_json("monitoring_streamline", &modbus_data)
-            .await?;
+    if quality != Quality::Good {
+        metrics()
+            .sensor_read_failures
+            .with_label_values(&[&actor.name, &sensor.id, quality.as_str()])
+            .inc();
+    }
+
+    let modbus_data = plc_io::ModbusData {
+        sensor_id: sensor.id,
+        time: now_timestamp(),
+        value,
+        key: sensor.label,
+        register: sensor.register,
+        s_type: sensor.s_type,
+        r_type: sensor.r_type,
+        quality,
+        error,
+    };
+    actor.uplink.send_sample(&modbus_data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_sensors_back_off_exponentially_up_to_the_limit() {
+        let mut backoff = Backoff::default();
+        assert!(!backoff.skip());
+
+        assert_eq!(backoff.slow_read(8), 1);
+        assert!(backoff.skip());
+        assert!(!backoff.skip());
+
+        assert_eq!(backoff.slow_read(8), 2);
+        assert_eq!(backoff.slow_read(8), 4);
+        assert_eq!(backoff.slow_read(8), 8);
+        assert_eq!(backoff.slow_read(8), 8);
+        assert_eq!((0..10).filter(|_| backoff.skip()).count(), 8);
     }
-    Ok(())
 }
@@ -0,0 +1,577 @@
+use rumqttc::v5::mqttbytes::v5::{LastWill as LastWillV5, Packet as PacketV5};
+use rumqttc::v5::mqttbytes::QoS as QoSV5;
+use rumqttc::{LastWill, Packet, QoS, TlsConfiguration, Transport};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error as StdError;
+use std::sync::Arc;
+use std::time::Duration;
+use sysinfo::System;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::config::AgentConfig;
+use crate::envelope::CommandVerifier;
+use crate::helper::AppError;
+use crate::protocol;
+use crate::sparkplug::{EdgeNode, Inbound, Outgoing, SparkplugConfig};
+use crate::state::SharedState;
+use crate::wire::Failure;
+use crate::ChEvent;
+
+/// Pause between reconnect attempts once the broker is gone.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Requests rumqttc may queue before `publish` waits for the event loop.
+const CLIENT_CAPACITY: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+pub enum MqttVersion {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// Where each kind of message goes. `{agent}` is replaced by the client id,
+/// `{event}` by the event name and `{sensor}` by the sensor id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct TopicLayout {
+    /// Samples, one topic per sensor.
+    pub telemetry: String,
+    /// Every other event the agent emits.
+    pub events: String,
+    /// Subscribed for commands, in the same format as over Socket.IO.
+    pub commands: String,
+    /// Retained birth and death messages. Not used with Sparkplug, whose
+    /// NBIRTH and NDEATH take their place.
+    pub status: String,
+}
+
+impl Default for TopicLayout {
+    fn default() -> Self {
+        Self {
+            telemetry: "synk9/{agent}/telemetry/{sensor}".to_string(),
+            events: "synk9/{agent}/events/{event}".to_string(),
+            commands: "synk9/{agent}/commands".to_string(),
+            status: "synk9/{agent}/status".to_string(),
+        }
+    }
+}
+
+/// Broker settings for `uplink.transport = "mqtt"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// `mqtt://host:1883` or `mqtts://host:8883`.
+    pub url: String,
+    pub version: MqttVersion,
+    /// Defaults to `synk9-<hostname>`.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive_secs: u64,
+    pub topics: TopicLayout,
+    pub sparkplug: SparkplugConfig,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            version: MqttVersion::default(),
+            client_id: None,
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            topics: TopicLayout::default(),
+            sparkplug: SparkplugConfig::default(),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if let Err(e) = self.broker() {
+            errors.push(format!("uplink.mqtt.url: {}", e));
+        }
+        if self.keep_alive_secs < 5 {
+            errors.push("uplink.mqtt.keep_alive_secs: must be at least 5".to_string());
+        }
+        if self.username.is_none() && self.password.is_some() {
+            errors.push("uplink.mqtt.password: needs a username".to_string());
+        }
+        if !self.topics.telemetry.contains("{sensor}") {
+            errors.push("uplink.mqtt.topics.telemetry: must contain {sensor}".to_string());
+        }
+        if !self.topics.events.contains("{event}") {
+            errors.push("uplink.mqtt.topics.events: must contain {event}".to_string());
+        }
+        if self.topics.commands.contains(['+', '#']) {
+            errors.push("uplink.mqtt.topics.commands: wildcards are not allowed".to_string());
+        }
+        self.sparkplug.validate(errors);
+    }
+
+    pub fn client_id(&self) -> String {
+        self.client_id.clone().unwrap_or_else(|| {
+            format!("synk9-{}", System::host_name().unwrap_or_else(|| "agent".to_string()))
+        })
+    }
+
+    /// Host, port and whether TLS is used.
+    fn broker(&self) -> Result<(String, u16, bool), String> {
+        if self.url.is_empty() {
+            return Err("required (or set MQTT_URL)".to_string());
+        }
+        let url = Url::parse(&self.url).map_err(|e| e.to_string())?;
+        let secure = match url.scheme() {
+            "mqtt" | "tcp" => false,
+            "mqtts" | "ssl" => true,
+            other => return Err(format!("unsupported scheme {}", other)),
+        };
+        let host = url.host_str().ok_or("missing host")?.to_string();
+        let port = url.port().unwrap_or(if secure { 8883 } else { 1883 });
+        Ok((host, port, secure))
+    }
+}
+
+/// Topic names with the client id filled in.
+#[derive(Debug, Clone)]
+struct Topics {
+    telemetry: String,
+    events: String,
+    commands: String,
+    status: String,
+}
+
+impl Topics {
+    fn new(layout: &TopicLayout, agent: &str) -> Self {
+        let fill = |topic: &str| topic.replace("{agent}", agent);
+        Self {
+            telemetry: fill(&layout.telemetry),
+            events: fill(&layout.events),
+            commands: fill(&layout.commands),
+            status: fill(&layout.status),
+        }
+    }
+
+    fn for_event(&self, event: &str, data: &Value) -> String {
+        match (event, data["sensor_id"].as_str()) {
+            ("monitoring_streamline", Some(sensor)) => self.telemetry.replace("{sensor}", sensor),
+            _ => self.events.replace("{event}", event),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(rumqttc::v5::AsyncClient),
+}
+
+impl Client {
+    async fn publish(&self, topic: &str, retain: bool, payload: Vec<u8>) -> Result<(), AppError> {
+        match self {
+            Client::V311(client) => client
+                .publish(topic, QoS::AtLeastOnce, retain, payload)
+                .await
+                .map_err(client_error),
+            Client::V5(client) => client
+                .publish(topic, QoSV5::AtLeastOnce, retain, payload)
+                .await
+                .map_err(client_error),
+        }
+    }
+
+    async fn publish_all(&self, messages: Vec<Outgoing>) -> Result<(), AppError> {
+        for (topic, payload) in messages {
+            self.publish(&topic, false, payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<(), AppError> {
+        match self {
+            Client::V311(client) => client
+                .subscribe(topic, QoS::AtLeastOnce)
+                .await
+                .map_err(client_error),
+            Client::V5(client) => client
+                .subscribe(topic, QoSV5::AtLeastOnce)
+                .await
+                .map_err(client_error),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<(), AppError> {
+        match self {
+            Client::V311(client) => client.disconnect().await.map_err(client_error),
+            Client::V5(client) => client.disconnect().await.map_err(client_error),
+        }
+    }
+}
+
+/// Boxed: both event loops carry large buffers inline.
+enum EventLoop {
+    V311(Box<rumqttc::EventLoop>),
+    V5(Box<rumqttc::v5::EventLoop>),
+}
+
+/// What the event loop reports, whatever the protocol version.
+enum Incoming {
+    Connected,
+    Message { topic: String, payload: Vec<u8> },
+    /// Our own disconnect request went out.
+    Closed,
+    Other,
+}
+
+impl EventLoop {
+    /// Replaces the will sent with the next CONNECT.
+    fn set_will(&mut self, (topic, payload): Outgoing, retain: bool) {
+        match self {
+            EventLoop::V311(events) => {
+                let will = LastWill::new(topic, payload, QoS::AtLeastOnce, retain);
+                events.mqtt_options.set_last_will(will);
+            }
+            EventLoop::V5(events) => {
+                let will = LastWillV5::new(topic, payload, QoSV5::AtLeastOnce, retain, None);
+                events.options.set_last_will(will);
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Result<Incoming, AppError> {
+        match self {
+            EventLoop::V311(events) => match events.poll().await.map_err(client_error)? {
+                rumqttc::Event::Incoming(Packet::ConnAck(_)) => Ok(Incoming::Connected),
+                rumqttc::Event::Incoming(Packet::Publish(publish)) => Ok(Incoming::Message {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                }),
+                rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => Ok(Incoming::Closed),
+                _ => Ok(Incoming::Other),
+            },
+            EventLoop::V5(events) => match events.poll().await.map_err(client_error)? {
+                rumqttc::v5::Event::Incoming(PacketV5::ConnAck(_)) => Ok(Incoming::Connected),
+                rumqttc::v5::Event::Incoming(PacketV5::Publish(publish)) => Ok(Incoming::Message {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload.to_vec(),
+                }),
+                rumqttc::v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => Ok(Incoming::Closed),
+                _ => Ok(Incoming::Other),
+            },
+        }
+    }
+}
+
+/// Publishes agent events to a broker and takes commands from it.
+pub struct MqttLink {
+    client: Client,
+    topics: Topics,
+    agent: String,
+    sparkplug: Option<Arc<EdgeNode>>,
+    task: JoinHandle<()>,
+}
+
+impl MqttLink {
+    /// Connects to the broker and returns once the first session is up.
+    /// Later disconnects are retried in the background. With Sparkplug
+    /// enabled, `state` supplies the sensors to announce in births.
+    pub async fn connect(
+        agent_config: &AgentConfig,
+        tx: mpsc::Sender<ChEvent>,
+        verifier: Arc<CommandVerifier>,
+        state: Arc<Mutex<SharedState>>,
+    ) -> Result<Self, AppError> {
+        let config = &agent_config.uplink.mqtt;
+        let tls = &agent_config.uplink.tls;
+        let (host, port, secure) = config.broker().map_err(AppError::mqtt)?;
+        let agent = config.client_id();
+        let topics = Topics::new(&config.topics, &agent);
+        let sparkplug = config.sparkplug.enabled.then(|| {
+            Arc::new(EdgeNode::new(
+                &config.sparkplug,
+                &agent,
+                state,
+            ))
+        });
+        // An MQTT session has a single will: Sparkplug hosts need the NDEATH.
+        let (will, will_retain) = match &sparkplug {
+            Some(node) => (node.death(), false),
+            None => {
+                let death = serde_json::to_vec(&json!({ "status": "offline", "agent": agent }))
+                    .expect("death message serializes");
+                ((topics.status.clone(), death), true)
+            }
+        };
+
+        let (host, port, transport) = if secure && !tls.pins.is_empty() {
+            // The tunnel checks the pins on every (re)connect to the broker.
+            let tunnel = tls.tunnel(&host, port).await?;
+            (tunnel.ip().to_string(), tunnel.port(), Transport::tcp())
+        } else if secure {
+            let connector = TlsConfiguration::NativeConnector(tls.connector()?);
+            (host, port, Transport::tls_with_config(connector))
+        } else {
+            if !tls.pins.is_empty() || tls.client_cert.is_some() {
+                warn!("TLS settings are ignored for non-TLS broker {}", config.url);
+            }
+            (host, port, Transport::tcp())
+        };
+        let keep_alive = Duration::from_secs(config.keep_alive_secs);
+
+        let (client, mut events) = match config.version {
+            MqttVersion::V311 => {
+                let mut options = rumqttc::MqttOptions::new(&agent, &host, port);
+                options.set_keep_alive(keep_alive).set_transport(transport);
+                if let Some(username) = &config.username {
+                    options.set_credentials(username, config.password.clone().unwrap_or_default());
+                }
+                let (client, events) = rumqttc::AsyncClient::new(options, CLIENT_CAPACITY);
+                (Client::V311(client), EventLoop::V311(Box::new(events)))
+            }
+            MqttVersion::V5 => {
+                let mut options = rumqttc::v5::MqttOptions::new(&agent, &host, port);
+                options.set_keep_alive(keep_alive).set_transport(transport);
+                if let Some(username) = &config.username {
+                    options.set_credentials(username, config.password.clone().unwrap_or_default());
+                }
+                let (client, events) = rumqttc::v5::AsyncClient::new(options, CLIENT_CAPACITY);
+                (Client::V5(client), EventLoop::V5(Box::new(events)))
+            }
+        };
+
+        events.set_will(will, will_retain);
+
+        let session = Session {
+            client: client.clone(),
+            topics: topics.clone(),
+            agent: agent.clone(),
+            sparkplug: sparkplug.clone(),
+            tx,
+            verifier,
+        };
+        loop {
+            match events.next().await? {
+                Incoming::Connected => break,
+                _ => continue,
+            }
+        }
+        info!(broker = %config.url, client_id = %agent, "Connected to MQTT broker");
+
+        let task = tokio::spawn(session.run(events));
+        Ok(Self {
+            client,
+            topics,
+            agent,
+            sparkplug,
+            task,
+        })
+    }
+
+    /// Publishes one event with QoS 1. With Sparkplug enabled, samples go
+    /// out as device data only and health reports also as node data.
+    pub async fn emit(&self, event: &str, data: Value) -> Result<(), AppError> {
+        if let Some(node) = &self.sparkplug {
+            match event {
+                "monitoring_streamline" => return self.client.publish_all(node.sample(data).await?).await,
+                "health_check" => self.client.publish_all(vec![node.health(&data)]).await?,
+                _ => {}
+            }
+        }
+        let topic = self.topics.for_event(event, &data);
+        let payload = serde_json::to_vec(&data)
+            .map_err(|e| AppError::InternalError(format!("Failed to encode {}: {}", event, e)))?;
+        self.client.publish(&topic, false, payload).await
+    }
+
+    /// Replaces the birth message with a death message, since a clean
+    /// disconnect does not trigger the will.
+    pub async fn close(&self) {
+        if let Some(node) = &self.sparkplug {
+            if let Err(e) = self.client.publish_all(vec![node.death()]).await {
+                warn!("Failed to publish NDEATH: {}", e);
+            }
+        } else {
+            let death = json!({ "status": "offline", "agent": self.agent });
+            let payload = serde_json::to_vec(&death).expect("death message serializes");
+            if let Err(e) = self.client.publish(&self.topics.status, true, payload).await {
+                warn!("Failed to publish death message: {}", e);
+            }
+        }
+        if let Err(e) = self.client.disconnect().await {
+            warn!("Failed to disconnect from MQTT broker: {}", e);
+        }
+        // The event loop writes everything queued before the disconnect.
+        for _ in 0..50 {
+            if self.task.is_finished() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        self.task.abort();
+    }
+}
+
+/// State the event loop needs to serve a broker session.
+struct Session {
+    client: Client,
+    topics: Topics,
+    agent: String,
+    sparkplug: Option<Arc<EdgeNode>>,
+    tx: mpsc::Sender<ChEvent>,
+    verifier: Arc<CommandVerifier>,
+}
+
+impl Session {
+    /// Runs on every (re)connect: subscriptions and the birth message are
+    /// not kept by the broker across clean sessions. With Sparkplug, the
+    /// NBIRTH is the only birth, matching the NDEATH will; a retained status
+    /// would never be replaced by a death.
+    async fn established(&self) {
+        if let Err(e) = self.client.subscribe(&self.topics.commands).await {
+            error!(topic = %self.topics.commands, "Failed to subscribe to commands: {}", e);
+        }
+        if let Some(node) = &self.sparkplug {
+            for filter in node.command_filters() {
+                if let Err(e) = self.client.subscribe(&filter).await {
+                    error!(topic = %filter, "Failed to subscribe to Sparkplug commands: {}", e);
+                }
+            }
+            self.rebirth(node).await;
+            return;
+        }
+        let mut birth = protocol::handshake();
+        birth["status"] = json!("online");
+        birth["agent"] = json!(self.agent);
+        let payload = serde_json::to_vec(&birth).expect("birth message serializes");
+        if let Err(e) = self.client.publish(&self.topics.status, true, payload).await {
+            error!("Failed to publish birth message: {}", e);
+        }
+    }
+
+    async fn rebirth(&self, node: &EdgeNode) {
+        if let Err(e) = self.client.publish_all(node.birth().await).await {
+            error!("Failed to publish Sparkplug births: {}", e);
+        }
+    }
+
+    /// Polls the event loop and hands everything worth acting on to a
+    /// separate task. Subscribing and publishing wait for room in the
+    /// client queue, which only this loop drains, so they must not run here.
+    /// A lost session moves Sparkplug to the next bdSeq before reconnecting.
+    async fn run(self, mut events: EventLoop) {
+        let sparkplug = self.sparkplug.clone();
+        let (inbox, received) = mpsc::unbounded_channel();
+        tokio::spawn(self.handle(received));
+        // `connect` already consumed the first ConnAck.
+        let _ = inbox.send(Incoming::Connected);
+        let mut online = true;
+        loop {
+            match events.next().await {
+                Ok(Incoming::Connected) => {
+                    info!("Reconnected to MQTT broker");
+                    online = true;
+                    let _ = inbox.send(Incoming::Connected);
+                }
+                Ok(message @ Incoming::Message { .. }) => {
+                    let _ = inbox.send(message);
+                }
+                Ok(Incoming::Closed) => return,
+                Ok(Incoming::Other) => {}
+                Err(e) => {
+                    warn!("MQTT connection lost, retrying: {}", e);
+                    if let Some(node) = sparkplug.as_ref().filter(|_| online) {
+                        node.next_session();
+                        events.set_will(node.death(), false);
+                    }
+                    online = false;
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn handle(self, mut received: mpsc::UnboundedReceiver<Incoming>) {
+        while let Some(incoming) = received.recv().await {
+            match incoming {
+                Incoming::Connected => self.established().await,
+                Incoming::Message { topic, payload } if topic == self.topics.commands => {
+                    self.command(&payload).await;
+                }
+                Incoming::Message { topic, payload }
+                    if self.sparkplug.as_ref().is_some_and(|node| node.is_command(&topic)) =>
+                {
+                    self.sparkplug_command(&topic, &payload).await;
+                }
+                Incoming::Message { topic, .. } => debug!(%topic, "Ignoring message"),
+                Incoming::Closed | Incoming::Other => {}
+            }
+        }
+    }
+
+    async fn command(&self, payload: &[u8]) {
+        let opened = serde_json::from_slice::<Value>(payload)
+            .map_err(|e| AppError::DeserializationError {
+                message: "invalid command payload".to_string(),
+                source: e,
+            })
+            .and_then(|message| self.verifier.open(&message));
+        match opened {
+            Ok(event) => self.dispatch(event).await,
+            Err(e) => self.reject(&e).await,
+        }
+    }
+
+    /// Node commands can only request a rebirth; device commands become
+    /// writes that take the same ACL-checked path as backend writes.
+    async fn sparkplug_command(&self, topic: &str, payload: &[u8]) {
+        let Some(node) = &self.sparkplug else {
+            return;
+        };
+        match node.command(topic, payload).await {
+            Ok(Inbound::Rebirth) => {
+                info!("Sparkplug rebirth requested");
+                self.rebirth(node).await;
+            }
+            Ok(Inbound::Writes(writes)) => {
+                for write in writes {
+                    match self.verifier.admit(write) {
+                        Ok(event) => self.dispatch(event).await,
+                        Err(e) => self.reject(&e).await,
+                    }
+                }
+            }
+            Err(e) => self.reject(&e).await,
+        }
+    }
+
+    async fn dispatch(&self, event: ChEvent) {
+        if let Err(e) = self.tx.send(event).await {
+            error!("Failed to send event to channel: {}", e);
+        }
+    }
+
+    async fn reject(&self, error: &AppError) {
+        warn!(code = ?error.code(), "Rejected command: {}", error);
+        let topic = self.topics.events.replace("{event}", "command_rejected");
+        let rejection = serde_json::to_vec(&Failure::from(error)).expect("failure report serializes");
+        if let Err(e) = self.client.publish(&topic, false, rejection).await {
+            warn!("Failed to report rejected command: {}", e);
+        }
+    }
+}
+
+fn client_error<E: StdError + Send + Sync + 'static>(error: E) -> AppError {
+    AppError::MqttError {
+        message: error.to_string(),
+        source: Some(Box::new(error)),
+    }
+}
@@ -0,0 +1,540 @@
+//! Optional OPC UA server mirroring the registered sensors, so plant tools
+//! can read the same tags locally. Browse path: `Objects/Devices/<device>/<sensor>`.
+
+use async_trait::async_trait;
+use opcua::server::address_space::{AccessLevel, AddressSpace, VariableBuilder};
+use opcua::server::diagnostics::NamespaceMetadata;
+use opcua::server::node_manager::memory::{
+    InMemoryNodeManager, InMemoryNodeManagerBuilder, InMemoryNodeManagerImpl, InMemoryNodeManagerImplBuilder,
+};
+use opcua::server::node_manager::{RequestContext, ServerContext, WriteNode};
+use opcua::server::{ServerBuilder, ServerEndpoint, ServerHandle, ServerUserToken, ANONYMOUS_USER_TOKEN_ID};
+use opcua::sync::RwLock;
+use opcua::types::{AttributeId, DataTypeId, DataValue, DateTime, NodeId, StatusCode, Variant};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tracing::{debug, error, info, warn};
+
+use crate::agent::{LocalWrite, WriteOutcome};
+use crate::config::SensorConfig;
+use crate::helper::AppError;
+use crate::plc_io::{ModbusData, Quality};
+use crate::protocol::AGENT_VERSION;
+use crate::state::SharedState;
+
+/// Namespace of the device and sensor nodes. It must differ from the
+/// application URI, which names the server's own namespace.
+const NAMESPACE: &str = "urn:synk9:agent";
+const APPLICATION_URI: &str = "urn:synk9:agent:server";
+
+/// How often nodes are added and removed to follow sensor changes.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+type SensorNodeManager = InMemoryNodeManager<SensorNodes>;
+
+/// Settings under `[opcua]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct OpcUaConfig {
+    pub enabled: bool,
+    pub listen: String,
+    /// Server certificate and key, plus trusted and rejected client
+    /// certificates. A self-signed certificate is created on first start.
+    pub pki_dir: String,
+    /// Also offers an endpoint without signing or encryption, for anonymous
+    /// sessions only.
+    pub allow_unsecured: bool,
+    /// Accounts that may write, accepted only on the encrypted endpoint.
+    /// Anonymous sessions never write, so without users every node is
+    /// read-only.
+    pub users: Vec<OpcUaUser>,
+}
+
+/// A user name and password for the encrypted endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct OpcUaUser {
+    pub name: String,
+    pub password: String,
+}
+
+impl Default for OpcUaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "0.0.0.0:4840".to_string(),
+            pki_dir: "pki".to_string(),
+            allow_unsecured: false,
+            users: Vec::new(),
+        }
+    }
+}
+
+impl OpcUaConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.enabled && self.listen.parse::<SocketAddr>().is_err() {
+            errors.push(format!("opcua.listen: expected ip:port, got {:?}", self.listen));
+        }
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if user.name.is_empty() || user.password.is_empty() {
+                errors.push("opcua.users: name and password must not be empty".to_string());
+            } else if !names.insert(user.name.as_str()) {
+                errors.push(format!("opcua.users: {} is listed twice", user.name));
+            }
+        }
+    }
+}
+
+/// The running server. Stops with the agent, or when `stop` is called.
+pub struct OpcUaServer {
+    handle: ServerHandle,
+    local_addr: SocketAddr,
+}
+
+impl OpcUaServer {
+    /// Binds the listener and starts serving. Writes from signed-in users
+    /// are sent to the agent on `writes`, which applies the running write
+    /// ACL and answers with the PLC's result. They carry no envelope: the
+    /// command signing key covers backend commands, while OPC UA writes are
+    /// authorized by the `[[opcua.users]]` accounts and the write ACL.
+    pub async fn start(
+        config: &OpcUaConfig,
+        state: Arc<Mutex<SharedState>>,
+        samples: broadcast::Receiver<ModbusData>,
+        writes: mpsc::Sender<LocalWrite>,
+    ) -> Result<Self, AppError> {
+        let listener = TcpListener::bind(&config.listen).await.map_err(|e| {
+            AppError::InternalError(format!("Failed to bind OPC UA server to {}: {}", config.listen, e))
+        })?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| AppError::InternalError(e.to_string()))?;
+
+        let anonymous = [ANONYMOUS_USER_TOKEN_ID.to_string()];
+        let mut secure_tokens = anonymous.to_vec();
+        let mut builder = ServerBuilder::new()
+            .application_name("synk9 agent")
+            .application_uri(APPLICATION_URI)
+            .product_uri(format!("{}:{}", NAMESPACE, AGENT_VERSION))
+            .host(local_addr.ip().to_string())
+            .port(local_addr.port())
+            .discovery_urls(vec![format!("opc.tcp://{}/", local_addr)])
+            .pki_dir(&config.pki_dir)
+            .create_sample_keypair(true);
+        for user in &config.users {
+            let id = format!("user:{}", user.name);
+            let token = ServerUserToken::user_pass(user.name.as_str(), user.password.as_str());
+            builder = builder.add_user_token(&id, token);
+            secure_tokens.push(id);
+        }
+        let nodes = SensorNodesBuilder {
+            namespace: NamespaceMetadata {
+                namespace_uri: NAMESPACE.to_string(),
+                ..Default::default()
+            },
+            writes,
+        };
+        builder = builder
+            .add_endpoint(
+                "sign_encrypt",
+                ServerEndpoint::new_basic256sha256_sign_encrypt("/", &secure_tokens),
+            )
+            .default_endpoint("sign_encrypt")
+            .with_node_manager(InMemoryNodeManagerBuilder::new(nodes));
+        if config.allow_unsecured {
+            warn!("OPC UA endpoint without security enabled");
+            builder = builder.add_endpoint("none", ServerEndpoint::new_none("/", &anonymous));
+        }
+        let (server, handle) = builder
+            .build()
+            .map_err(|e| AppError::InternalError(format!("Invalid OPC UA configuration: {}", e)))?;
+
+        let manager = handle
+            .node_managers()
+            .get_of_type::<SensorNodeManager>()
+            .ok_or_else(|| AppError::InternalError("OPC UA node manager missing".to_string()))?;
+        let ns = handle
+            .get_namespace_index(NAMESPACE)
+            .ok_or_else(|| AppError::InternalError("OPC UA namespace missing".to_string()))?;
+        let root = NodeId::new(ns, "Devices");
+        manager
+            .address_space()
+            .write()
+            .add_folder(&root, "Devices", "Devices", &NodeId::objects_folder_id());
+
+        let mirror = Mirror {
+            manager,
+            handle: handle.clone(),
+            ns,
+            root,
+            writable: !config.users.is_empty(),
+            state,
+            nodes: HashMap::new(),
+        };
+        tokio::spawn(mirror.run(samples));
+        tokio::spawn(async move {
+            if let Err(e) = server.run_with(listener).await {
+                error!("OPC UA server stopped: {}", e);
+            }
+        });
+        info!("OPC UA server listening on opc.tcp://{}/", local_addr);
+        Ok(Self { handle, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(&self) {
+        self.handle.cancel();
+    }
+}
+
+/// Where writes to a sensor node go.
+#[derive(Clone)]
+struct Target {
+    device: String,
+    register: u16,
+    r_type: String,
+}
+
+struct SensorNodesBuilder {
+    namespace: NamespaceMetadata,
+    writes: mpsc::Sender<LocalWrite>,
+}
+
+impl InMemoryNodeManagerImplBuilder for SensorNodesBuilder {
+    type Impl = SensorNodes;
+
+    fn build(mut self, context: ServerContext, address_space: &mut AddressSpace) -> SensorNodes {
+        let uri = &self.namespace.namespace_uri;
+        self.namespace.namespace_index = context.type_tree.write().namespaces_mut().add_namespace(uri);
+        address_space.add_namespace(uri, self.namespace.namespace_index);
+        SensorNodes {
+            namespaces: vec![self.namespace],
+            targets: RwLock::default(),
+            writes: self.writes,
+        }
+    }
+}
+
+/// Node manager for the device and sensor nodes. The mirror sets their
+/// values, and writes are answered once the agent has run them, so a client
+/// sees denials and PLC failures instead of an early `Good`.
+struct SensorNodes {
+    namespaces: Vec<NamespaceMetadata>,
+    targets: RwLock<HashMap<NodeId, Target>>,
+    writes: mpsc::Sender<LocalWrite>,
+}
+
+#[async_trait]
+impl InMemoryNodeManagerImpl for SensorNodes {
+    async fn init(&self, _address_space: &mut AddressSpace, _context: ServerContext) {}
+
+    fn name(&self) -> &str {
+        "synk9"
+    }
+
+    fn namespaces(&self) -> Vec<NamespaceMetadata> {
+        self.namespaces.clone()
+    }
+
+    async fn write(
+        &self,
+        context: &RequestContext,
+        address_space: &RwLock<AddressSpace>,
+        nodes_to_write: &mut [&mut WriteNode],
+    ) -> Result<(), StatusCode> {
+        for node in nodes_to_write.iter_mut() {
+            let status = self.write_value(context, address_space, node).await;
+            node.set_status(status);
+        }
+        Ok(())
+    }
+}
+
+impl SensorNodes {
+    fn route(&self, node: NodeId, target: Target) {
+        self.targets.write().insert(node, target);
+    }
+
+    fn unroute(&self, node: &NodeId) {
+        self.targets.write().remove(node);
+    }
+
+    async fn write_value(
+        &self,
+        context: &RequestContext,
+        address_space: &RwLock<AddressSpace>,
+        node: &WriteNode,
+    ) -> StatusCode {
+        let value = node.value();
+        {
+            let mut address_space = address_space.write();
+            let type_tree = context.type_tree.read();
+            if let Err(status) = address_space.validate_node_write(context, value, &*type_tree) {
+                return status;
+            }
+        }
+        if value.attribute_id != AttributeId::Value {
+            return StatusCode::BadNotWritable;
+        }
+        if context.token.is_anonymous() {
+            return StatusCode::BadUserAccessDenied;
+        }
+        let Some(target) = self.targets.read().get(&value.node_id).cloned() else {
+            return StatusCode::BadNotWritable;
+        };
+        let Some(val) = value.value.value.as_ref().and_then(to_register) else {
+            return StatusCode::BadTypeMismatch;
+        };
+
+        let (reply, outcome) = oneshot::channel();
+        let write = LocalWrite {
+            device: target.device,
+            reg: target.register,
+            val,
+            r_type: target.r_type,
+            reply,
+        };
+        if self.writes.send(write).await.is_err() {
+            return StatusCode::BadServerHalted;
+        }
+        match outcome.await {
+            Ok(WriteOutcome::Written) => StatusCode::Good,
+            Ok(WriteOutcome::Locked | WriteOutcome::Denied) => StatusCode::BadUserAccessDenied,
+            Ok(WriteOutcome::Failed(e)) => status_of_error(&e),
+            Err(_) => StatusCode::BadServerHalted,
+        }
+    }
+}
+
+/// What a sensor node was built from, to notice edits.
+#[derive(PartialEq)]
+struct NodeSpec {
+    device: String,
+    register: u16,
+    r_type: String,
+    label: String,
+}
+
+/// Node spec of `sensor` under the running default device.
+fn spec(sensor: &SensorConfig, default_device: &str) -> NodeSpec {
+    NodeSpec {
+        device: sensor.device.clone().unwrap_or_else(|| default_device.to_string()),
+        register: sensor.start_register,
+        r_type: sensor.r_type.clone(),
+        label: sensor.label.clone(),
+    }
+}
+
+/// Keeps the address space in step with the registered sensors and their
+/// latest samples.
+struct Mirror {
+    manager: Arc<SensorNodeManager>,
+    handle: ServerHandle,
+    ns: u16,
+    root: NodeId,
+    /// Users are configured, so sensor nodes accept writes. Whether the
+    /// write ACL allows one is decided by the agent when it arrives.
+    writable: bool,
+    state: Arc<Mutex<SharedState>>,
+    /// Sensor nodes by sensor id.
+    nodes: HashMap<String, NodeSpec>,
+}
+
+impl Mirror {
+    async fn run(mut self, mut samples: broadcast::Receiver<ModbusData>) {
+        let mut sync = tokio::time::interval(SYNC_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.handle.token().cancelled() => return,
+                _ = sync.tick() => self.sync().await,
+                sample = samples.recv() => match sample {
+                    Ok(sample) => {
+                        if !self.nodes.contains_key(&sample.sensor_id) {
+                            self.sync().await;
+                        }
+                        self.update(&sample);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!(missed, "OPC UA mirror fell behind, skipping samples");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    }
+
+    /// Adds nodes for new or edited sensors and removes those of sensors
+    /// that are gone. Sensors without a device move with the default device
+    /// of the running configuration.
+    async fn sync(&mut self) {
+        let (sensors, values, default) = {
+            let state = self.state.lock().await;
+            (
+                state.registered_sensors.clone(),
+                state.last_values.clone(),
+                state.default_device.clone(),
+            )
+        };
+
+        let current: Vec<&str> = sensors.iter().map(|s| s.id.as_str()).collect();
+        let removed: Vec<String> = self
+            .nodes
+            .keys()
+            .filter(|id| !current.contains(&id.as_str()))
+            .cloned()
+            .collect();
+        for id in removed {
+            self.remove(&id);
+        }
+
+        for sensor in &sensors {
+            let spec = spec(sensor, &default);
+            if self.nodes.get(&sensor.id) == Some(&spec) {
+                continue;
+            }
+            if self.nodes.contains_key(&sensor.id) {
+                self.remove(&sensor.id);
+            }
+            self.add(sensor, spec, values.get(&sensor.id).copied());
+        }
+    }
+
+    fn device_node(&self, device: &str) -> NodeId {
+        NodeId::new(self.ns, device.to_string())
+    }
+
+    fn sensor_node(&self, device: &str, sensor: &str) -> NodeId {
+        NodeId::new(self.ns, format!("{}/{}", device, sensor))
+    }
+
+    fn add(&mut self, sensor: &SensorConfig, spec: NodeSpec, last_value: Option<u16>) {
+        let folder = self.device_node(&spec.device);
+        let node = self.sensor_node(&spec.device, &sensor.id);
+        let is_bit = spec.r_type != "REG";
+        {
+            let mut address_space = self.manager.address_space().write();
+            if !address_space.node_exists(&folder) {
+                address_space.add_folder(&folder, spec.device.as_str(), spec.device.as_str(), &self.root);
+            }
+            let data_type = if is_bit { DataTypeId::Boolean } else { DataTypeId::UInt16 };
+            let mut variable = VariableBuilder::new(&node, sensor.id.as_str(), spec.label.as_str())
+                .data_type(data_type)
+                .access_level(AccessLevel::CURRENT_READ)
+                .user_access_level(AccessLevel::CURRENT_READ)
+                .organized_by(folder.clone());
+            if self.writable {
+                variable = variable.writable();
+            }
+            variable.insert(&mut *address_space);
+        }
+        let value = match last_value {
+            Some(value) => data_value(is_bit, value, Quality::Good),
+            None => DataValue {
+                status: Some(StatusCode::BadWaitingForInitialData),
+                server_timestamp: Some(DateTime::now()),
+                ..Default::default()
+            },
+        };
+        self.set(&node, value);
+
+        if self.writable {
+            let target = Target {
+                device: spec.device.clone(),
+                register: spec.register,
+                r_type: spec.r_type.clone(),
+            };
+            self.manager.inner().route(node, target);
+        }
+        debug!(sensor_id = %sensor.id, device = %spec.device, "Added OPC UA node");
+        self.nodes.insert(sensor.id.clone(), spec);
+    }
+
+    fn remove(&mut self, sensor: &str) {
+        let Some(spec) = self.nodes.remove(sensor) else {
+            return;
+        };
+        let folder = self.device_node(&spec.device);
+        let node = self.sensor_node(&spec.device, sensor);
+        self.manager.inner().unroute(&node);
+        let mut address_space = self.manager.address_space().write();
+        address_space.delete(&node, true);
+        if !self.nodes.values().any(|other| other.device == spec.device) {
+            address_space.delete(&folder, true);
+        }
+        debug!(sensor_id = %sensor, "Removed OPC UA node");
+    }
+
+    fn update(&self, sample: &ModbusData) {
+        let Some(spec) = self.nodes.get(&sample.sensor_id) else {
+            return;
+        };
+        let node = self.sensor_node(&spec.device, &sample.sensor_id);
+        self.set(&node, data_value(spec.r_type != "REG", sample.value, sample.quality));
+    }
+
+    fn set(&self, node: &NodeId, value: DataValue) {
+        if let Err(status) = self.manager.set_value(self.handle.subscriptions(), node, None, value) {
+            debug!(%node, "Failed to update OPC UA node: {}", status);
+        }
+    }
+}
+
+fn data_value(is_bit: bool, value: u16, quality: Quality) -> DataValue {
+    let now = DateTime::now();
+    let value = if is_bit {
+        Variant::Boolean(value != 0)
+    } else {
+        Variant::UInt16(value)
+    };
+    DataValue {
+        value: Some(value),
+        status: Some(status_of(quality)),
+        source_timestamp: Some(now),
+        server_timestamp: Some(now),
+        ..Default::default()
+    }
+}
+
+fn status_of(quality: Quality) -> StatusCode {
+    match quality {
+        Quality::Good => StatusCode::Good,
+        Quality::Stale => StatusCode::UncertainLastUsableValue,
+        Quality::CommFailure => StatusCode::BadCommunicationError,
+        Quality::OutOfRange => StatusCode::BadOutOfRange,
+        Quality::ConfigError => StatusCode::BadConfigurationError,
+        Quality::Substituted => StatusCode::GoodLocalOverride,
+    }
+}
+
+fn status_of_error(error: &AppError) -> StatusCode {
+    match error {
+        AppError::ConnectTimeout(_) | AppError::RequestTimeout(_) => StatusCode::BadTimeout,
+        AppError::ModbusException(..) => StatusCode::BadDeviceFailure,
+        AppError::PlcError { .. } => StatusCode::BadCommunicationError,
+        AppError::ValidationError(_) => StatusCode::BadConfigurationError,
+        _ => StatusCode::BadInternalError,
+    }
+}
+
+/// Accepts booleans and integers that fit a register.
+fn to_register(value: &Variant) -> Option<u16> {
+    match value {
+        Variant::Boolean(on) => Some(*on as u16),
+        Variant::UInt16(v) => Some(*v),
+        Variant::Int16(v) => u16::try_from(*v).ok(),
+        Variant::Int32(v) => u16::try_from(*v).ok(),
+        Variant::UInt32(v) => u16::try_from(*v).ok(),
+        Variant::Int64(v) => u16::try_from(*v).ok(),
+        Variant::UInt64(v) => u16::try_from(*v).ok(),
+        _ => None,
+    }
+}
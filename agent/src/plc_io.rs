@@ -1,6 +1,13 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
 use tokio_modbus::client::{Context, Reader};
 use tokio_modbus::prelude::Writer;
+use tokio_modbus::ExceptionCode;
+use tracing::{info, trace};
+
+use crate::helper::{AppError, ErrorReport};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WriteData {
@@ -8,7 +15,47 @@ pub struct WriteData {
     pub value: u16,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// OPC-style quality attached to every sample sent upstream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Quality {
+    Good,
+    /// The value is the last one read successfully: the device link is down
+    /// or the read was dropped because the poll cycle ran late.
+    Stale,
+    CommFailure,
+    /// The device rejected the address range configured for the sensor.
+    OutOfRange,
+    ConfigError,
+    /// The value was forced by an operator rather than read from the device.
+    Substituted,
+}
+
+impl Quality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Stale => "stale",
+            Quality::CommFailure => "comm-failure",
+            Quality::OutOfRange => "out-of-range",
+            Quality::ConfigError => "config-error",
+            Quality::Substituted => "substituted",
+        }
+    }
+
+    /// OPC DA quality code, as understood by SCADA clients.
+    pub fn opc_code(&self) -> i32 {
+        match self {
+            Quality::Good => 192,
+            Quality::Stale => 68,
+            Quality::CommFailure => 24,
+            Quality::OutOfRange | Quality::ConfigError => 4,
+            Quality::Substituted => 216,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ModbusData {
     pub sensor_id: String,
     pub register: String,
@@ -17,15 +64,144 @@ pub struct ModbusData {
     pub key: String,
     pub s_type: String,
     pub r_type: String,
+    pub quality: Quality,
+    /// Why the value is not fresh, for samples that are not good.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport>,
+}
+
+/// Exception responses a PLC can answer with instead of data.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusException {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    SlaveDeviceBusy,
+    GatewayPathUnavailable,
+    GatewayTargetFailed,
+    /// Any other code, such as acknowledge or memory parity error.
+    Other(u8),
+}
+
+impl From<ExceptionCode> for ModbusException {
+    fn from(code: ExceptionCode) -> Self {
+        match code {
+            ExceptionCode::IllegalFunction => ModbusException::IllegalFunction,
+            ExceptionCode::IllegalDataAddress => ModbusException::IllegalDataAddress,
+            ExceptionCode::IllegalDataValue => ModbusException::IllegalDataValue,
+            ExceptionCode::ServerDeviceFailure => ModbusException::SlaveDeviceFailure,
+            ExceptionCode::ServerDeviceBusy => ModbusException::SlaveDeviceBusy,
+            ExceptionCode::GatewayPathUnavailable => ModbusException::GatewayPathUnavailable,
+            ExceptionCode::GatewayTargetDevice => ModbusException::GatewayTargetFailed,
+            other => ModbusException::Other(u8::from(other)),
+        }
+    }
 }
 
-pub async fn stop_plc(ctx: &mut Context) -> Result<(), Box<dyn std::error::Error>> {
-    let stop_register = 0;
-    let stop_value = 0b00001000;
-    ctx.write_single_register(stop_register, stop_value).await?;
-    println!(
+impl ModbusException {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModbusException::IllegalFunction => "illegal_function",
+            ModbusException::IllegalDataAddress => "illegal_data_address",
+            ModbusException::IllegalDataValue => "illegal_data_value",
+            ModbusException::SlaveDeviceFailure => "slave_device_failure",
+            ModbusException::SlaveDeviceBusy => "slave_device_busy",
+            ModbusException::GatewayPathUnavailable => "gateway_path_unavailable",
+            ModbusException::GatewayTargetFailed => "gateway_target_failed",
+            ModbusException::Other(_) => "other",
+        }
+    }
+
+    /// Exception code as sent on the wire.
+    pub fn code(&self) -> u8 {
+        match self {
+            ModbusException::IllegalFunction => 0x01,
+            ModbusException::IllegalDataAddress => 0x02,
+            ModbusException::IllegalDataValue => 0x03,
+            ModbusException::SlaveDeviceFailure => 0x04,
+            ModbusException::SlaveDeviceBusy => 0x06,
+            ModbusException::GatewayPathUnavailable => 0x0A,
+            ModbusException::GatewayTargetFailed => 0x0B,
+            ModbusException::Other(code) => *code,
+        }
+    }
+
+    /// Busy devices and gateways can answer differently on a later try.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ModbusException::SlaveDeviceBusy
+                | ModbusException::GatewayPathUnavailable
+                | ModbusException::GatewayTargetFailed
+        )
+    }
+
+    /// Quality of the substitute sample sent when a read gets this answer.
+    pub fn quality(&self) -> Quality {
+        match self {
+            ModbusException::IllegalFunction => Quality::ConfigError,
+            ModbusException::IllegalDataAddress | ModbusException::IllegalDataValue => {
+                Quality::OutOfRange
+            }
+            _ => Quality::CommFailure,
+        }
+    }
+
+    /// What went wrong in terms of the request, for operators.
+    pub fn describe(&self, request: &Request) -> String {
+        match (self, request) {
+            (ModbusException::IllegalDataAddress, Request::Read { .. }) => {
+                format!("{} does not exist on the device", request.target())
+            }
+            (ModbusException::IllegalDataAddress, _) => {
+                format!("{} does not exist or is read-only", request.target())
+            }
+            (ModbusException::IllegalDataValue, _) => {
+                format!("the device rejected the value or quantity for {}", request.target())
+            }
+            (ModbusException::IllegalFunction, _) => {
+                format!("the device does not support the {}", request)
+            }
+            (ModbusException::SlaveDeviceFailure, _) => {
+                format!("the device failed while processing the {}", request)
+            }
+            (ModbusException::SlaveDeviceBusy, _) => {
+                format!("the device was busy and refused the {}", request)
+            }
+            (ModbusException::GatewayPathUnavailable, _) => {
+                "the gateway has no path to the device".to_string()
+            }
+            (ModbusException::GatewayTargetFailed, _) => {
+                "the device behind the gateway did not respond".to_string()
+            }
+            (ModbusException::Other(code), _) => {
+                format!("the device answered the {} with exception code {}", request, code)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ModbusException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModbusException::Other(code) => write!(f, "exception code {}", code),
+            other => write!(f, "{}", other.as_str()),
+        }
+    }
+}
+
+/// Holding register and value that stop the PLC.
+const STOP_REGISTER: u16 = 0;
+const STOP_VALUE: u16 = 0b00001000;
+
+pub async fn stop_plc(ctx: &mut Context) -> Result<(), PlcFailure> {
+    let request = Request::Stop;
+    check(&request, ctx.write_single_register(STOP_REGISTER, STOP_VALUE).await)?;
+    info!(
         "PLC stopped by writing {} to register {}",
-        stop_value, stop_register
+        STOP_VALUE, STOP_REGISTER
     );
     Ok(())
 }
@@ -34,18 +210,23 @@ pub async fn write_to_plc(
     register: u16,
     value: u16,
     r_type: String,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), PlcFailure> {
+    let request = Request::Write {
+        register,
+        value,
+        r_type: r_type.clone(),
+    };
     if r_type == "REG" {
-        ctx.write_single_register(register, value).await?;
-        println!("Wrote {} to register {}", value, register);
+        check(&request, ctx.write_single_register(register, value).await)?;
+        info!("Wrote {} to register {}", value, register);
         Ok(())
     } else {
         let mut bl = true;
         if value == 0 {
             bl = false;
         };
-        ctx.write_single_coil(register, bl).await?;
-        println!("Wrote {} to register {}", value, register);
+        check(&request, ctx.write_single_coil(register, bl).await)?;
+        info!("Wrote {} to coil {}", value, register);
         Ok(())
     }
 }
@@ -53,23 +234,316 @@ pub async fn read_from_plc(
     ctx: &mut Context,
     start_register: u16,
     end_register: u16,
-    readType: String,
-) -> Result<u16, Box<dyn std::error::Error>> {
-    if readType == "REG" {
-        let data = ctx
-            .read_holding_registers(start_register, end_register)
-            .await?;
-        println!("{:?}", data);
-        let result = data[0];
-
-        Ok(result)
+    r_type: String,
+) -> Result<u16, PlcFailure> {
+    let request = Request::Read {
+        start_register,
+        end_register,
+        r_type: r_type.clone(),
+    };
+    if r_type == "REG" {
+        let data = check(
+            &request,
+            ctx.read_holding_registers(start_register, end_register).await,
+        )?;
+        trace!(start_register, ?data, "Read holding registers");
+        data.first().copied().ok_or_else(|| PlcFailure::empty(&request))
     } else {
-        let data = ctx.read_coils(start_register, end_register).await?;
-        println!("{:?}", data);
-        let result = data[0];
-        if result == true {
-            return Ok(1);
+        let data = check(&request, ctx.read_coils(start_register, end_register).await)?;
+        trace!(start_register, ?data, "Read coils");
+        let bit = data.first().copied().ok_or_else(|| PlcFailure::empty(&request))?;
+        Ok(u16::from(bit))
+    }
+}
+
+/// Splits a tokio-modbus result into the data or a classified failure.
+fn check<T>(request: &Request, result: tokio_modbus::Result<T>) -> Result<T, PlcFailure> {
+    match result {
+        Ok(Ok(data)) => Ok(data),
+        Ok(Err(code)) => Err(PlcFailure::exception(request, code.into())),
+        Err(tokio_modbus::Error::Transport(e)) => Err(PlcFailure {
+            quality: if e.kind() == ErrorKind::InvalidInput {
+                Quality::ConfigError
+            } else {
+                Quality::CommFailure
+            },
+            link_down: is_link_error(e.kind()),
+            exception: None,
+            error: AppError::PlcError {
+                message: format!("{} failed", request),
+                source: Some(Box::new(e)),
+            },
+        }),
+        // The response does not belong to the request, so the stream is out
+        // of step and the connection cannot be trusted any more.
+        Err(tokio_modbus::Error::Protocol(e)) => {
+            Err(PlcFailure::disconnected(AppError::PlcError {
+                message: format!("{} failed", request),
+                source: Some(Box::new(e)),
+            }))
+        }
+    }
+}
+
+/// One Modbus transaction, kept so it can be re-issued on retry.
+#[derive(Debug, Clone)]
+pub enum Request {
+    Read {
+        start_register: u16,
+        end_register: u16,
+        r_type: String,
+    },
+    Write {
+        register: u16,
+        value: u16,
+        r_type: String,
+    },
+    Stop,
+}
+
+impl Request {
+    pub fn is_read(&self) -> bool {
+        matches!(self, Request::Read { .. })
+    }
+
+    /// The table and address range the request touches.
+    pub fn target(&self) -> String {
+        let table = |r_type: &str| if r_type == "REG" { "register" } else { "coil" };
+        match self {
+            Request::Read {
+                start_register,
+                end_register,
+                r_type,
+            } if *end_register > 1 => format!(
+                "{} {}..{}",
+                table(r_type),
+                start_register,
+                start_register.saturating_add(*end_register - 1)
+            ),
+            Request::Read {
+                start_register,
+                r_type,
+                ..
+            } => format!("{} {}", table(r_type), start_register),
+            Request::Write {
+                register, r_type, ..
+            } => format!("{} {}", table(r_type), register),
+            Request::Stop => format!("register {}", STOP_REGISTER),
+        }
+    }
+
+    /// Modbus function code the request is sent with.
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::Read { r_type, .. } if r_type == "REG" => 3,
+            Request::Read { .. } => 1,
+            Request::Write { r_type, .. } if r_type == "REG" => 6,
+            Request::Write { .. } => 5,
+            Request::Stop => 6,
+        }
+    }
+
+    /// First address and number of addresses the request touches.
+    pub fn range(&self) -> (u16, u16) {
+        match self {
+            Request::Read {
+                start_register,
+                end_register,
+                ..
+            } => (*start_register, *end_register),
+            Request::Write { register, .. } => (*register, 1),
+            Request::Stop => (STOP_REGISTER, 1),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Read {
+                start_register,
+                r_type,
+                ..
+            } => write!(f, "read of {} {}", r_type, start_register),
+            Request::Write {
+                register, r_type, ..
+            } => write!(f, "write to {} {}", r_type, register),
+            Request::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+/// Runs `request` once. Reads return the value read, writes the value
+/// written.
+pub async fn execute(ctx: &mut Context, request: &Request) -> Result<u16, PlcFailure> {
+    match request {
+        Request::Read {
+            start_register,
+            end_register,
+            r_type,
+        } => read_from_plc(ctx, *start_register, *end_register, r_type.clone()).await,
+        Request::Write {
+            register,
+            value,
+            r_type,
+        } => {
+            write_to_plc(ctx, *register, *value, r_type.clone()).await?;
+            Ok(*value)
+        }
+        Request::Stop => {
+            stop_plc(ctx).await?;
+            Ok(STOP_VALUE)
         }
-        Ok(0)
+    }
+}
+
+/// A failed transaction, classified while the original error is at hand.
+#[derive(Debug)]
+pub struct PlcFailure {
+    pub error: AppError,
+    pub quality: Quality,
+    /// The connection is unusable and must be reopened.
+    pub link_down: bool,
+    /// Set when the PLC answered with an exception response.
+    pub exception: Option<ModbusException>,
+}
+
+impl PlcFailure {
+    pub fn timeout(message: String) -> Self {
+        Self {
+            error: AppError::RequestTimeout(message),
+            quality: Quality::CommFailure,
+            link_down: true,
+            exception: None,
+        }
+    }
+
+    pub fn disconnected(error: AppError) -> Self {
+        Self {
+            error,
+            quality: Quality::CommFailure,
+            link_down: true,
+            exception: None,
+        }
+    }
+
+    fn exception(request: &Request, exception: ModbusException) -> Self {
+        Self {
+            error: AppError::ModbusException(exception, exception.describe(request)),
+            quality: exception.quality(),
+            link_down: false,
+            exception: Some(exception),
+        }
+    }
+
+    fn empty(request: &Request) -> Self {
+        Self {
+            error: AppError::plc(format!("{} returned no data", request)),
+            quality: Quality::CommFailure,
+            link_down: false,
+            exception: None,
+        }
+    }
+}
+
+/// True when the error means the TCP link to the PLC itself is gone, as
+/// opposed to the PLC rejecting a single request.
+fn is_link_error(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::ErrorCode;
+    use std::io;
+
+    fn read() -> Request {
+        Request::Read {
+            start_register: 512,
+            end_register: 1,
+            r_type: "REG".to_string(),
+        }
+    }
+
+    fn transport(kind: ErrorKind) -> PlcFailure {
+        check::<()>(&read(), Err(io::Error::from(kind).into())).unwrap_err()
+    }
+
+    #[test]
+    fn quality_names_match_the_wire_format() {
+        for quality in [
+            Quality::Good,
+            Quality::Stale,
+            Quality::CommFailure,
+            Quality::OutOfRange,
+            Quality::ConfigError,
+            Quality::Substituted,
+        ] {
+            assert_eq!(serde_json::to_value(quality).unwrap(), quality.as_str());
+        }
+    }
+
+    #[test]
+    fn transport_errors_are_classified_by_kind() {
+        let dropped = transport(ErrorKind::ConnectionReset);
+        assert_eq!(dropped.quality, Quality::CommFailure);
+        assert!(dropped.link_down);
+
+        let invalid = transport(ErrorKind::InvalidInput);
+        assert_eq!(invalid.quality, Quality::ConfigError);
+        assert!(!invalid.link_down);
+    }
+
+    #[test]
+    fn exception_responses_keep_the_link_and_map_to_a_quality() {
+        let cases = [
+            (ExceptionCode::IllegalFunction, 0x01, Quality::ConfigError, false),
+            (ExceptionCode::IllegalDataAddress, 0x02, Quality::OutOfRange, false),
+            (ExceptionCode::IllegalDataValue, 0x03, Quality::OutOfRange, false),
+            (ExceptionCode::ServerDeviceFailure, 0x04, Quality::CommFailure, false),
+            (ExceptionCode::ServerDeviceBusy, 0x06, Quality::CommFailure, true),
+            (ExceptionCode::GatewayPathUnavailable, 0x0A, Quality::CommFailure, true),
+            (ExceptionCode::GatewayTargetDevice, 0x0B, Quality::CommFailure, true),
+            (ExceptionCode::Acknowledge, 0x05, Quality::CommFailure, false),
+        ];
+        for (code, wire, quality, transient) in cases {
+            let failure = check::<()>(&read(), Ok(Err(code))).unwrap_err();
+            let exception = failure.exception.unwrap();
+            assert_eq!(exception.code(), wire, "{:?}", code);
+            assert_eq!(failure.quality, quality, "{:?}", code);
+            assert_eq!(failure.error.is_transient(), transient, "{:?}", code);
+            assert!(!failure.link_down);
+        }
+    }
+
+    #[test]
+    fn missing_registers_are_described_in_terms_of_the_request() {
+        let write = Request::Write {
+            register: 40,
+            value: 1,
+            r_type: "COIL".to_string(),
+        };
+        let missing = ModbusException::IllegalDataAddress;
+        assert_eq!(missing.describe(&read()), "register 512 does not exist on the device");
+        assert_eq!(missing.describe(&write), "coil 40 does not exist or is read-only");
+    }
+
+    #[test]
+    fn timeouts_drop_the_link_and_are_transient() {
+        let timeout = PlcFailure::timeout(format!("{} after 300 ms", read()));
+        assert!(timeout.link_down);
+        assert_eq!(timeout.quality, Quality::CommFailure);
+        assert_eq!(timeout.error.code(), ErrorCode::RequestTimeout);
+        assert!(timeout.error.is_transient());
+        assert!(timeout.exception.is_none());
     }
 }
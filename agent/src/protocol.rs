@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::helper::AppError;
+
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Wire protocol spoken by this build.
+///
+/// 1. The original format: bare samples and a sensor list as health check.
+/// 2. Samples carry `quality` and `error`, health checks a full report.
+/// 3. Commands arrive as `{v, command}`, as described by the wire schema.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Oldest protocol this build can still speak. Servers that never answer the
+/// handshake are assumed to speak it.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// What this build supports, announced in the handshake.
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    pub transports: Vec<&'static str>,
+    /// Value types samples can carry.
+    pub data_types: Vec<&'static str>,
+    /// Several samples per message.
+    pub batching: bool,
+    /// Threshold alarms evaluated on the agent.
+    pub alarms: bool,
+}
+
+impl Capabilities {
+    pub fn current() -> Self {
+        Self {
+            transports: vec!["socket.io", "mqtt"],
+            data_types: vec!["u16", "bool"],
+            batching: false,
+            alarms: false,
+        }
+    }
+}
+
+/// Fields added to every handshake, whatever the auth scheme.
+pub fn handshake() -> Value {
+    json!({
+        "agent_version": AGENT_VERSION,
+        "protocol": {
+            "version": PROTOCOL_VERSION,
+            "min": MIN_PROTOCOL_VERSION,
+        },
+        "capabilities": Capabilities::current(),
+    })
+}
+
+/// Sent by the server after it accepts the handshake.
+#[derive(Deserialize, Debug)]
+pub struct ServerHello {
+    pub protocol: u8,
+    #[serde(default)]
+    pub server_version: Option<String>,
+}
+
+/// Protocol version agreed with the server for the current session, shared
+/// between the Socket.IO handlers and the emitter.
+#[derive(Clone, Debug)]
+pub struct Negotiated(Arc<AtomicU8>);
+
+impl Default for Negotiated {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU8::new(MIN_PROTOCOL_VERSION)))
+    }
+}
+
+impl Negotiated {
+    /// A version settled by configuration rather than a server hello, for
+    /// transports such as MQTT where nobody answers the handshake.
+    pub fn fixed(version: u8) -> Self {
+        Self(Arc::new(AtomicU8::new(version)))
+    }
+
+    pub fn version(&self) -> u8 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Switches to the version the server advertised. Versions this build
+    /// cannot speak are refused and the current one is kept.
+    pub fn accept(&self, hello: &ServerHello) -> Result<u8, AppError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol) {
+            return Err(AppError::ValidationError(format!(
+                "server protocol {} is outside the supported range {}..={}",
+                hello.protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        self.0.store(hello.protocol, Ordering::Relaxed);
+        info!(
+            protocol = hello.protocol,
+            server_version = hello.server_version.as_deref().unwrap_or("unknown"),
+            "Negotiated uplink protocol"
+        );
+        Ok(hello.protocol)
+    }
+
+    /// Falls back to the oldest protocol until the next server hello.
+    pub fn reset(&self) {
+        self.0.store(MIN_PROTOCOL_VERSION, Ordering::Relaxed);
+    }
+}
+
+/// Rewrites an outgoing event into the shape `version` expects.
+pub fn adapt(event: &str, mut data: Value, version: u8) -> Value {
+    if version >= 2 {
+        return data;
+    }
+    match event {
+        "monitoring_streamline" => {
+            if let Some(sample) = data.as_object_mut() {
+                sample.remove("quality");
+                sample.remove("error");
+            }
+            data
+        }
+        "health_check" => match data.get_mut("sensors") {
+            Some(sensors) => sensors.take(),
+            None => data,
+        },
+        _ => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(protocol: u8) -> ServerHello {
+        ServerHello {
+            protocol,
+            server_version: None,
+        }
+    }
+
+    #[test]
+    fn only_supported_server_protocols_are_accepted() {
+        let negotiated = Negotiated::default();
+        assert_eq!(negotiated.version(), MIN_PROTOCOL_VERSION);
+
+        assert_eq!(negotiated.accept(&hello(PROTOCOL_VERSION)).unwrap(), PROTOCOL_VERSION);
+        assert!(negotiated.accept(&hello(PROTOCOL_VERSION + 1)).is_err());
+        assert!(negotiated.accept(&hello(0)).is_err());
+        assert_eq!(negotiated.version(), PROTOCOL_VERSION);
+
+        negotiated.reset();
+        assert_eq!(negotiated.version(), MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn version_one_servers_get_the_original_shapes() {
+        let sample = json!({ "sensor_id": "level", "value": 1, "quality": "good", "error": null });
+        let health = json!({ "paused": false, "sensors": [{ "id": "level" }] });
+
+        assert_eq!(
+            adapt("monitoring_streamline", sample.clone(), 1),
+            json!({ "sensor_id": "level", "value": 1 })
+        );
+        assert_eq!(adapt("health_check", health.clone(), 1), json!([{ "id": "level" }]));
+        assert_eq!(adapt("poll_overrun", sample.clone(), 1), sample);
+
+        assert_eq!(adapt("monitoring_streamline", sample.clone(), 2), sample);
+        assert_eq!(adapt("health_check", health.clone(), PROTOCOL_VERSION), health);
+    }
+}
@@ -0,0 +1,214 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use tokio::time::Instant;
+
+use crate::config::SensorConfig;
+use crate::device::DeviceCommand;
+
+/// Order in which queued device commands run. Anything here runs before the
+/// next poll read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Connection changes and shutdown, after every pending write.
+    Control,
+    /// Operator writes.
+    Operator,
+    /// Stop commands, ahead of everything else.
+    Emergency,
+}
+
+struct Queued {
+    priority: Priority,
+    seq: Reverse<u64>,
+    command: DeviceCommand,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+/// Pending commands of one device, highest priority first and FIFO within
+/// a priority.
+#[derive(Default)]
+pub struct CommandQueue {
+    heap: BinaryHeap<Queued>,
+    seq: u64,
+}
+
+impl CommandQueue {
+    pub fn push(&mut self, command: DeviceCommand) {
+        self.seq += 1;
+        self.heap.push(Queued {
+            priority: command.priority(),
+            seq: Reverse(self.seq),
+            command,
+        });
+    }
+
+    pub fn pop(&mut self) -> Option<DeviceCommand> {
+        self.heap.pop().map(|queued| queued.command)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+/// A read scheduled by the poll cycle. It is only worth doing before the
+/// next cycle would read the same sensor again.
+pub struct PollRequest {
+    pub sensor: SensorConfig,
+    pub deadline: Instant,
+}
+
+/// Reads of one poll cycle, in sensor order from a starting sensor.
+pub struct PollQueue {
+    requests: VecDeque<PollRequest>,
+    served: usize,
+}
+
+impl PollQueue {
+    /// Queues a read of every sensor, beginning at `start` and wrapping
+    /// around, so a cycle that runs late does not always drop the same ones.
+    pub fn new(mut sensors: Vec<SensorConfig>, start: usize, deadline: Instant) -> Self {
+        if !sensors.is_empty() {
+            let start = start % sensors.len();
+            sensors.rotate_left(start);
+        }
+        Self {
+            requests: sensors
+                .into_iter()
+                .map(|sensor| PollRequest { sensor, deadline })
+                .collect(),
+            served: 0,
+        }
+    }
+
+    /// Returns the next request still within its deadline, along with the
+    /// expired requests dropped to reach it.
+    pub fn next(&mut self, now: Instant) -> (Option<PollRequest>, Vec<PollRequest>) {
+        let mut dropped = Vec::new();
+        while let Some(request) = self.requests.pop_front() {
+            if request.deadline > now {
+                self.served += 1;
+                return (Some(request), dropped);
+            }
+            dropped.push(request);
+        }
+        (None, dropped)
+    }
+
+    /// Requests handed out so far, dropped ones excluded.
+    pub fn served(&self) -> usize {
+        self.served
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PollSettings;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn write(reg: u16) -> DeviceCommand {
+        DeviceCommand::Write {
+            reg,
+            val: 1,
+            r_type: "REG".to_string(),
+            reply: oneshot::channel().0,
+        }
+    }
+
+    fn sensor(id: &str) -> SensorConfig {
+        SensorConfig {
+            id: id.to_string(),
+            label: id.to_string(),
+            s_type: "sensor".to_string(),
+            r_type: "REG".to_string(),
+            start_register: 0,
+            register: "0".to_string(),
+            end_register: 1,
+            device: None,
+        }
+    }
+
+    fn ids(requests: &[PollRequest]) -> Vec<&str> {
+        requests.iter().map(|r| r.sensor.id.as_str()).collect()
+    }
+
+    #[test]
+    fn commands_run_by_priority_then_in_arrival_order() {
+        let mut queue = CommandQueue::default();
+        queue.push(DeviceCommand::Configure {
+            settings: PollSettings::default(),
+            is_default: true,
+        });
+        queue.push(write(1));
+        queue.push(DeviceCommand::Stop {
+            reply: oneshot::channel().0,
+        });
+        queue.push(write(2));
+        queue.push(DeviceCommand::Shutdown(oneshot::channel().0));
+
+        let mut order = Vec::new();
+        while let Some(command) = queue.pop() {
+            order.push(match command {
+                DeviceCommand::Stop { .. } => "stop".to_string(),
+                DeviceCommand::Write { reg, .. } => format!("write {}", reg),
+                DeviceCommand::Configure { .. } => "configure".to_string(),
+                DeviceCommand::Reconnect(..) => "reconnect".to_string(),
+                DeviceCommand::Shutdown(_) => "shutdown".to_string(),
+            });
+        }
+        assert_eq!(order, ["stop", "write 1", "write 2", "configure", "shutdown"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn poll_reads_start_at_the_given_sensor_and_wrap_around() {
+        let now = Instant::now();
+        let sensors = vec![sensor("a"), sensor("b"), sensor("c")];
+        let mut queue = PollQueue::new(sensors, 4, now + Duration::from_secs(1));
+
+        let mut served = Vec::new();
+        while let (Some(request), dropped) = queue.next(now) {
+            assert!(dropped.is_empty());
+            served.push(request);
+        }
+        assert_eq!(ids(&served), ["b", "c", "a"]);
+        assert_eq!(queue.served(), 3);
+    }
+
+    #[test]
+    fn reads_past_their_deadline_are_dropped() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(1);
+        let mut queue = PollQueue::new(vec![sensor("a"), sensor("b"), sensor("c")], 0, deadline);
+
+        let (first, dropped) = queue.next(now);
+        assert_eq!(first.unwrap().sensor.id, "a");
+        assert!(dropped.is_empty());
+
+        let (rest, dropped) = queue.next(deadline);
+        assert!(rest.is_none());
+        assert_eq!(ids(&dropped), ["b", "c"]);
+        assert_eq!(queue.served(), 1);
+    }
+}
@@ -0,0 +1,487 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio_modbus::client::Context;
+use tracing::{debug, error, info, warn};
+
+use crate::agent::Agent;
+use crate::config::{AgentConfig, ChEvent, DeviceConfig, SensorConfig};
+use crate::device::DeviceCommand;
+use crate::helper::AppError;
+use crate::logging;
+use crate::mdb_client;
+use crate::plc_io::{self, Request};
+use crate::wire::{ConfigApplied, ConfigRejected, Failure};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a reload changed, reported back to the backend.
+#[derive(Serialize, Debug, Default, JsonSchema)]
+pub struct ReloadSummary {
+    pub added_devices: Vec<String>,
+    pub removed_devices: Vec<String>,
+    pub reconnected_devices: Vec<String>,
+    pub restarted_polling: bool,
+    /// Changed sections that only take effect after a restart.
+    pub restart_required: Vec<String>,
+}
+
+/// Sends `ReloadConfig` when the file's modification time changes or the
+/// process receives SIGHUP.
+pub fn spawn_watchers(path: PathBuf, tx: mpsc::Sender<ChEvent>) {
+    let watch_tx = tx.clone();
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = modified(&path);
+            if current.is_some() && current != last_modified {
+                last_modified = current;
+                info!("Configuration file {} changed, reloading", path.display());
+                if watch_tx.send(ChEvent::ReloadConfig).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            if tx.send(ChEvent::ReloadConfig).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads run one at a time. New and changed devices are connected and
+/// probed in a spawned task, so commands keep flowing meanwhile; the result
+/// comes back to the dispatcher as a [`Prepared`] and is applied there.
+/// Requests that arrive while one is in flight wait their turn.
+pub struct Reloads {
+    tx: mpsc::Sender<Prepared>,
+    rx: mpsc::Receiver<Prepared>,
+    in_flight: bool,
+    waiting: VecDeque<ChEvent>,
+}
+
+impl Reloads {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel(1);
+        Self {
+            tx,
+            rx,
+            in_flight: false,
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// Resolves once the devices of the reload in flight are connected.
+    pub async fn prepared(&mut self) -> Option<Prepared> {
+        self.rx.recv().await
+    }
+}
+
+impl Default for Reloads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a reload came from, which decides how it is saved and reported.
+enum Origin {
+    File,
+    /// Pushed by the backend; `document` is what gets saved to the file.
+    Remote { version: u64, document: Box<AgentConfig> },
+}
+
+/// A configuration whose new and changed devices are connected and
+/// answered a probe, or the reason they did not.
+pub struct Prepared {
+    origin: Origin,
+    config: AgentConfig,
+    summary: ReloadSummary,
+    connections: Result<Vec<(DeviceConfig, Context)>, AppError>,
+}
+
+/// Starts a `ReloadConfig` or `ApplyConfig`, or queues it behind the one in
+/// flight.
+pub async fn request(agent: &mut Agent, path: &Path, event: ChEvent) {
+    agent.reloads.waiting.push_back(event);
+    start_next(agent, path).await;
+}
+
+/// Applies a reload whose devices are connected, reports it and starts the
+/// next waiting one.
+pub async fn finish(agent: &mut Agent, path: &Path, prepared: Prepared) {
+    agent.reloads.in_flight = false;
+    let Prepared {
+        origin,
+        config,
+        summary,
+        connections,
+    } = prepared;
+    match origin {
+        Origin::File => match connections {
+            Ok(connections) => {
+                commit(agent, config, &summary, connections).await;
+                info!(?summary, "Configuration reloaded");
+                report(agent, "config_reloaded", &summary).await;
+            }
+            Err(e) => reload_failed(agent, &e).await,
+        },
+        Origin::Remote { version, document } => {
+            // Saved before anything changes, so a failed save leaves the
+            // agent as it was.
+            match connections.and_then(|c| document.save(path).map(|_| c)) {
+                Ok(connections) => {
+                    commit(agent, config, &summary, connections).await;
+                    info!(version, ?summary, "Applied configuration from backend");
+                    report(agent, "config_applied", &ConfigApplied { version, summary }).await;
+                }
+                Err(e) => rejected(agent, version, &e).await,
+            }
+        }
+    }
+
+    start_next(agent, path).await;
+}
+
+/// Starts waiting requests until one is in flight; requests that fail
+/// before connecting anything are reported right away.
+async fn start_next(agent: &mut Agent, path: &Path) {
+    while !agent.reloads.in_flight {
+        let Some(event) = agent.reloads.waiting.pop_front() else {
+            return;
+        };
+        agent.reloads.in_flight = match event {
+            ChEvent::ApplyConfig { version, config } => {
+                start_remote(agent, path, version, *config).await
+            }
+            _ => start_file(agent, path).await,
+        };
+    }
+}
+
+/// Loads the file and starts applying it. A file that matches the running
+/// configuration, such as one the agent just saved itself, is not applied
+/// again. Returns whether a reload is now in flight.
+async fn start_file(agent: &mut Agent, path: &Path) -> bool {
+    let config = match AgentConfig::load(path) {
+        Ok(config) if config == agent.config => {
+            debug!("Configuration file matches the running configuration");
+            return false;
+        }
+        Ok(config) => config,
+        Err(e) => {
+            reload_failed(agent, &e).await;
+            return false;
+        }
+    };
+    match prepare(agent, Origin::File, config).await {
+        Ok(()) => true,
+        Err(e) => {
+            reload_failed(agent, &e).await;
+            false
+        }
+    }
+}
+
+/// Starts applying a configuration pushed by the backend. Returns whether
+/// a reload is now in flight.
+///
+/// The file gets the backend's document with the file's own uplink section,
+/// so the backend cannot redirect the agent or swap its credentials, and
+/// environment overrides never end up on disk. The agent runs that document
+/// with the overrides applied, as it would after a restart.
+async fn start_remote(agent: &mut Agent, path: &Path, version: u64, mut document: AgentConfig) -> bool {
+    let running = agent.config.version;
+    let result = if version <= running {
+        Err(AppError::ValidationError(format!(
+            "configuration version {} is not newer than the running version {}",
+            version, running
+        )))
+    } else {
+        document.version = version;
+        match AgentConfig::read_file(path).and_then(|file| {
+            document.uplink = file.uplink;
+            document.clone().with_env()
+        }) {
+            Ok(config) => {
+                let origin = Origin::Remote {
+                    version,
+                    document: Box::new(document),
+                };
+                prepare(agent, origin, config).await
+            }
+            Err(e) => Err(e),
+        }
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            rejected(agent, version, &e).await;
+            false
+        }
+    }
+}
+
+/// Validates `config` and spawns the task that connects and probes its new
+/// and changed devices, which hands the result to [`Reloads::prepared`].
+async fn prepare(agent: &Agent, origin: Origin, config: AgentConfig) -> Result<(), AppError> {
+    config.validate()?;
+    let summary = diff(&agent.config, &config);
+
+    let mut changed = Vec::new();
+    {
+        let state = agent.state.lock().await;
+        for device in &config.devices {
+            if summary.added_devices.contains(&device.name)
+                || summary.reconnected_devices.contains(&device.name)
+            {
+                let is_default = config.default_device() == device.name;
+                let sensor = state
+                    .registered_sensors
+                    .iter()
+                    .find(|s| s.belongs_to(&device.name, is_default))
+                    .cloned();
+                changed.push((device.clone(), sensor));
+            }
+        }
+    }
+
+    let tx = agent.reloads.tx.clone();
+    tokio::spawn(async move {
+        let connections = connect_all(changed).await;
+        let prepared = Prepared {
+            origin,
+            config,
+            summary,
+            connections,
+        };
+        let _ = tx.send(prepared).await;
+    });
+    Ok(())
+}
+
+async fn connect_all(
+    devices: Vec<(DeviceConfig, Option<SensorConfig>)>,
+) -> Result<Vec<(DeviceConfig, Context)>, AppError> {
+    let mut connections = Vec::new();
+    for (device, sensor) in devices {
+        debug!(device = %device.name, "Connecting to PLC at {}", device.address);
+        let mut ctx = mdb_client::connect(&device).await?;
+        probe(&device, sensor, &mut ctx).await?;
+        connections.push((device, ctx));
+    }
+    Ok(connections)
+}
+
+/// Switches the agent to `config`. Nothing here talks to a new PLC, so it
+/// cannot fail halfway: the agent runs either the old configuration or the
+/// new one. Unchanged devices keep their connection, and sensors and last
+/// values are never dropped.
+async fn commit(
+    agent: &mut Agent,
+    config: AgentConfig,
+    summary: &ReloadSummary,
+    connections: Vec<(DeviceConfig, Context)>,
+) {
+    if agent.config.logging.filter != config.logging.filter {
+        // Checked by validation, so this only fails if the filter layer is gone.
+        if let Err(e) = logging::set_filter(&agent.log_handle, &config.logging.filter) {
+            error!("Failed to apply log filter: {}", e);
+        }
+    }
+
+    for name in &summary.removed_devices {
+        agent.remove_device(name).await;
+    }
+    agent.config = config;
+
+    // Changed devices keep their actor and swap connections between reads.
+    for (device, ctx) in connections {
+        match agent.devices.get(&device.name) {
+            Some(handle) => {
+                let name = device.name.clone();
+                if let Err(e) = handle.send(DeviceCommand::Reconnect(device, ctx)).await {
+                    error!(device = %name, "Failed to reconnect device: {}", e);
+                }
+            }
+            None => agent.spawn_device(device, ctx),
+        }
+    }
+    agent.configure_devices().await;
+}
+
+async fn reload_failed(agent: &Agent, e: &AppError) {
+    error!("Configuration reload failed, keeping the running configuration: {}", e);
+    report(agent, "config_reload_failed", &Failure::from(e)).await;
+}
+
+async fn rejected(agent: &Agent, version: u64, e: &AppError) {
+    error!(version, "Rejected configuration from backend: {}", e);
+    let rejected = ConfigRejected {
+        version,
+        running_version: agent.config.version,
+        failure: Failure::from(e),
+    };
+    report(agent, "config_rejected", &rejected).await;
+}
+
+async fn report<T: Serialize>(agent: &Agent, event: &str, data: &T) {
+    if let Err(e) = agent.send_json(event, data).await {
+        warn!("Failed to report {}: {}", event, e);
+    }
+}
+
+/// What applying `new` over `old` changes. Devices whose settings differ
+/// are reconnected; sections the agent only reads at start-up are listed
+/// as needing a restart.
+fn diff(old: &AgentConfig, new: &AgentConfig) -> ReloadSummary {
+    let mut summary = ReloadSummary::default();
+    for device in &new.devices {
+        match old.devices.iter().find(|d| d.name == device.name) {
+            None => summary.added_devices.push(device.name.clone()),
+            Some(previous) if previous != device => {
+                summary.reconnected_devices.push(device.name.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    summary.removed_devices = old
+        .devices
+        .iter()
+        .filter(|d| !new.devices.iter().any(|n| n.name == d.name))
+        .map(|d| d.name.clone())
+        .collect();
+    summary.restarted_polling = old.poll != new.poll;
+
+    if old.uplink != new.uplink {
+        summary.restart_required.push("uplink".to_string());
+    }
+    if old.buffers != new.buffers {
+        summary.restart_required.push("buffers".to_string());
+    }
+    if old.metrics != new.metrics {
+        summary.restart_required.push("metrics".to_string());
+    }
+    if old.opcua != new.opcua {
+        summary.restart_required.push("opcua".to_string());
+    }
+    if old.logging.format != new.logging.format
+        || old.logging.dir != new.logging.dir
+        || old.logging.max_files != new.logging.max_files
+    {
+        summary.restart_required.push("logging".to_string());
+    }
+    summary
+}
+
+/// Reads the device's first sensor, or holding register 0 without one, so a
+/// PLC that accepts the connection but does not answer is refused before
+/// anything changes. An exception response counts as an answer.
+async fn probe(
+    device: &DeviceConfig,
+    sensor: Option<SensorConfig>,
+    ctx: &mut Context,
+) -> Result<(), AppError> {
+    let request = match sensor {
+        Some(sensor) => Request::Read {
+            start_register: sensor.start_register,
+            end_register: sensor.end_register,
+            r_type: sensor.r_type,
+        },
+        None => Request::Read {
+            start_register: 0,
+            end_register: 1,
+            r_type: "REG".to_string(),
+        },
+    };
+    let timeout = Duration::from_millis(device.timeout_ms);
+    match tokio::time::timeout(timeout, plc_io::execute(ctx, &request)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(failure)) if !failure.link_down => Ok(()),
+        Ok(Err(failure)) => Err(failure.error),
+        Err(_) => Err(AppError::RequestTimeout(format!(
+            "{} did not answer a {} within {} ms",
+            device.name, request, device.timeout_ms
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(devices: &str) -> AgentConfig {
+        let raw = format!("[uplink]\nurl = \"https://backend.example:8000\"\n{}", devices);
+        AgentConfig::parse(&raw).unwrap()
+    }
+
+    #[test]
+    fn diff_reports_device_changes_and_sections_needing_a_restart() {
+        let old = config(
+            r#"
+            [[devices]]
+            name = "plc"
+            address = "10.0.0.1:502"
+            [[devices]]
+            name = "line2"
+            address = "10.0.0.2:502"
+            [[devices]]
+            name = "line3"
+            address = "10.0.0.3:502"
+            "#,
+        );
+        let mut new = config(
+            r#"
+            [[devices]]
+            name = "plc"
+            address = "10.0.0.1:502"
+            [[devices]]
+            name = "line2"
+            address = "10.0.0.2:502"
+            timeout_ms = 500
+            [[devices]]
+            name = "line4"
+            address = "10.0.0.4:502"
+            "#,
+        );
+        new.poll.interval_ms += 1;
+        new.opcua.enabled = true;
+
+        let summary = diff(&old, &new);
+        assert_eq!(summary.added_devices, ["line4"]);
+        assert_eq!(summary.reconnected_devices, ["line2"]);
+        assert_eq!(summary.removed_devices, ["line3"]);
+        assert!(summary.restarted_polling);
+        assert_eq!(summary.restart_required, ["opcua"]);
+
+        let unchanged = diff(&old, &old);
+        assert!(unchanged.added_devices.is_empty());
+        assert!(unchanged.reconnected_devices.is_empty());
+        assert!(unchanged.removed_devices.is_empty());
+        assert!(!unchanged.restarted_polling);
+        assert!(unchanged.restart_required.is_empty());
+    }
+}
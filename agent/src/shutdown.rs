@@ -0,0 +1,137 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, warn};
+
+use crate::agent::Agent;
+use crate::config::ChEvent;
+use crate::helper::{now_timestamp, AppError};
+
+/// How long queued writes and stops may take to reach the PLCs, and the
+/// device actors to finish, on shutdown.
+const QUEUED_COMMAND_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How long telling the backend and closing the uplink may take afterwards.
+const UPLINK_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct AgentOffline {
+    pub reason: String,
+    pub time: String,
+    pub dropped_events: usize,
+}
+
+/// Resolves with the signal name once SIGINT or SIGTERM is received.
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+/// Stops the agent in order: refuse new commands, run the writes and stops
+/// already queued, let every device actor finish, tell the backend why and
+/// close every connection. Each phase has a deadline, so an unreachable PLC
+/// or broker cannot hold the process up.
+pub async fn run(agent: &mut Agent, rx: &mut mpsc::Receiver<ChEvent>, reason: &str) {
+    info!(reason, "Shutting down");
+
+    let deadline = Instant::now() + QUEUED_COMMAND_DEADLINE;
+    let mut dropped_events = 0;
+    if timeout_at(deadline, stop_devices(agent, rx, deadline, &mut dropped_events))
+        .await
+        .is_err()
+    {
+        warn!("Device actors did not stop before the shutdown deadline, aborting them");
+        agent.devices.clear();
+        for (_, task) in agent.device_tasks.drain() {
+            task.abort();
+        }
+    }
+
+    let offline = AgentOffline {
+        reason: reason.to_string(),
+        time: now_timestamp(),
+        dropped_events,
+    };
+    let farewell = async {
+        if let Err(e) = agent.send_json("agent_offline", &offline).await {
+            warn!("Failed to send agent_offline: {}", e);
+        }
+        // Flushes the samples still queued ahead of agent_offline.
+        agent.uplink.close().await;
+    };
+    if tokio::time::timeout(UPLINK_CLOSE_TIMEOUT, farewell).await.is_err() {
+        warn!("Uplink did not close before the shutdown deadline");
+    }
+    info!("Shutdown complete");
+}
+
+/// Runs the queued writes and stops, then shuts every device actor down.
+/// Other queued events are counted in `dropped_events`.
+async fn stop_devices(
+    agent: &mut Agent,
+    rx: &mut mpsc::Receiver<ChEvent>,
+    deadline: Instant,
+    dropped_events: &mut usize,
+) {
+    rx.close();
+    let mut queued = Vec::new();
+    while let Ok(event) = rx.try_recv() {
+        if !matches!(event, ChEvent::Write { .. } | ChEvent::Stop) {
+            warn!("Dropping queued event during shutdown: {:?}", event);
+            *dropped_events += 1;
+            continue;
+        }
+        agent.event = event;
+        match agent.queue_command().await {
+            Ok(Some((device, pending))) => queued.push((agent.event.name(), device, pending)),
+            Ok(None) => {}
+            Err(e) => agent.uplink.command_failed(agent.event.name(), &e).await,
+        }
+    }
+    if let Some(mut writes) = agent.local_writes.take() {
+        writes.close();
+        while let Ok(write) = writes.try_recv() {
+            agent.local_write(write).await;
+        }
+    }
+
+    for (command, device, pending) in queued {
+        let result = match timeout_at(deadline, pending.result()).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError::RequestTimeout(format!(
+                "{} on {} did not finish before shutdown",
+                command, device
+            ))),
+        };
+        if let Err(e) = result {
+            error!(device = %device, "{} failed during shutdown: {}", command, e);
+            agent.uplink.command_failed(command, &e).await;
+        }
+    }
+
+    let devices: Vec<String> = agent.devices.keys().cloned().collect();
+    for device in devices {
+        agent.remove_device(&device).await;
+    }
+}
@@ -0,0 +1,576 @@
+//! Sparkplug B over the MQTT uplink. The agent is the edge node, every PLC a
+//! device and every sensor a metric named after its id.
+
+use chrono::Utc;
+use prost::Message;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::config::SensorConfig;
+use crate::helper::AppError;
+use crate::plc_io::{ModbusData, Quality};
+use crate::protocol::AGENT_VERSION;
+use crate::state::SharedState;
+use crate::ChEvent;
+
+const NAMESPACE: &str = "spBv1.0";
+pub const REBIRTH: &str = "Node Control/Rebirth";
+pub const BD_SEQ: &str = "bdSeq";
+const UPTIME: &str = "Agent/Uptime";
+const PAUSED: &str = "Agent/Paused";
+const VERSION: &str = "Properties/Agent Version";
+
+/// Sparkplug B datatypes the agent uses.
+pub mod datatype {
+    pub const INT32: u32 = 3;
+    pub const UINT16: u32 = 6;
+    pub const UINT64: u32 = 8;
+    pub const BOOLEAN: u32 = 11;
+    pub const STRING: u32 = 12;
+}
+
+/// `org.eclipse.tahu.protobuf.Payload`, limited to the fields the agent
+/// reads or writes. Unknown fields are skipped when decoding.
+#[derive(Clone, PartialEq, Message)]
+pub struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    #[prost(message, optional, tag = "9")]
+    pub properties: Option<PropertySet>,
+    #[prost(oneof = "MetricValue", tags = "10, 11, 14, 15")]
+    pub value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    /// Every integer type up to 32 bits, UInt16 included.
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PropertySet {
+    #[prost(string, repeated, tag = "1")]
+    pub keys: Vec<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub values: Vec<PropertyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PropertyValue {
+    #[prost(uint32, optional, tag = "1")]
+    pub r#type: Option<u32>,
+    #[prost(bool, optional, tag = "2")]
+    pub is_null: Option<bool>,
+    #[prost(uint32, optional, tag = "3")]
+    pub int_value: Option<u32>,
+}
+
+impl Metric {
+    fn named(name: &str, datatype: u32, value: MetricValue) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            datatype: Some(datatype),
+            timestamp: Some(now_millis()),
+            value: Some(value),
+            ..Default::default()
+        }
+    }
+
+    /// The metric with this name, if it carries a value.
+    pub fn find<'a>(metrics: &'a [Metric], name: &str) -> Option<&'a MetricValue> {
+        metrics
+            .iter()
+            .find(|m| m.name.as_deref() == Some(name))
+            .and_then(|m| m.value.as_ref())
+    }
+}
+
+/// Settings under `[uplink.mqtt.sparkplug]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct SparkplugConfig {
+    /// Publishes samples as Sparkplug B instead of JSON telemetry.
+    pub enabled: bool,
+    pub group_id: String,
+    /// Defaults to the MQTT client id.
+    pub edge_node: Option<String>,
+    /// Accepts DCMD writes. They carry no signature, so they must be turned
+    /// off when `uplink.command_key_file` requires signed writes.
+    pub device_writes: bool,
+}
+
+impl Default for SparkplugConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group_id: "synk9".to_string(),
+            edge_node: None,
+            device_writes: true,
+        }
+    }
+}
+
+impl SparkplugConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if !self.enabled {
+            return;
+        }
+        let ids = [("group_id", Some(&self.group_id)), ("edge_node", self.edge_node.as_ref())];
+        for (field, id) in ids {
+            if id.is_some_and(|id| id.is_empty() || id.contains(['/', '+', '#'])) {
+                errors.push(format!(
+                    "uplink.mqtt.sparkplug.{}: must be a non-empty topic level",
+                    field
+                ));
+            }
+        }
+    }
+}
+
+/// What a node or device command asks for.
+#[derive(Debug)]
+pub enum Inbound {
+    Rebirth,
+    /// Writes for the agent, in the same form as backend commands.
+    Writes(Vec<ChEvent>),
+}
+
+/// Topic and payload of one message to publish.
+pub type Outgoing = (String, Vec<u8>);
+
+/// Sequence numbers and births of the current session.
+#[derive(Default)]
+struct Session {
+    seq: u8,
+    /// Stable for the life of the process, so hosts can keep theirs.
+    aliases: HashMap<String, u64>,
+    /// Metrics announced in each device's last DBIRTH, with their datatype.
+    born: HashMap<String, Vec<(String, u32)>>,
+}
+
+impl Session {
+    fn alias(&mut self, sensor: &str) -> u64 {
+        let next = self.aliases.len() as u64 + 1;
+        *self.aliases.entry(sensor.to_string()).or_insert(next)
+    }
+
+    fn payload(&mut self, metrics: Vec<Metric>) -> Vec<u8> {
+        let payload = Payload {
+            timestamp: Some(now_millis()),
+            metrics,
+            seq: Some(self.seq as u64),
+        };
+        self.seq = self.seq.wrapping_add(1);
+        payload.encode_to_vec()
+    }
+}
+
+/// The agent as a Sparkplug edge node. Turns samples into births and data
+/// messages and node or device commands into agent events.
+pub struct EdgeNode {
+    group: String,
+    edge: String,
+    device_writes: bool,
+    /// Carried by NBIRTH and the NDEATH will, and advanced for every MQTT
+    /// session so a late death of an earlier session or run never matches
+    /// the current birth. Starts from the clock.
+    bd_seq: AtomicU64,
+    state: Arc<Mutex<SharedState>>,
+    session: StdMutex<Session>,
+}
+
+impl EdgeNode {
+    pub fn new(
+        config: &SparkplugConfig,
+        client_id: &str,
+        state: Arc<Mutex<SharedState>>,
+    ) -> Self {
+        Self {
+            group: config.group_id.clone(),
+            edge: config.edge_node.clone().unwrap_or_else(|| client_id.to_string()),
+            device_writes: config.device_writes,
+            bd_seq: AtomicU64::new(Utc::now().timestamp().rem_euclid(256) as u64),
+            state,
+            session: StdMutex::new(Session::default()),
+        }
+    }
+
+    fn node_topic(&self, kind: &str) -> String {
+        format!("{}/{}/{}/{}", NAMESPACE, self.group, kind, self.edge)
+    }
+
+    fn device_topic(&self, kind: &str, device: &str) -> String {
+        format!("{}/{}", self.node_topic(kind), device)
+    }
+
+    /// Node and device command subscriptions.
+    pub fn command_filters(&self) -> [String; 2] {
+        [self.node_topic("NCMD"), self.device_topic("DCMD", "+")]
+    }
+
+    pub fn is_command(&self, topic: &str) -> bool {
+        topic == self.node_topic("NCMD") || topic.starts_with(&self.device_topic("DCMD", ""))
+    }
+
+    /// Moves to the bdSeq of the next session. Its `death` must replace the
+    /// will before the client connects again.
+    pub fn next_session(&self) {
+        let _ = self
+            .bd_seq
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |seq| Some((seq + 1) % 256));
+    }
+
+    /// NDEATH, registered as the will and sent before a clean disconnect.
+    pub fn death(&self) -> Outgoing {
+        let payload = Payload {
+            timestamp: Some(now_millis()),
+            metrics: vec![Metric::named(BD_SEQ, datatype::UINT64, MetricValue::Long(self.bd_seq.load(Ordering::SeqCst)))],
+            seq: None,
+        };
+        (self.node_topic("NDEATH"), payload.encode_to_vec())
+    }
+
+    /// NBIRTH, then a DBIRTH for every device with sensors. Starts a new
+    /// sequence, so it is sent on every connect and rebirth request.
+    pub async fn birth(&self) -> Vec<Outgoing> {
+        let (sensors, values, default, uptime, paused) = {
+            let state = self.state.lock().await;
+            (
+                state.registered_sensors.clone(),
+                state.last_values.clone(),
+                state.default_device.clone(),
+                state.started_at.elapsed().as_secs(),
+                state.paused_agent,
+            )
+        };
+
+        let mut session = self.session.lock().expect("sparkplug session lock");
+        session.seq = 0;
+        session.born.clear();
+        let node_metrics = vec![
+            Metric::named(BD_SEQ, datatype::UINT64, MetricValue::Long(self.bd_seq.load(Ordering::SeqCst))),
+            Metric::named(REBIRTH, datatype::BOOLEAN, MetricValue::Boolean(false)),
+            Metric::named(VERSION, datatype::STRING, MetricValue::String(AGENT_VERSION.to_string())),
+            Metric::named(UPTIME, datatype::UINT64, MetricValue::Long(uptime)),
+            Metric::named(PAUSED, datatype::BOOLEAN, MetricValue::Boolean(paused)),
+        ];
+        let mut births = vec![(self.node_topic("NBIRTH"), session.payload(node_metrics))];
+
+        let mut devices: Vec<&str> = sensors.iter().map(|s| device_of(s, &default)).collect();
+        devices.sort();
+        devices.dedup();
+        for device in devices {
+            births.push(self.device_birth(&mut session, device, &default, &sensors, &values, None));
+        }
+        births
+    }
+
+    /// DDATA for a sample. When the sample's metric was not in the device's
+    /// last birth, the device is reborn instead: a DDEATH if it was born,
+    /// then a DBIRTH with all of its metrics.
+    pub async fn sample(&self, data: Value) -> Result<Vec<Outgoing>, AppError> {
+        let sample: ModbusData =
+            serde_json::from_value(data).map_err(|e| AppError::DeserializationError {
+                message: "invalid sample".to_string(),
+                source: e,
+            })?;
+        let (sensors, values, default) = {
+            let state = self.state.lock().await;
+            (
+                state.registered_sensors.clone(),
+                state.last_values.clone(),
+                state.default_device.clone(),
+            )
+        };
+        let Some(sensor) = sensors.iter().find(|s| s.id == sample.sensor_id) else {
+            debug!(sensor_id = %sample.sensor_id, "Dropping sample of a removed sensor");
+            return Ok(Vec::new());
+        };
+
+        let device = device_of(sensor, &default);
+        let datatype = datatype_of(sensor);
+        let mut session = self.session.lock().expect("sparkplug session lock");
+        let born = session.born.get(device);
+        if !born.is_some_and(|metrics| metrics.iter().any(|(id, t)| *id == sensor.id && *t == datatype)) {
+            let mut messages = Vec::new();
+            if born.is_some() {
+                messages.push((self.device_topic("DDEATH", device), session.payload(Vec::new())));
+            }
+            let birth = self.device_birth(&mut session, device, &default, &sensors, &values, Some(&sample));
+            messages.push(birth);
+            return Ok(messages);
+        }
+
+        let metric = Metric {
+            alias: Some(session.alias(&sensor.id)),
+            timestamp: Some(now_millis()),
+            ..reading(datatype, Some((sample.value, sample.quality)))
+        };
+        Ok(vec![(self.device_topic("DDATA", device), session.payload(vec![metric]))])
+    }
+
+    /// NDATA with the node metrics found in a health report.
+    pub fn health(&self, report: &Value) -> Outgoing {
+        let mut metrics = Vec::new();
+        if let Some(uptime) = report["uptime_secs"].as_u64() {
+            metrics.push(Metric::named(UPTIME, datatype::UINT64, MetricValue::Long(uptime)));
+        }
+        if let Some(paused) = report["paused"].as_bool() {
+            metrics.push(Metric::named(PAUSED, datatype::BOOLEAN, MetricValue::Boolean(paused)));
+        }
+        let mut session = self.session.lock().expect("sparkplug session lock");
+        (self.node_topic("NDATA"), session.payload(metrics))
+    }
+
+    /// Decodes an NCMD or DCMD. Device metrics are matched by name or alias
+    /// and turned into writes to the sensor's first register, unless device
+    /// writes are turned off.
+    pub async fn command(&self, topic: &str, payload: &[u8]) -> Result<Inbound, AppError> {
+        let payload = Payload::decode(payload)
+            .map_err(|e| AppError::ValidationError(format!("Invalid Sparkplug payload: {}", e)))?;
+
+        let Some(device) = topic.strip_prefix(&self.device_topic("DCMD", "")) else {
+            return match Metric::find(&payload.metrics, REBIRTH) {
+                Some(MetricValue::Boolean(true)) => Ok(Inbound::Rebirth),
+                _ => Err(AppError::ValidationError(
+                    "Unsupported node command, only rebirth is accepted".to_string(),
+                )),
+            };
+        };
+
+        if !self.device_writes {
+            return Err(AppError::ValidationError(
+                "Sparkplug device writes are turned off".to_string(),
+            ));
+        }
+
+        let (sensors, default) = {
+            let state = self.state.lock().await;
+            (state.registered_sensors.clone(), state.default_device.clone())
+        };
+        let aliases = self.session.lock().expect("sparkplug session lock").aliases.clone();
+        let mut writes = Vec::new();
+        for metric in &payload.metrics {
+            let id = match (&metric.name, metric.alias) {
+                (Some(name), _) => name.as_str(),
+                (None, Some(alias)) => aliases
+                    .iter()
+                    .find(|(_, a)| **a == alias)
+                    .map(|(id, _)| id.as_str())
+                    .ok_or_else(|| {
+                        AppError::ValidationError(format!("Unknown metric alias {}", alias))
+                    })?,
+                (None, None) => {
+                    return Err(AppError::ValidationError(
+                        "Metric needs a name or alias".to_string(),
+                    ))
+                }
+            };
+            let sensor = sensors
+                .iter()
+                .find(|s| s.id == id && device_of(s, &default) == device)
+                .ok_or_else(|| {
+                    AppError::ValidationError(format!("Unknown metric {} on device {}", id, device))
+                })?;
+            let val = match metric.value {
+                Some(MetricValue::Boolean(on)) => Some(on as u16),
+                Some(MetricValue::Int(v)) => u16::try_from(v).ok(),
+                Some(MetricValue::Long(v)) => u16::try_from(v).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                AppError::ValidationError(format!("Metric {} needs a 16-bit or boolean value", id))
+            })?;
+            writes.push(ChEvent::Write {
+                reg: sensor.start_register,
+                val,
+                r_type: sensor.r_type.clone(),
+                device: Some(device.to_string()),
+            });
+        }
+        Ok(Inbound::Writes(writes))
+    }
+
+    /// DBIRTH listing every sensor of `device`, with the latest sample or
+    /// last good value of each.
+    fn device_birth(
+        &self,
+        session: &mut Session,
+        device: &str,
+        default: &str,
+        sensors: &[SensorConfig],
+        values: &HashMap<String, u16>,
+        sample: Option<&ModbusData>,
+    ) -> Outgoing {
+        let mut metrics = Vec::new();
+        let mut born = Vec::new();
+        for sensor in sensors.iter().filter(|s| device_of(s, default) == device) {
+            let datatype = datatype_of(sensor);
+            let value = match sample {
+                Some(sample) if sample.sensor_id == sensor.id => Some((sample.value, sample.quality)),
+                _ => values.get(&sensor.id).map(|v| (*v, Quality::Good)),
+            };
+            metrics.push(Metric {
+                name: Some(sensor.id.clone()),
+                alias: Some(session.alias(&sensor.id)),
+                timestamp: Some(now_millis()),
+                ..reading(datatype, value)
+            });
+            born.push((sensor.id.clone(), datatype));
+        }
+        session.born.insert(device.to_string(), born);
+        (self.device_topic("DBIRTH", device), session.payload(metrics))
+    }
+}
+
+/// Sparkplug device of `sensor`, given the running default device.
+fn device_of<'a>(sensor: &'a SensorConfig, default: &'a str) -> &'a str {
+    sensor.device.as_deref().unwrap_or(default)
+}
+
+/// Registers map to UInt16, coils to Boolean.
+fn datatype_of(sensor: &SensorConfig) -> u32 {
+    if sensor.r_type == "REG" {
+        datatype::UINT16
+    } else {
+        datatype::BOOLEAN
+    }
+}
+
+/// Value and quality of a sensor metric. Anything but good quality is
+/// flagged with an OPC `Quality` property.
+fn reading(datatype: u32, value: Option<(u16, Quality)>) -> Metric {
+    let Some((value, quality)) = value else {
+        return Metric {
+            datatype: Some(datatype),
+            is_null: Some(true),
+            ..Default::default()
+        };
+    };
+    let properties = (quality != Quality::Good).then(|| PropertySet {
+        keys: vec!["Quality".to_string()],
+        values: vec![PropertyValue {
+            r#type: Some(datatype::INT32),
+            is_null: None,
+            int_value: Some(quality.opc_code() as u32),
+        }],
+    });
+    let value = if datatype == datatype::BOOLEAN {
+        MetricValue::Boolean(value != 0)
+    } else {
+        MetricValue::Int(value as u32)
+    };
+    Metric {
+        datatype: Some(datatype),
+        properties,
+        value: Some(value),
+        ..Default::default()
+    }
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sensor(id: &str, device: Option<&str>) -> SensorConfig {
+        SensorConfig {
+            id: id.to_string(),
+            label: id.to_string(),
+            s_type: "sensor".to_string(),
+            r_type: "REG".to_string(),
+            start_register: 0,
+            register: "0".to_string(),
+            end_register: 1,
+            device: device.map(str::to_string),
+        }
+    }
+
+    fn sample(id: &str) -> Value {
+        json!({
+            "sensor_id": id,
+            "register": "0",
+            "time": "2026-01-01T00:00:00Z",
+            "value": 7,
+            "key": id,
+            "s_type": "sensor",
+            "r_type": "REG",
+            "quality": "good",
+        })
+    }
+
+    fn kinds(messages: &[Outgoing]) -> Vec<&str> {
+        messages.iter().map(|(topic, _)| topic.split('/').nth(2).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn new_metrics_rebirth_their_device() {
+        let state = SharedState::new("plc");
+        let node = EdgeNode::new(&SparkplugConfig::default(), "edge", state.clone());
+        state.lock().await.add_sensor(sensor("level", None));
+
+        assert_eq!(kinds(&node.sample(sample("level")).await.unwrap()), ["DBIRTH"]);
+        assert_eq!(kinds(&node.sample(sample("level")).await.unwrap()), ["DDATA"]);
+
+        state.lock().await.add_sensor(sensor("flow", None));
+        let rebirth = node.sample(sample("flow")).await.unwrap();
+        assert_eq!(kinds(&rebirth), ["DDEATH", "DBIRTH"]);
+        let dbirth = Payload::decode(rebirth[1].1.as_slice()).unwrap();
+        assert_eq!(dbirth.metrics.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sensors_without_a_device_belong_to_the_current_default() {
+        let state = SharedState::new("plc");
+        let node = EdgeNode::new(&SparkplugConfig::default(), "edge", state.clone());
+        state.lock().await.add_sensor(sensor("level", None));
+        state.lock().await.add_sensor(sensor("flow", Some("line2")));
+
+        state.lock().await.default_device = "line1".to_string();
+        let births = node.birth().await;
+        let topics: Vec<&str> = births.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "spBv1.0/synk9/NBIRTH/edge",
+                "spBv1.0/synk9/DBIRTH/edge/line1",
+                "spBv1.0/synk9/DBIRTH/edge/line2",
+            ]
+        );
+    }
+}
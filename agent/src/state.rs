@@ -1,39 +1,125 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tracing::{debug, info};
 
 use crate::config::SensorConfig;
+use crate::helper::now_timestamp;
 
+/// Link state of one PLC as seen by the poll loop.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct DeviceHealth {
+    pub link_up: bool,
+    pub last_success: Option<String>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    /// Modbus requests that hit the device timeout.
+    pub timeouts: u64,
+    /// Reads re-issued after a timeout or dropped link.
+    pub retries: u64,
+    pub poll: PollStats,
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct PollStats {
+    pub interval_ms: u64,
+    pub cycles: u64,
+    pub last_cycle_ms: u64,
+    pub max_cycle_ms: u64,
+    pub overruns: u64,
+    /// Poll reads dropped because commands held them past their deadline.
+    pub dropped_reads: u64,
+}
+
+/// State store shared by the dispatcher, device actors and uplink. Only held
+/// for short in-memory updates, never across PLC or network I/O.
 #[derive(Debug)]
 pub struct SharedState {
     pub registered_sensors: Vec<SensorConfig>,
     pub paused_agent: bool,
+    /// Last value read with good quality, keyed by sensor id.
+    pub last_values: HashMap<String, u16>,
+    pub started_at: Instant,
+    pub devices: HashMap<String, DeviceHealth>,
+    /// Device of sensors that name none, kept in step with the running
+    /// configuration.
+    pub default_device: String,
 }
 
 impl SharedState {
-    pub fn new() -> Arc<Mutex<Self>> {
+    pub fn new(default_device: &str) -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(Self {
             registered_sensors: Vec::new(),
             paused_agent: false,
+            last_values: HashMap::new(),
+            started_at: Instant::now(),
+            devices: HashMap::new(),
+            default_device: default_device.to_string(),
         }))
     }
 
+    pub fn record_read_success(&mut self, device: &str) {
+        let health = self.devices.entry(device.to_string()).or_default();
+        health.link_up = true;
+        health.last_success = Some(now_timestamp());
+        health.consecutive_failures = 0;
+    }
+
+    pub fn record_read_failure(&mut self, device: &str, error: String, link_down: bool) {
+        let health = self.devices.entry(device.to_string()).or_default();
+        if link_down {
+            health.link_up = false;
+        }
+        health.last_error = Some(error);
+        health.consecutive_failures += 1;
+    }
+
+    pub fn record_timeout(&mut self, device: &str) {
+        self.devices.entry(device.to_string()).or_default().timeouts += 1;
+    }
+
+    pub fn record_retry(&mut self, device: &str) {
+        self.devices.entry(device.to_string()).or_default().retries += 1;
+    }
+
+    pub fn record_cycle(&mut self, device: &str, elapsed: Duration, overrun: bool) {
+        let poll = &mut self.devices.entry(device.to_string()).or_default().poll;
+        let elapsed_ms = elapsed.as_millis() as u64;
+        poll.cycles += 1;
+        poll.last_cycle_ms = elapsed_ms;
+        poll.max_cycle_ms = poll.max_cycle_ms.max(elapsed_ms);
+        if overrun {
+            poll.overruns += 1;
+        }
+    }
+
+    pub fn record_dropped_reads(&mut self, device: &str, dropped: u64) {
+        self.devices.entry(device.to_string()).or_default().poll.dropped_reads += dropped;
+    }
+
     pub fn add_sensor(&mut self, sensor: SensorConfig) {
-        println!("Adding sensor: {:?}", sensor);
+        info!(sensor_id = %sensor.id, "Adding sensor");
+        debug!(?sensor, "Sensor config");
         self.registered_sensors.push(sensor);
-        println!("Current sensors: {:?}", self.registered_sensors);
     }
 
     pub fn remove_sensor(&mut self, id: &str) {
         self.registered_sensors.retain(|sensor| sensor.id != id);
-        println!(
-            "Sensor {} removed, remaining: {:?}",
-            id, self.registered_sensors
+        self.last_values.remove(id);
+        info!(
+            sensor_id = id,
+            remaining = self.registered_sensors.len(),
+            "Sensor removed"
         );
     }
 
     pub fn cleanup_sensors(&mut self) {
         self.registered_sensors.clear();
-        println!("All sensors cleared");
+        self.last_values.clear();
+        info!("All sensors cleared");
     }
 
     pub fn edit_sensor(
@@ -47,7 +133,28 @@ impl SharedState {
             sensor.label = new_label;
             sensor.start_register = new_start_register;
             sensor.end_register = new_end_register;
-            println!("Sensor {} updated: {:?}", id, sensor);
+            info!(sensor_id = id, "Sensor updated");
+            debug!(?sensor, "Sensor config");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_and_retries_are_counted_per_device() {
+        let state = SharedState::new("plc");
+        let mut state = state.try_lock().unwrap();
+        state.record_timeout("plc");
+        state.record_timeout("plc");
+        state.record_retry("plc");
+        state.record_timeout("line2");
+
+        assert_eq!(state.devices["plc"].timeouts, 2);
+        assert_eq!(state.devices["plc"].retries, 1);
+        assert_eq!(state.devices["line2"].timeouts, 1);
+        assert_eq!(state.devices["line2"].retries, 0);
+    }
+}
@@ -0,0 +1,275 @@
+use native_tls::{Certificate, Identity, TlsConnector};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509VerifyResult, X509};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslStream;
+use tracing::{debug, warn};
+use url::{Position, Url};
+
+use crate::helper::AppError;
+
+/// TLS options for the uplink, only applied to `https://` and `mqtts://` URLs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// PEM bundle of trusted CAs. When set, the system roots are not trusted.
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprints (hex, `:` optional) of accepted server certificates.
+    /// When set, connections go through a local tunnel that checks them in
+    /// the TLS handshake.
+    pub pins: Vec<String>,
+    /// PEM client certificate and PKCS#8 key for mutual TLS.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl TlsSettings {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        for pin in &self.pins {
+            let fingerprint = normalize_fingerprint(pin);
+            if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                errors.push(format!(
+                    "uplink.tls.pins: {:?} is not a SHA-256 fingerprint of 64 hex digits",
+                    pin
+                ));
+            }
+        }
+    }
+
+    pub fn connector(&self) -> Result<TlsConnector, AppError> {
+        let mut builder = TlsConnector::builder();
+
+        if let Some(ca_file) = &self.ca_file {
+            let pem = read_file(ca_file)?;
+            let certs = X509::stack_from_pem(&pem)
+                .map_err(|e| tls_error(format!("Invalid CA bundle {}: {}", ca_file, e)))?;
+            for cert in certs {
+                let der = cert.to_der().map_err(|e| tls_error(e.to_string()))?;
+                let cert = Certificate::from_der(&der).map_err(|e| tls_error(e.to_string()))?;
+                builder.add_root_certificate(cert);
+            }
+            builder.disable_built_in_roots(true);
+        }
+
+        if let (Some(cert_file), Some(key_file)) = (&self.client_cert, &self.client_key) {
+            let identity = Identity::from_pkcs8(&read_file(cert_file)?, &read_file(key_file)?)
+                .map_err(|e| tls_error(format!("Invalid client certificate: {}", e)))?;
+            builder.identity(identity);
+        }
+
+        builder
+            .build()
+            .map_err(|e| tls_error(format!("Failed to build TLS connector: {}", e)))
+    }
+
+    /// Opens a loopback listener whose connections are carried over TLS to
+    /// `host:port`. The pins are checked during each upstream handshake, so
+    /// nothing is relayed to a server that fails them.
+    pub async fn tunnel(&self, host: &str, port: u16) -> Result<SocketAddr, AppError> {
+        let connector = self.pinned_connector()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(|e| tls_error(format!("Failed to open TLS tunnel: {}", e)))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| tls_error(e.to_string()))?;
+
+        let host = host.to_string();
+        debug!("Pinned TLS tunnel to {}:{} on {}", host, port, local_addr);
+        tokio::spawn(async move {
+            loop {
+                let local = match listener.accept().await {
+                    Ok((local, _)) => local,
+                    Err(e) => {
+                        warn!("TLS tunnel accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let (connector, host) = (connector.clone(), host.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = relay(local, &connector, &host, port).await {
+                        warn!("{}", e);
+                    }
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Where an HTTP client should connect for `url`. Secure URLs go through
+    /// a pinned tunnel when pins are configured.
+    pub async fn route(&self, url: &str) -> Result<Route, AppError> {
+        if self.pins.is_empty() || !is_secure(url) {
+            return Ok(Route {
+                url: url.to_string(),
+                host: None,
+            });
+        }
+
+        let parsed =
+            Url::parse(url).map_err(|e| tls_error(format!("Invalid URL {}: {}", url, e)))?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| tls_error(format!("URL {} has no host", url)))?
+            .to_string();
+        let port = parsed.port_or_known_default().unwrap_or(443);
+        let authority = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.clone(),
+        };
+
+        let tunnel = self.tunnel(&host, port).await?;
+        let scheme = if parsed.scheme() == "wss" { "ws" } else { "http" };
+        // Keep the path as written, callers append to bare base URLs.
+        let mut rest = &parsed[Position::BeforePath..];
+        if rest == "/" && !url.ends_with('/') {
+            rest = "";
+        }
+        Ok(Route {
+            url: format!("{}://{}{}", scheme, tunnel, rest),
+            host: Some(authority),
+        })
+    }
+
+    /// Openssl connector that applies the CA bundle and client certificate,
+    /// and only accepts a leaf certificate matching one of the pins.
+    fn pinned_connector(&self) -> Result<SslConnector, AppError> {
+        let mut connector =
+            SslConnector::builder(SslMethod::tls()).map_err(|e| tls_error(e.to_string()))?;
+        if let Some(ca_file) = &self.ca_file {
+            connector
+                .set_ca_file(ca_file)
+                .map_err(|e| tls_error(format!("Invalid CA bundle {}: {}", ca_file, e)))?;
+        }
+        if let (Some(cert_file), Some(key_file)) = (&self.client_cert, &self.client_key) {
+            connector
+                .set_certificate_chain_file(cert_file)
+                .and_then(|_| connector.set_private_key_file(key_file, SslFiletype::PEM))
+                .map_err(|e| tls_error(format!("Invalid client certificate: {}", e)))?;
+        }
+
+        let pins: Vec<String> = self.pins.iter().map(|pin| normalize_fingerprint(pin)).collect();
+        connector.set_verify_callback(SslVerifyMode::PEER, move |verified, ctx| {
+            if !verified || ctx.error_depth() != 0 {
+                return verified;
+            }
+            let fingerprint = ctx
+                .current_cert()
+                .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+            let pinned = fingerprint.as_ref().is_some_and(|f| pins.contains(f));
+            if !pinned {
+                warn!("Certificate pin mismatch: got {}", fingerprint.unwrap_or_default());
+                ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+            }
+            pinned
+        });
+        Ok(connector.build())
+    }
+}
+
+/// A URL to connect to, and the `Host` header to send when it points at a
+/// tunnel rather than at the server itself.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub url: String,
+    pub host: Option<String>,
+}
+
+/// Copies one local connection to and from the server, once the pinned
+/// handshake has succeeded.
+async fn relay(
+    mut local: TcpStream,
+    connector: &SslConnector,
+    host: &str,
+    port: u16,
+) -> Result<(), AppError> {
+    let upstream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| tls_error(format!("Failed to connect to {}:{}: {}", host, port, e)))?;
+    let ssl = connector
+        .configure()
+        .and_then(|config| config.into_ssl(host))
+        .map_err(|e| tls_error(e.to_string()))?;
+    let mut upstream = SslStream::new(ssl, upstream).map_err(|e| tls_error(e.to_string()))?;
+    Pin::new(&mut upstream)
+        .connect()
+        .await
+        .map_err(|e| tls_error(format!("TLS handshake with {} failed: {}", host, e)))?;
+    // Either side closing ends the relay; the error only says which.
+    let _ = copy_bidirectional(&mut local, &mut upstream).await;
+    Ok(())
+}
+
+/// Engine.IO upgrades from HTTP itself, so ws(s) URLs are accepted as aliases.
+pub fn http_url(url: &str) -> String {
+    url.replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1)
+}
+
+pub fn is_secure(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("wss://")
+}
+
+fn normalize_fingerprint(pin: &str) -> String {
+    pin.trim().replace(':', "").to_lowercase()
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, AppError> {
+    fs::read(path).map_err(|e| tls_error(format!("Failed to read {}: {}", path, e)))
+}
+
+fn tls_error(message: String) -> AppError {
+    AppError::TlsError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin_errors(pin: &str) -> Vec<String> {
+        let settings = TlsSettings {
+            pins: vec![pin.to_string()],
+            ..TlsSettings::default()
+        };
+        let mut errors = Vec::new();
+        settings.validate(&mut errors);
+        errors
+    }
+
+    #[test]
+    fn pins_must_be_sha256_fingerprints() {
+        let hex = "AB".repeat(32);
+        let colons = vec!["ab"; 32].join(":");
+        assert!(pin_errors(&hex).is_empty());
+        assert!(pin_errors(&colons).is_empty());
+        assert_eq!(pin_errors(&"ab".repeat(20)).len(), 1);
+        assert_eq!(pin_errors(&"zz".repeat(32)).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_secure_urls_with_pins_are_routed_through_a_tunnel() {
+        let pinned = TlsSettings {
+            pins: vec!["ab".repeat(32)],
+            ..TlsSettings::default()
+        };
+        let plain = pinned.route("http://backend:3000").await.unwrap();
+        assert_eq!(plain.url, "http://backend:3000");
+        assert!(plain.host.is_none());
+
+        let unpinned = TlsSettings::default().route("https://backend").await.unwrap();
+        assert_eq!(unpinned.url, "https://backend");
+
+        let tunnelled = pinned.route("https://backend:8443/api").await.unwrap();
+        assert!(tunnelled.url.starts_with("http://127.0.0.1:"));
+        assert!(tunnelled.url.ends_with("/api"));
+        assert_eq!(tunnelled.host.as_deref(), Some("backend:8443"));
+    }
+}
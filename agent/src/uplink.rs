@@ -0,0 +1,147 @@
+use rust_socketio::asynchronous::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tracing::{error, warn};
+
+use crate::helper::{AppError, ErrorCode};
+use crate::mqtt::MqttLink;
+use crate::plc_io::ModbusData;
+use crate::protocol::{self, Negotiated};
+use crate::state::SharedState;
+use crate::wire::{CommandFailed, Failure, Message};
+
+/// The connection events leave through.
+pub enum Link {
+    SocketIo(Client),
+    Mqtt(MqttLink),
+}
+
+impl Link {
+    async fn emit(&self, event: &str, data: Value) -> Result<(), AppError> {
+        match self {
+            Link::SocketIo(client) => client.emit(event, data).await.map_err(AppError::from),
+            Link::Mqtt(link) => link.emit(event, data).await,
+        }
+    }
+
+    async fn close(&self) {
+        match self {
+            Link::SocketIo(client) => {
+                if let Err(e) = client.disconnect().await {
+                    warn!("Failed to close Socket.IO connection: {}", e);
+                }
+            }
+            Link::Mqtt(link) => link.close().await,
+        }
+    }
+}
+
+enum Outbound {
+    Emit { event: String, data: Value },
+    Close(oneshot::Sender<()>),
+}
+
+/// Handle to the emitter task, the only owner of the uplink connection.
+/// Cloned into every task that reports to the backend.
+#[derive(Clone)]
+pub struct Uplink {
+    tx: mpsc::Sender<Outbound>,
+    /// Every sample, for local consumers such as the OPC UA server. Lagging
+    /// receivers lose samples rather than slowing the poll loop.
+    samples: broadcast::Sender<ModbusData>,
+}
+
+impl Uplink {
+    pub fn spawn(
+        link: Link,
+        state: Arc<Mutex<SharedState>>,
+        protocol: Negotiated,
+        capacity: usize,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(capacity);
+        let (samples, _) = broadcast::channel(capacity);
+        tokio::spawn(run_emitter(link, state, protocol, rx));
+        Self { tx, samples }
+    }
+
+    pub fn samples(&self) -> broadcast::Receiver<ModbusData> {
+        self.samples.subscribe()
+    }
+
+    /// Queues a sample for the backend and hands it to local subscribers.
+    pub async fn send_sample(&self, sample: &ModbusData) -> Result<(), AppError> {
+        let _ = self.samples.send(sample.clone());
+        self.send_json("monitoring_streamline", sample).await
+    }
+
+    /// Queues an event for the backend.
+    pub async fn send_json<T: Serialize>(&self, event: &str, data: &T) -> Result<(), AppError> {
+        let data = serde_json::to_value(data)
+            .map_err(|e| AppError::InternalError(format!("Failed to encode {}: {}", event, e)))?;
+        self.tx
+            .send(Outbound::Emit {
+                event: event.to_string(),
+                data,
+            })
+            .await
+            .map_err(|_| AppError::socket_io("uplink is closed".to_string()))
+    }
+
+    pub async fn send_message(&self, event: &str, message: &str) -> Result<(), AppError> {
+        let message = Message {
+            message: message.to_string(),
+        };
+        self.send_json(event, &message).await
+    }
+
+    /// Reports a command that failed after it was accepted.
+    pub async fn command_failed(&self, command: &str, error: &AppError) {
+        let result = CommandFailed {
+            command: command.to_string(),
+            failure: Failure::from(error),
+        };
+        if let Err(e) = self.send_json("command_failed", &result).await {
+            warn!("Failed to report failed command: {}", e);
+        }
+    }
+
+    /// Flushes everything queued so far, then closes the connection.
+    pub async fn close(&self) {
+        let (reply, done) = oneshot::channel();
+        if self.tx.send(Outbound::Close(reply)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+}
+
+async fn run_emitter(
+    link: Link,
+    state: Arc<Mutex<SharedState>>,
+    protocol: Negotiated,
+    mut rx: mpsc::Receiver<Outbound>,
+) {
+    while let Some(outbound) = rx.recv().await {
+        match outbound {
+            Outbound::Emit { event, data } => {
+                // Shaped at send time: the session may have been renegotiated
+                // while the event was queued.
+                let data = protocol::adapt(&event, data, protocol.version());
+                if let Err(err) = link.emit(&event, data).await {
+                    error!(event = %event, code = ?err.code(), "Failed to emit: {}", err);
+                    // The backend re-registers sensors after the session is
+                    // re-established, so drop ours rather than duplicate them.
+                    if err.code() == ErrorCode::UplinkTransportLost {
+                        state.lock().await.registered_sensors.clear();
+                    }
+                }
+            }
+            Outbound::Close(reply) => {
+                link.close().await;
+                let _ = reply.send(());
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use schemars::{JsonSchema, SchemaGenerator};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::health::HealthReport;
+use crate::helper::{parse_message_to_event, AppError, ErrorReport};
+use crate::monitoring::PollOverrun;
+use crate::plc_io::ModbusData;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::reload::ReloadSummary;
+use crate::shutdown::AgentOffline;
+use crate::ChEvent;
+
+/// Version of the wire schema, published as `schema/wire-v{N}.json`.
+pub const WIRE_VERSION: u8 = PROTOCOL_VERSION;
+
+/// First protocol whose commands carry a version. Older servers send bare
+/// commands, which are still accepted.
+pub const FIRST_VERSIONED: u8 = 3;
+
+/// A command from the backend: `{"v": 3, "command": {"AddSensor": {...}}}`.
+/// Unit commands are plain strings: `{"v": 3, "command": "HealthCheck"}`.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Command {
+    pub v: u8,
+    pub command: ChEvent,
+}
+
+impl Command {
+    pub fn new(command: ChEvent) -> Self {
+        Self {
+            v: WIRE_VERSION,
+            command,
+        }
+    }
+}
+
+/// Parses an inbound command, versioned or bare. Versions this build does
+/// not know are rejected before the payload is looked at.
+pub fn parse_command(message: &Value) -> Result<ChEvent, AppError> {
+    let Some(v) = message.get("v") else {
+        return parse_message_to_event(message);
+    };
+    let version = v
+        .as_u64()
+        .ok_or_else(|| AppError::ValidationError(format!("Wire version must be a number, got {}", v)))?;
+    if !(FIRST_VERSIONED as u64..=WIRE_VERSION as u64).contains(&version) {
+        return Err(AppError::UnsupportedVersion(version));
+    }
+    let command: Command =
+        serde_json::from_value(message.clone()).map_err(|e| AppError::DeserializationError {
+            message: "failed to parse command".to_string(),
+            source: e,
+        })?;
+    Ok(command.command)
+}
+
+/// Payload of status notices such as `agent_locked` and `agent_status`.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Message {
+    pub message: String,
+}
+
+/// Acknowledges every inbound message, sent as `data`.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Ack {
+    pub status: &'static str,
+}
+
+impl Ack {
+    pub const RECEIVED: Ack = Ack { status: "received" };
+}
+
+/// Something the agent could not do, with the error behind it.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Failure {
+    pub message: String,
+    pub error: ErrorReport,
+}
+
+impl From<&AppError> for Failure {
+    fn from(error: &AppError) -> Self {
+        Self {
+            message: error.to_string(),
+            error: error.report(),
+        }
+    }
+}
+
+/// A command that failed after it was accepted.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct CommandFailed {
+    pub command: String,
+    #[serde(flatten)]
+    pub failure: Failure,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct ConfigApplied {
+    pub version: u64,
+    pub summary: ReloadSummary,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct ConfigRejected {
+    pub version: u64,
+    /// Version the agent runs after rejecting `version`.
+    pub running_version: u64,
+    #[serde(flatten)]
+    pub failure: Failure,
+}
+
+/// Every event the agent emits, with the schema of its payload.
+fn events(generator: &mut SchemaGenerator) -> Map<String, Value> {
+    let mut events = Map::new();
+    let mut add = |name: &str, schema: schemars::Schema| {
+        events.insert(name.to_string(), schema.to_value());
+    };
+    add("data", generator.subschema_for::<Ack>());
+    add("monitoring_streamline", generator.subschema_for::<ModbusData>());
+    add("health_check", generator.subschema_for::<HealthReport>());
+    add("poll_overrun", generator.subschema_for::<PollOverrun>());
+    add("command_failed", generator.subschema_for::<CommandFailed>());
+    add("command_rejected", generator.subschema_for::<Failure>());
+    add("config_reloaded", generator.subschema_for::<ReloadSummary>());
+    add("config_reload_failed", generator.subschema_for::<Failure>());
+    add("config_applied", generator.subschema_for::<ConfigApplied>());
+    add("config_rejected", generator.subschema_for::<ConfigRejected>());
+    add("agent_offline", generator.subschema_for::<AgentOffline>());
+    for notice in ["agent_status", "agent_locked", "write_denied", "log_level"] {
+        add(notice, generator.subschema_for::<Message>());
+    }
+    events
+}
+
+/// JSON Schema of the whole wire protocol: the command envelope the backend
+/// sends and the payload of every event the agent emits.
+pub fn schema() -> Value {
+    let mut generator = SchemaGenerator::default();
+    let command = generator.subschema_for::<Command>().to_value();
+    let events = events(&mut generator);
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "synk9 agent wire protocol",
+        "version": WIRE_VERSION,
+        "command": command,
+        "events": events,
+        "$defs": generator.take_definitions(true),
+    })
+}
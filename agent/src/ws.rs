@@ -1,72 +1,159 @@
 use futures::FutureExt;
 use rust_socketio::Payload;
-use rust_socketio::{asynchronous::Client, asynchronous::ClientBuilder};
+use rust_socketio::asynchronous::{Client, ClientBuilder, ReconnectSettings};
 use serde_json::json;
 use std::error::Error as StdError;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 
-use crate::helper::parse_message_to_event;
+use crate::envelope::CommandVerifier;
+use crate::helper::AppError;
+use crate::identity::AgentAuth;
+use crate::metrics::metrics;
+use crate::protocol::{Negotiated, ServerHello};
+use crate::tls::{self, Route, TlsSettings};
+use crate::wire::{Ack, Failure};
 use crate::ChEvent;
 
+/// Connects to the backend along `route`, the one the enrollment client
+/// uses too, so a pinned backend is reached through a single tunnel.
 pub async fn setup_socket_io(
-    url: &str,
+    route: &Route,
     tx: mpsc::Sender<ChEvent>,
-    fingerprint: &str,
+    auth: AgentAuth,
+    tls: &TlsSettings,
+    verifier: Arc<CommandVerifier>,
+    protocol: Negotiated,
 ) -> Result<Client, Box<dyn StdError>> {
-    let socket = ClientBuilder::new(url)
-        .auth(json!({
-            "token": fingerprint,
-            "type": "agent"
-        }))
+    let url = route.url.as_str();
+    let secure = tls::is_secure(url);
+    if route.host.is_none() && !secure && (!tls.pins.is_empty() || tls.client_cert.is_some()) {
+        warn!("TLS settings are ignored for non-TLS URL {}", url);
+    }
+
+    // With pins, every connection, reconnects included, goes through the
+    // pinned tunnel, so credentials never reach a server that fails them.
+    let mut builder = ClientBuilder::new(url);
+    if let Some(host) = &route.host {
+        builder = builder.opening_header("Host", host.as_str());
+    } else if secure {
+        builder = builder.tls_config(tls.connector()?);
+    }
+
+    let reconnect_protocol = protocol.clone();
+    let socket = builder
+        .auth(auth.payload()?)
         .namespace("/")
         .reconnect_on_disconnect(true)
+        .on_reconnect(move || {
+            let auth = auth.clone();
+            // The new session may land on a different server build.
+            reconnect_protocol.reset();
+            async move {
+                metrics().socketio_reconnects.inc();
+                let mut settings = ReconnectSettings::new();
+                // Signed handshakes need a fresh timestamp and nonce every time.
+                match auth.payload() {
+                    Ok(payload) => settings.auth(payload),
+                    Err(e) => error!("Failed to build auth payload: {}", e),
+                }
+                settings
+            }
+            .boxed()
+        })
+        .on("open", |_, _| {
+            async move {
+                metrics().socketio_connected.set(1);
+            }
+            .boxed()
+        })
+        .on("close", |_, _| {
+            async move {
+                metrics().socketio_connected.set(0);
+            }
+            .boxed()
+        })
+        .on("server_hello", move |payload: Payload, _| {
+            let protocol = protocol.clone();
+            async move {
+                let Payload::Text(values) = payload else {
+                    return;
+                };
+                let Some(hello) = values.into_iter().next() else {
+                    return;
+                };
+                let accepted = serde_json::from_value::<ServerHello>(hello)
+                    .map_err(|e| AppError::DeserializationError {
+                        message: "invalid server hello".to_string(),
+                        source: e,
+                    })
+                    .and_then(|hello| protocol.accept(&hello));
+                if let Err(e) = accepted {
+                    warn!(
+                        protocol = protocol.version(),
+                        "Keeping current uplink protocol: {}", e
+                    );
+                }
+            }
+            .boxed()
+        })
         .on("data", move |payload: Payload, socket: Client| {
             let tx = tx.clone();
+            let verifier = verifier.clone();
 
             async move {
                 match payload {
                     Payload::Text(ref str) => {
                         let msg = &str[0];
-                        println!("Received message: {:?}", msg);
+                        debug!("Received message: {:?}", msg);
 
-                        match parse_message_to_event(msg) {
+                        match verifier.open(msg) {
                             Ok(event) => {
                                 if let Err(e) = tx.send(event).await {
-                                    eprintln!("Failed to send event to channel: {}", e);
+                                    error!("Failed to send event to channel: {}", e);
+                                }
+                            }
+                            Err(e) => {
+                                warn!(code = ?e.code(), "Rejected command: {}", e);
+                                let rejection = serde_json::to_value(Failure::from(&e))
+                                    .expect("failure report serializes");
+                                if let Err(e) = socket.emit("command_rejected", rejection).await {
+                                    warn!("Failed to report rejected command: {}", e);
                                 }
                             }
-                            Err(e) => eprintln!("Failed to parse message: {}", e),
                         }
                     }
                     Payload::Binary(bin_data) => {
-                        println!("Received binary data: {:?}", bin_data);
+                        debug!("Received binary data: {:?}", bin_data);
                     }
                     _ => {
-                        println!("Received string data ");
+                        debug!("Received string data");
                     }
                 }
 
-                if let Err(e) = socket.emit("data", json!({"status": "received"})).await {
-                    eprintln!("Failed to send acknowledgment: {}", e);
+                if let Err(e) = socket.emit("data", json!(Ack::RECEIVED)).await {
+                    warn!("Failed to send acknowledgment: {}", e);
                 }
             }
             .boxed()
         })
         .on("error", |err, _| {
             async move {
-                println!("Socket.IO error: {:?}", err);
+                error!("Socket.IO error: {:?}", err);
             }
             .boxed()
         })
         .on("disconnect", |_, _| {
             async move {
-                println!("Disconnected from Socket.IO server");
+                warn!("Disconnected from Socket.IO server");
             }
             .boxed()
         })
         .connect()
         .await?;
 
-    println!("Connected to Socket.IO server: {}", url);
+    metrics().socketio_connected.set(1);
+    info!("Connected to Socket.IO server: {}", url);
     Ok(socket)
 }
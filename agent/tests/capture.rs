@@ -0,0 +1,83 @@
+mod harness;
+
+use harness::{add_sensor, config, eventually, plc, scratch_path, Backend, TestAgent};
+use serde_json::Value;
+use simulator::{Fault, FaultRule, RegisterMap, ReplayConfig, Simulator};
+use std::fs;
+
+#[tokio::test]
+async fn captured_traffic_replays_as_a_virtual_device() {
+    let capture = scratch_path("plc.jsonl");
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let mut recording = config(&backend, &plc);
+    recording.devices[0].capture = Some(capture.clone());
+    let agent = TestAgent::start_with(&mut backend, recording).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    backend.expect("monitoring_streamline").await;
+    plc.inject(FaultRule::new(Fault::Exception { code: 2 }).times(1));
+    backend
+        .expect_where("monitoring_streamline", |s| s["quality"] == "out-of-range")
+        .await;
+    agent.shutdown().await;
+
+    let records: Vec<Value> = fs::read_to_string(&capture)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let read = &records[0];
+    assert_eq!(read["device"], "plc");
+    assert_eq!(read["function"], 3);
+    assert_eq!(read["address"], 512);
+    assert_eq!(read["count"], 1);
+    assert_eq!(read["value"], 321);
+    assert!(read["ts_ms"].as_i64().unwrap() > 0);
+    assert!(records.iter().any(|r| r["exception"] == 2));
+
+    // The site's PLC is gone; the capture stands in for it.
+    drop(plc);
+    let replay = Simulator::start(RegisterMap {
+        replay: Some(ReplayConfig::new(&capture)),
+        ..RegisterMap::local()
+    })
+    .await
+    .unwrap();
+    let mut backend = Backend::start().await;
+    let _agent = TestAgent::start(&mut backend, &replay).await;
+
+    // The replay loops, so the recorded exception comes round again too.
+    backend.send(&add_sensor("level", 512)).await;
+    let sample = backend
+        .expect_where("monitoring_streamline", |s| s["quality"] == "good")
+        .await;
+    assert_eq!(sample["value"], 321);
+    let _ = fs::remove_file(&capture);
+}
+
+#[tokio::test]
+async fn rotates_the_capture_file_at_its_size_limit() {
+    let capture = scratch_path("rotated.jsonl");
+    let rotated = capture.with_extension("jsonl.1");
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let mut recording = config(&backend, &plc);
+    recording.devices[0].capture = Some(capture.clone());
+    recording.devices[0].capture_max_bytes = 1000;
+    let agent = TestAgent::start_with(&mut backend, recording).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    eventually("capture rotation", || rotated.exists()).await;
+    agent.shutdown().await;
+
+    for path in [&capture, &rotated] {
+        let contents = fs::read_to_string(path).unwrap();
+        assert!(contents.len() <= 1000, "{} holds {} bytes", path.display(), contents.len());
+        for line in contents.lines() {
+            let record: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["device"], "plc");
+        }
+        let _ = fs::remove_file(path);
+    }
+}
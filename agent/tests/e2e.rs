@@ -0,0 +1,386 @@
+mod harness;
+
+use agent::config::{ChEvent, DeviceConfig};
+use agent::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use agent::wire::WIRE_VERSION;
+use harness::{add_sensor, config, eventually, plc, silent_plc, Backend, TestAgent, POLL_INTERVAL_MS};
+use serde_json::json;
+use simulator::{Fault, FaultRule, Table};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Long enough for a poll cycle that started before a command to finish.
+const SETTLE: Duration = Duration::from_millis(POLL_INTERVAL_MS * 2);
+
+fn is_sensor(id: &'static str) -> impl Fn(&serde_json::Value) -> bool {
+    move |sample| sample["sensor_id"] == id
+}
+
+#[tokio::test]
+async fn streams_samples_in_the_backend_format() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    let sample = backend.expect("monitoring_streamline").await;
+
+    let fields: BTreeSet<&str> = sample.as_object().unwrap().keys().map(String::as_str).collect();
+    let expected = ["key", "quality", "r_type", "register", "s_type", "sensor_id", "time", "value"];
+    assert_eq!(fields, BTreeSet::from(expected));
+    assert_eq!(sample["sensor_id"], "level");
+    assert_eq!(sample["key"], "level label");
+    assert_eq!(sample["register"], "512");
+    assert_eq!(sample["value"], 321);
+    assert_eq!(sample["quality"], "good");
+}
+
+#[tokio::test]
+async fn pause_stops_polling_and_locks_commands_until_resumed() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    backend.expect("monitoring_streamline").await;
+
+    backend.send(&ChEvent::PauseAgent).await;
+    let status = backend.expect("agent_status").await;
+    assert_eq!(status["message"], "Agent paused");
+    backend.drain(SETTLE).await;
+    backend.assert_quiet("monitoring_streamline", SETTLE * 3).await;
+
+    backend.send(&add_sensor("speed", 513)).await;
+    backend.expect("agent_locked").await;
+
+    backend.send(&ChEvent::PauseAgent).await;
+    let status = backend.expect("agent_status").await;
+    assert_eq!(status["message"], "Agent resumed");
+    backend.expect_where("monitoring_streamline", is_sensor("level")).await;
+}
+
+#[tokio::test]
+async fn cleanup_removes_every_sensor() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    backend.send(&add_sensor("speed", 513)).await;
+    let speed = backend.expect_where("monitoring_streamline", is_sensor("speed")).await;
+    assert_eq!(speed["value"], 654);
+
+    backend.send(&ChEvent::CleanUp).await;
+    backend.drain(SETTLE).await;
+    backend.assert_quiet("monitoring_streamline", SETTLE * 3).await;
+}
+
+#[tokio::test]
+async fn recovers_after_the_plc_drops_the_link() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    backend.expect("monitoring_streamline").await;
+
+    // One drop for the read and one for its retry.
+    plc.inject(FaultRule::new(Fault::Disconnect).times(2));
+    let failed = backend
+        .expect_where("monitoring_streamline", |s| s["quality"] != "good")
+        .await;
+    assert_eq!(failed["quality"], "comm-failure");
+    assert_eq!(failed["value"], 321, "last good value is kept");
+    assert_eq!(failed["error"]["code"], "plc_comm");
+    assert_eq!(failed["error"]["transient"], true);
+
+    backend
+        .expect_where("monitoring_streamline", |s| s["quality"] == "good")
+        .await;
+    let state = agent.state.lock().await;
+    assert!(state.devices["plc"].retries >= 1);
+    assert!(state.devices["plc"].link_up);
+}
+
+#[tokio::test]
+async fn keeps_taking_commands_after_the_backend_reconnects() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.cut_link();
+    backend.wait_for_handshake(2).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    let sample = backend.expect("monitoring_streamline").await;
+    assert_eq!(sample["sensor_id"], "level");
+}
+
+#[tokio::test]
+async fn overrunning_cycles_do_not_starve_the_last_sensors() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    let ids = ["a", "b", "c", "d"];
+    for (register, id) in (20..).zip(ids) {
+        backend.send(&add_sensor(id, register)).await;
+    }
+    backend.expect_where("monitoring_streamline", |s| s["sensor_id"] == "d").await;
+
+    // Two reads fit in the 100 ms interval, the other two are dropped.
+    plc.inject(FaultRule::new(Fault::Latency { ms: 60 }));
+    let dropped = backend
+        .expect_where("monitoring_streamline", |s| s["quality"] == "stale")
+        .await;
+    assert_eq!(dropped["error"]["code"], "request_timeout");
+
+    let mut read = BTreeSet::new();
+    while read.len() < ids.len() {
+        let sample = backend
+            .expect_where("monitoring_streamline", |s| s["quality"] == "good")
+            .await;
+        read.insert(sample["sensor_id"].as_str().unwrap().to_string());
+    }
+}
+
+#[tokio::test]
+async fn write_and_stop_reach_the_plc() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend
+        .send(&ChEvent::Write {
+            reg: 21,
+            val: 1234,
+            r_type: "REG".to_string(),
+            device: None,
+        })
+        .await;
+    eventually("write of register 21", || {
+        plc.get(Table::HoldingRegisters, 21) == Some(1234)
+    })
+    .await;
+
+    backend.send(&ChEvent::Stop).await;
+    eventually("stop", || plc.get(Table::HoldingRegisters, 0) == Some(8)).await;
+}
+
+#[tokio::test]
+async fn writes_that_time_out_are_not_retried() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    // The PLC applies the write but the answer is lost.
+    plc.inject(FaultRule::new(Fault::NoResponse).function(6));
+    let requests = plc.request_count();
+    backend
+        .send(&ChEvent::Write {
+            reg: 21,
+            val: 1234,
+            r_type: "REG".to_string(),
+            device: None,
+        })
+        .await;
+    let failed = backend.expect("command_failed").await;
+    assert_eq!(failed["command"], "Write");
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(plc.request_count(), requests + 1);
+}
+
+#[tokio::test]
+async fn a_stop_does_not_wait_for_read_retries() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let mut config = config(&backend, &plc);
+    config.devices[0].retries = 10;
+    let _agent = TestAgent::start_with(&mut backend, config).await;
+
+    plc.inject(FaultRule::new(Fault::NoResponse).function(3).addresses(512, 512));
+    backend.send(&add_sensor("level", 512)).await;
+    backend
+        .expect_where("monitoring_streamline", |s| s["quality"] != "good")
+        .await;
+
+    // Retrying the read would take 11 timeouts of 300 ms; the stop only
+    // waits for the attempt in flight.
+    let sent = std::time::Instant::now();
+    backend.send(&ChEvent::Stop).await;
+    eventually("stop", || plc.get(Table::HoldingRegisters, 0) == Some(8)).await;
+    assert!(sent.elapsed() < Duration::from_millis(1500), "{:?}", sent.elapsed());
+}
+
+#[tokio::test]
+async fn writes_reach_the_plc_in_the_order_sent() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    for val in 1..=20 {
+        backend
+            .send(&ChEvent::Write {
+                reg: 21,
+                val,
+                r_type: "REG".to_string(),
+                device: None,
+            })
+            .await;
+    }
+    eventually("last write of register 21", || {
+        plc.get(Table::HoldingRegisters, 21) == Some(20)
+    })
+    .await;
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(20));
+}
+
+#[tokio::test]
+async fn reports_writes_the_plc_rejects() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend
+        .send(&ChEvent::Write {
+            reg: 400,
+            val: 1,
+            r_type: "REG".to_string(),
+            device: None,
+        })
+        .await;
+    let failed = backend.expect("command_failed").await;
+    assert_eq!(failed["command"], "Write");
+    assert_eq!(failed["error"]["code"], "modbus_exception");
+    assert_eq!(failed["error"]["exception"], "illegal_data_address");
+}
+
+#[tokio::test]
+async fn rejects_commands_it_cannot_parse() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend
+        .send_raw(json!({
+            "type_of_event": "add_sensor",
+            "data": { "id": "level", "start_register": 512, "end_register": 1 }
+        }))
+        .await;
+    let rejected = backend.expect("command_rejected").await;
+    assert_eq!(rejected["error"]["code"], "deserialization");
+}
+
+#[tokio::test]
+async fn announces_shutdown_to_the_backend() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let agent = TestAgent::start(&mut backend, &plc).await;
+
+    agent.shutdown().await;
+    let offline = backend.expect("agent_offline").await;
+    assert_eq!(offline["reason"], "test finished");
+    assert_eq!(offline["dropped_events"], 0);
+}
+
+#[tokio::test]
+async fn runs_queued_writes_before_shutting_down() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let running = config(&backend, &plc);
+    let agent = TestAgent::start_with(&mut backend, running.clone()).await;
+    running.save(&agent.config_path).unwrap();
+
+    // Probing a device that never answers holds the event loop, so the
+    // writes below are still queued when the shutdown starts.
+    let mut pushed = running.clone();
+    let mut silent = pushed.devices[0].clone();
+    silent.name = "silent".to_string();
+    silent.address = silent_plc().await;
+    pushed.devices.push(DeviceConfig { retries: 0, ..silent });
+    backend
+        .send(&ChEvent::ApplyConfig {
+            version: 1,
+            config: Box::new(pushed),
+        })
+        .await;
+    for reg in [21, 400] {
+        backend
+            .send(&ChEvent::Write {
+                reg,
+                val: 1234,
+                r_type: "REG".to_string(),
+                device: None,
+            })
+            .await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent.shutdown().await;
+
+    let failed = backend.expect("command_failed").await;
+    assert_eq!(failed["command"], "Write");
+    assert_eq!(failed["error"]["exception"], "illegal_data_address");
+    let offline = backend.expect("agent_offline").await;
+    assert_eq!(offline["dropped_events"], 0);
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(1234));
+}
+
+#[tokio::test]
+async fn announces_version_and_capabilities_in_the_handshake() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    let auth = backend.wait_for_handshake(1).await;
+    assert_eq!(auth["type"], "agent");
+    assert_eq!(auth["agent_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(auth["protocol"]["version"], PROTOCOL_VERSION);
+    assert_eq!(auth["protocol"]["min"], MIN_PROTOCOL_VERSION);
+    assert_eq!(auth["capabilities"]["transports"], json!(["socket.io", "mqtt"]));
+    assert_eq!(auth["capabilities"]["batching"], false);
+    assert_eq!(auth["capabilities"]["alarms"], false);
+}
+
+#[tokio::test]
+async fn speaks_the_original_format_to_servers_without_a_hello() {
+    let mut backend = Backend::legacy().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    let sample = backend.expect("monitoring_streamline").await;
+    assert!(sample.get("quality").is_none());
+    assert_eq!(sample["value"], 321);
+
+    backend.send(&ChEvent::HealthCheck).await;
+    let health = backend.expect("health_check").await;
+    assert_eq!(health[0]["id"], "level", "legacy health check is the sensor list");
+}
+
+#[tokio::test]
+async fn keeps_the_original_format_when_the_server_version_is_unsupported() {
+    let mut backend = Backend::speaking(Some(PROTOCOL_VERSION + 1)).await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    let bare = serde_json::to_value(add_sensor("level", 512)).unwrap();
+    backend.send_raw(bare).await;
+    let sample = backend.expect("monitoring_streamline").await;
+    assert!(sample.get("quality").is_none());
+}
+
+#[tokio::test]
+async fn rejects_commands_of_an_unknown_wire_version() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start(&mut backend, &plc).await;
+
+    backend
+        .send_raw(json!({ "v": WIRE_VERSION + 1, "command": "HealthCheck" }))
+        .await;
+    let rejected = backend.expect("command_rejected").await;
+    assert_eq!(rejected["error"]["code"], "unsupported_version");
+    assert_eq!(rejected["error"]["transient"], false);
+    backend.assert_quiet("health_check", SETTLE).await;
+}
@@ -0,0 +1,99 @@
+mod harness;
+
+use agent::config::ChEvent;
+use agent::envelope::CommandVerifier;
+use agent::wire::Command;
+use chrono::Utc;
+use harness::scratch_path;
+use openssl::base64;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde_json::{json, Value};
+
+struct Backend {
+    key: PKey<Private>,
+    key_file: String,
+}
+
+impl Backend {
+    fn new() -> Self {
+        let key = PKey::generate_ed25519().unwrap();
+        let key_file = scratch_path("command-key.pem");
+        std::fs::write(&key_file, key.public_key_to_pem().unwrap()).unwrap();
+        Self {
+            key,
+            key_file: key_file.display().to_string(),
+        }
+    }
+
+    fn verifier(&self, agent_id: &str) -> CommandVerifier {
+        CommandVerifier::from_key_file(Some(&self.key_file), agent_id).unwrap()
+    }
+
+    /// Signs `event` for `agent_id`, naming it `command` in the envelope.
+    fn envelope(&self, agent_id: &str, command: &str, event: ChEvent) -> Value {
+        let payload = serde_json::to_string(&Command::new(event)).unwrap();
+        let nonce = format!("{:x}", rand_nonce());
+        let expires_at = Utc::now().timestamp() + 60;
+        let message = format!("{}|{}|{}|{}|{}", agent_id, command, nonce, expires_at, payload);
+        let mut signer = Signer::new_without_digest(&self.key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(message.as_bytes()).unwrap();
+        json!({
+            "envelope": {
+                "agent_id": agent_id,
+                "command": command,
+                "payload": payload,
+                "nonce": nonce,
+                "expires_at": expires_at,
+                "signature": base64::encode_block(&signature),
+            }
+        })
+    }
+}
+
+fn rand_nonce() -> u128 {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).unwrap();
+    u128::from_le_bytes(bytes)
+}
+
+#[test]
+fn opens_envelopes_addressed_to_this_agent_once() {
+    let backend = Backend::new();
+    let verifier = backend.verifier("agent-1");
+
+    let envelope = backend.envelope("agent-1", "Stop", ChEvent::Stop);
+    assert!(matches!(verifier.open(&envelope), Ok(ChEvent::Stop)));
+    assert!(verifier.open(&envelope).is_err());
+}
+
+#[test]
+fn rejects_envelopes_for_another_agent() {
+    let backend = Backend::new();
+    let verifier = backend.verifier("agent-1");
+
+    let envelope = backend.envelope("agent-2", "Stop", ChEvent::Stop);
+    assert!(verifier.open(&envelope).is_err());
+}
+
+#[test]
+fn rejects_payloads_of_another_command_type() {
+    let backend = Backend::new();
+    let verifier = backend.verifier("agent-1");
+
+    let envelope = backend.envelope("agent-1", "HealthCheck", ChEvent::Stop);
+    assert!(verifier.open(&envelope).is_err());
+}
+
+#[test]
+fn requires_signatures_for_agent_control_commands() {
+    let backend = Backend::new();
+    let verifier = backend.verifier("agent-1");
+
+    for event in [ChEvent::PauseAgent, ChEvent::ReloadConfig] {
+        let message = serde_json::to_value(Command::new(event)).unwrap();
+        assert!(verifier.open(&message).is_err());
+    }
+    let health = serde_json::to_value(Command::new(ChEvent::HealthCheck)).unwrap();
+    assert!(matches!(verifier.open(&health), Ok(ChEvent::HealthCheck)));
+}
@@ -0,0 +1,270 @@
+//! Minimal MQTT 3.1.1 broker: enough of the protocol for one agent to
+//! connect, subscribe, publish with QoS 1 and leave a will.
+
+use bytes::BytesMut;
+use rumqttc::{
+    ConnAck, ConnectReturnCode, LastWill, Packet, PubAck, Publish, QoS, SubAck,
+    SubscribeReasonCode,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use super::EXPECT_TIMEOUT;
+
+const MAX_PACKET: usize = 1024 * 1024;
+
+/// A message a client published, or a will the broker published for it.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub topic: String,
+    /// The payload parsed as JSON, or null for binary payloads.
+    pub payload: Value,
+    pub raw: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+pub struct Broker {
+    addr: SocketAddr,
+    published: mpsc::UnboundedReceiver<Published>,
+    retained: Arc<Mutex<HashMap<String, Published>>>,
+    commands: broadcast::Sender<(String, Vec<u8>)>,
+    cut: watch::Sender<u64>,
+    offline: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct Shared {
+    published: mpsc::UnboundedSender<Published>,
+    retained: Arc<Mutex<HashMap<String, Published>>>,
+    commands: broadcast::Sender<(String, Vec<u8>)>,
+    cut: watch::Receiver<u64>,
+}
+
+impl Broker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (published_tx, published) = mpsc::unbounded_channel();
+        let retained = Arc::new(Mutex::new(HashMap::new()));
+        let (commands, _) = broadcast::channel(16);
+        let (cut, cut_rx) = watch::channel(0);
+        let offline = Arc::new(AtomicBool::new(false));
+        let refusing = offline.clone();
+        let shared = Shared {
+            published: published_tx,
+            retained: retained.clone(),
+            commands: commands.clone(),
+            cut: cut_rx,
+        };
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if refusing.load(Ordering::SeqCst) {
+                    continue;
+                }
+                tokio::spawn(serve(stream, shared.clone()));
+            }
+        });
+        Self {
+            addr,
+            published,
+            retained,
+            commands,
+            cut,
+            offline,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("mqtt://{}", self.addr)
+    }
+
+    /// Delivers `payload` to every client subscribed to `topic`.
+    pub fn publish(&self, topic: &str, payload: &Value) {
+        self.publish_raw(topic, serde_json::to_vec(payload).unwrap());
+    }
+
+    pub fn publish_raw(&self, topic: &str, payload: Vec<u8>) {
+        let _ = self.commands.send((topic.to_string(), payload));
+    }
+
+    /// Waits for the next message on `topic`, skipping everything else.
+    pub async fn expect(&mut self, topic: &str) -> Published {
+        self.expect_where(topic, |_| true).await
+    }
+
+    pub async fn expect_where(&mut self, topic: &str, matches: impl Fn(&Value) -> bool) -> Published {
+        self.expect_message(topic, |message| matches(&message.payload)).await
+    }
+
+    pub async fn expect_message(
+        &mut self,
+        topic: &str,
+        matches: impl Fn(&Published) -> bool,
+    ) -> Published {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        loop {
+            match timeout_at(deadline, self.published.recv()).await {
+                Ok(Some(message)) if message.topic == topic && matches(&message) => return message,
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("broker stopped while waiting for {}", topic),
+                Err(_) => panic!("no matching message on {} within {:?}", topic, EXPECT_TIMEOUT),
+            }
+        }
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<Published> {
+        self.retained.lock().unwrap().get(topic).cloned()
+    }
+
+    /// Drops every client connection without a DISCONNECT, so wills fire.
+    pub fn cut_link(&self) {
+        self.cut.send_modify(|generation| *generation += 1);
+    }
+
+    /// Cuts every link and refuses connections until `restore`.
+    pub fn go_offline(&self) {
+        self.offline.store(true, Ordering::SeqCst);
+        self.cut_link();
+    }
+
+    pub fn restore(&self) {
+        self.offline.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, shared: Shared) {
+    let mut cut = shared.cut.clone();
+    cut.mark_unchanged();
+    let mut commands = shared.commands.subscribe();
+    let mut buffer = BytesMut::new();
+    let mut will: Option<LastWill> = None;
+    let mut subscriptions: Vec<String> = Vec::new();
+    let mut next_pkid = 0u16;
+
+    loop {
+        let packet = loop {
+            match Packet::read(&mut buffer, MAX_PACKET) {
+                Ok(packet) => break Some(packet),
+                Err(rumqttc::Error::InsufficientBytes(_)) => {}
+                Err(_) => break None,
+            }
+            tokio::select! {
+                read = stream.read_buf(&mut buffer) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        break None;
+                    }
+                }
+                Ok((topic, payload)) = commands.recv() => {
+                    if subscriptions.iter().any(|filter| filter_matches(filter, &topic)) {
+                        next_pkid = next_pkid % u16::MAX + 1;
+                        let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+                        publish.pkid = next_pkid;
+                        write(&mut stream, Packet::Publish(publish)).await;
+                    }
+                }
+                _ = cut.changed() => break None,
+            }
+        };
+
+        let reply = match packet {
+            Some(Packet::Connect(connect)) => {
+                will = connect.last_will;
+                Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false))
+            }
+            Some(Packet::Subscribe(subscribe)) => {
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|filter| SubscribeReasonCode::Success(filter.qos))
+                    .collect();
+                subscriptions.extend(subscribe.filters.into_iter().map(|filter| filter.path));
+                Packet::SubAck(SubAck::new(subscribe.pkid, codes))
+            }
+            Some(Packet::Publish(publish)) => {
+                let pkid = publish.pkid;
+                let qos = publish.qos;
+                record(
+                    &shared,
+                    Published {
+                        topic: publish.topic,
+                        payload: serde_json::from_slice(&publish.payload).unwrap_or(Value::Null),
+                        raw: publish.payload.to_vec(),
+                        qos,
+                        retain: publish.retain,
+                    },
+                );
+                if qos == QoS::AtMostOnce {
+                    continue;
+                }
+                Packet::PubAck(PubAck::new(pkid))
+            }
+            Some(Packet::PingReq) => Packet::PingResp,
+            Some(Packet::Disconnect) => return,
+            Some(_) => continue,
+            None => {
+                if let Some(will) = will {
+                    record(
+                        &shared,
+                        Published {
+                            topic: will.topic,
+                            payload: serde_json::from_slice(&will.message).unwrap_or(Value::Null),
+                            raw: will.message.to_vec(),
+                            qos: will.qos,
+                            retain: will.retain,
+                        },
+                    );
+                }
+                return;
+            }
+        };
+        write(&mut stream, reply).await;
+    }
+}
+
+/// Topic filter matching with `+` and `#` wildcards.
+fn filter_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn record(shared: &Shared, message: Published) {
+    if message.retain {
+        shared
+            .retained
+            .lock()
+            .unwrap()
+            .insert(message.topic.clone(), message.clone());
+    }
+    let _ = shared.published.send(message);
+}
+
+async fn write(stream: &mut TcpStream, packet: Packet) {
+    let mut out = BytesMut::new();
+    packet.write(&mut out, MAX_PACKET).unwrap();
+    let _ = stream.write_all(&out).await;
+}
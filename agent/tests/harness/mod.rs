@@ -0,0 +1,463 @@
+//! End-to-end harness: the real agent wired to an in-process Socket.IO
+//! backend and a simulated PLC, so tests speak the same protocol as the
+//! production backend.
+
+#![allow(dead_code)]
+
+pub mod broker;
+
+use agent::agent::Agent;
+use agent::config::{AgentConfig, ChEvent, UplinkTransport};
+use agent::envelope::CommandVerifier;
+use agent::identity::AgentAuth;
+use agent::logging::{self, LogHandle, LogOptions};
+use agent::mqtt::MqttLink;
+use agent::opcua_server::OpcUaServer;
+use agent::protocol::{Negotiated, PROTOCOL_VERSION};
+use agent::state::SharedState;
+use agent::uplink::{Link, Uplink};
+use agent::wire::FIRST_VERSIONED;
+use agent::{mdb_client, shutdown, tls, ws};
+use serde_json::{json, Value};
+use simulator::{Point, RegisterMap, Simulator, Table};
+use broker::Broker;
+use socketioxide::extract::{Data, Event, SocketRef};
+use socketioxide::SocketIo;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+/// How long `expect` waits before failing a test.
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const POLL_INTERVAL_MS: u64 = 100;
+
+/// Birth and death topic of the MQTT test agent.
+pub const MQTT_STATUS: &str = "synk9/e2e/status";
+
+/// An event the agent emitted.
+#[derive(Debug, Clone)]
+pub struct Emitted {
+    pub event: String,
+    pub data: Value,
+}
+
+/// In-process stand-in for the backend's Socket.IO gateway. The agent
+/// reaches it through a proxy so tests can cut the link.
+pub struct Backend {
+    addr: SocketAddr,
+    io: SocketIo,
+    events: mpsc::UnboundedReceiver<Emitted>,
+    /// Auth payload of every handshake so far.
+    handshakes: watch::Receiver<Vec<Value>>,
+    cut: watch::Sender<u64>,
+    protocol: Option<u8>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Backend {
+    /// A backend on the agent's own protocol version.
+    pub async fn start() -> Self {
+        Self::speaking(Some(PROTOCOL_VERSION)).await
+    }
+
+    /// A backend that predates the handshake and never says hello.
+    pub async fn legacy() -> Self {
+        Self::speaking(None).await
+    }
+
+    /// A backend that answers the handshake with `protocol`, or never
+    /// answers it when `None`.
+    pub async fn speaking(protocol: Option<u8>) -> Self {
+        let (layer, io) = SocketIo::new_layer();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (handshakes_tx, handshakes) = watch::channel(Vec::new());
+        let handshakes_tx = Arc::new(handshakes_tx);
+
+        io.ns("/", async move |socket: SocketRef, Data(auth): Data<Value>| {
+            handshakes_tx.send_modify(|all| all.push(auth));
+            if let Some(protocol) = protocol {
+                let hello = json!({ "protocol": protocol, "server_version": "e2e" });
+                socket.emit("server_hello", &hello).unwrap();
+            }
+            socket.on_fallback(async move |Event(event): Event, Data(data): Data<Value>| {
+                let _ = events_tx.send(Emitted { event, data });
+            });
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let app = axum::Router::new().layer(layer);
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        let (cut, cut_rx) = watch::channel(0);
+        let proxy = tokio::spawn(run_proxy(proxy, upstream, cut_rx));
+        Self {
+            addr,
+            io,
+            events,
+            handshakes,
+            cut,
+            protocol,
+            tasks: vec![server, proxy],
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Sends a command the way the backend does, versioned once the
+    /// protocol has versioned commands.
+    pub async fn send(&self, event: &ChEvent) {
+        let message = match self.protocol {
+            Some(protocol) if protocol >= FIRST_VERSIONED => json!({ "v": protocol, "command": event }),
+            _ => serde_json::to_value(event).unwrap(),
+        };
+        self.send_raw(message).await;
+    }
+
+    pub async fn send_raw(&self, message: Value) {
+        self.io.emit("data", &message).await.unwrap();
+    }
+
+    /// Waits for the next `event`, skipping everything else.
+    pub async fn expect(&mut self, event: &str) -> Value {
+        self.expect_where(event, |_| true).await
+    }
+
+    /// Waits for an `event` whose payload satisfies `matches`.
+    pub async fn expect_where(&mut self, event: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        loop {
+            match timeout_at(deadline, self.events.recv()).await {
+                Ok(Some(emitted)) if emitted.event == event && matches(&emitted.data) => {
+                    return emitted.data
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("backend stopped while waiting for {}", event),
+                Err(_) => panic!("no matching {} within {:?}", event, EXPECT_TIMEOUT),
+            }
+        }
+    }
+
+    /// Fails if `event` arrives within `period`.
+    pub async fn assert_quiet(&mut self, event: &str, period: Duration) {
+        let deadline = Instant::now() + period;
+        while let Ok(Some(emitted)) = timeout_at(deadline, self.events.recv()).await {
+            assert_ne!(emitted.event, event, "unexpected {}: {}", event, emitted.data);
+        }
+    }
+
+    /// Discards whatever the agent emits during `period`.
+    pub async fn drain(&mut self, period: Duration) {
+        let deadline = Instant::now() + period;
+        while let Ok(Some(_)) = timeout_at(deadline, self.events.recv()).await {}
+    }
+
+    /// Waits until the agent has completed `count` handshakes and returns
+    /// the auth payload of the last one.
+    pub async fn wait_for_handshake(&mut self, count: usize) -> Value {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        let all = timeout_at(deadline, self.handshakes.wait_for(|all| all.len() >= count))
+            .await
+            .unwrap_or_else(|_| panic!("fewer than {} handshakes within {:?}", count, EXPECT_TIMEOUT))
+            .unwrap();
+        all[count - 1].clone()
+    }
+
+    /// Drops every open connection between agent and backend, as a
+    /// network outage would. New connections go through.
+    pub fn cut_link(&self) {
+        self.cut.send_modify(|generation| *generation += 1);
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn run_proxy(listener: TcpListener, upstream: SocketAddr, cut: watch::Receiver<u64>) {
+    while let Ok((mut inbound, _)) = listener.accept().await {
+        let mut cut = cut.clone();
+        cut.mark_unchanged();
+        tokio::spawn(async move {
+            let Ok(mut outbound) = TcpStream::connect(upstream).await else {
+                return;
+            };
+            tokio::select! {
+                _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                _ = cut.changed() => {}
+            }
+        });
+    }
+}
+
+/// A PLC with two analog inputs at 512 and 513, setpoints at 20..30 and the
+/// stop register at 0.
+pub async fn plc() -> Simulator {
+    let map = RegisterMap::local()
+        .point(Point::new(Table::HoldingRegisters, 0, 0))
+        .point(Point::new(Table::HoldingRegisters, 512, 321))
+        .point(Point::new(Table::HoldingRegisters, 513, 654))
+        .point(Point {
+            count: 10,
+            ..Point::new(Table::HoldingRegisters, 20, 0)
+        });
+    Simulator::start(map).await.unwrap()
+}
+
+/// A PLC address that accepts connections and never answers a request.
+pub async fn silent_plc() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut open = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            open.push(stream);
+        }
+    });
+    address
+}
+
+/// The running agent. Dropping it aborts the event loop; `shutdown` runs
+/// the orderly shutdown instead.
+pub struct TestAgent {
+    pub state: Arc<Mutex<SharedState>>,
+    /// Set when the configuration enables the OPC UA server.
+    pub opcua: Option<OpcUaServer>,
+    /// Where the agent reads and saves its configuration file.
+    pub config_path: PathBuf,
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+/// Configuration for an agent with one device served by `plc`.
+pub fn config(backend: &Backend, plc: &Simulator) -> AgentConfig {
+    config_for(&backend.url(), plc)
+}
+
+fn config_for(url: &str, plc: &Simulator) -> AgentConfig {
+    AgentConfig::parse(&format!(
+        r#"
+        [uplink]
+        url = "{url}"
+        fingerprint = "e2e"
+
+        [[devices]]
+        name = "plc"
+        address = "{plc}"
+        timeout_ms = 300
+        retries = 1
+
+        [poll]
+        interval_ms = {interval}
+
+        [metrics]
+        enabled = false
+        "#,
+        plc = plc.local_addr(),
+        interval = POLL_INTERVAL_MS,
+    ))
+    .unwrap()
+}
+
+/// The same agent publishing to `broker` as client `e2e`.
+pub fn mqtt_config(broker: &Broker, plc: &Simulator) -> AgentConfig {
+    let mut config = config_for("", plc);
+    config.uplink.transport = UplinkTransport::Mqtt;
+    config.uplink.mqtt.url = broker.url();
+    config.uplink.mqtt.client_id = Some("e2e".to_string());
+    config
+}
+
+impl TestAgent {
+    /// Connects an agent to `backend` with one device served by `plc`.
+    pub async fn start(backend: &mut Backend, plc: &Simulator) -> Self {
+        let config = config(backend, plc);
+        Self::start_with(backend, config).await
+    }
+
+    pub async fn start_with(backend: &mut Backend, config: AgentConfig) -> Self {
+        let handshakes = backend.handshakes.borrow().len();
+        config.validate().unwrap();
+
+        let (tx, rx) = mpsc::channel(config.buffers.event_channel);
+        let verifier = Arc::new(CommandVerifier::from_key_file(None, "e2e").unwrap());
+        let auth = AgentAuth::Legacy("e2e".to_string());
+        let protocol = Negotiated::default();
+        let route = config.uplink.tls.route(&tls::http_url(&config.uplink.url)).await.unwrap();
+        let socket = ws::setup_socket_io(
+            &route,
+            tx.clone(),
+            auth,
+            &config.uplink.tls,
+            verifier,
+            protocol.clone(),
+        )
+        .await
+        .unwrap();
+
+        let link = Link::SocketIo(socket);
+        let state = SharedState::new(config.default_device());
+        let agent = Self::spawn(config, link, protocol, rx, state).await;
+        backend.wait_for_handshake(handshakes + 1).await;
+        agent
+    }
+
+    /// Connects an agent to `broker` over MQTT, with one device served by
+    /// `plc`, and waits for its birth message.
+    pub async fn start_mqtt(broker: &mut Broker, plc: &Simulator) -> Self {
+        Self::start_mqtt_with(broker, mqtt_config(broker, plc)).await
+    }
+
+    pub async fn start_mqtt_with(broker: &mut Broker, config: AgentConfig) -> Self {
+        config.validate().unwrap();
+
+        let (tx, rx) = mpsc::channel(config.buffers.event_channel);
+        let verifier = Arc::new(CommandVerifier::from_key_file(None, "e2e").unwrap());
+        let state = SharedState::new(config.default_device());
+        let link = MqttLink::connect(&config, tx.clone(), verifier, state.clone())
+            .await
+            .unwrap();
+
+        let protocol = Negotiated::fixed(PROTOCOL_VERSION);
+        let link = Link::Mqtt(link);
+        let sparkplug = config.uplink.mqtt.sparkplug.enabled;
+        let agent = Self::spawn(config, link, protocol, rx, state).await;
+        // Sparkplug agents announce themselves with an NBIRTH instead, which
+        // the caller waits for.
+        if !sparkplug {
+            broker.expect_where(MQTT_STATUS, |birth| birth["status"] == "online").await;
+        }
+        agent
+    }
+
+    async fn spawn(
+        config: AgentConfig,
+        link: Link,
+        protocol: Negotiated,
+        mut rx: mpsc::Receiver<ChEvent>,
+        state: Arc<Mutex<SharedState>>,
+    ) -> Self {
+        let mut devices = HashMap::new();
+        for device in &config.devices {
+            devices.insert(device.name.clone(), mdb_client::connect(device).await.unwrap());
+        }
+
+        let uplink = Uplink::spawn(link, state.clone(), protocol, config.buffers.uplink_channel);
+        let (writes_tx, writes_rx) = mpsc::channel(config.buffers.event_channel);
+        let opcua = if config.opcua.enabled {
+            let server = OpcUaServer::start(
+                &config.opcua,
+                state.clone(),
+                uplink.samples(),
+                writes_tx,
+            )
+            .await
+            .unwrap();
+            Some(server)
+        } else {
+            None
+        };
+        let mut agent = Agent::new(devices, ChEvent::Wait, uplink, state.clone(), log_handle(), config)
+            .with_local_writes(writes_rx);
+
+        let (stop, stopped) = oneshot::channel();
+        let config_path = scratch_path("agent.toml");
+        let path = config_path.clone();
+        let task = tokio::spawn(async move {
+            let stopped = async {
+                let _ = stopped.await;
+                "test finished"
+            };
+            let reason = agent.run(&mut rx, &path, stopped).await;
+            shutdown::run(&mut agent, &mut rx, reason).await;
+        });
+
+        Self {
+            state,
+            opcua,
+            config_path,
+            stop: Some(stop),
+            task,
+        }
+    }
+
+    /// Runs the same shutdown as SIGTERM and waits for it to finish.
+    pub async fn shutdown(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        (&mut self.task).await.unwrap();
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(server) = &self.opcua {
+            server.stop();
+        }
+    }
+}
+
+/// An AddSensor command for one holding register on the default device.
+pub fn add_sensor(id: &str, register: u16) -> ChEvent {
+    ChEvent::AddSensor {
+        id: id.to_string(),
+        label: format!("{} label", id),
+        start_register: register,
+        register: register.to_string(),
+        end_register: 1,
+        s_type: "sensor".to_string(),
+        r_type: "REG".to_string(),
+        device: None,
+    }
+}
+
+/// Polls `condition` until it holds.
+pub async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + EXPECT_TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "{} did not happen within {:?}", what, EXPECT_TIMEOUT);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// The logger is global, so it is installed once per test binary. Set
+/// `RUST_LOG` to see the agent's output.
+fn log_handle() -> LogHandle {
+    static HANDLE: OnceLock<LogHandle> = OnceLock::new();
+    HANDLE
+        .get_or_init(|| {
+            let options = LogOptions {
+                filter: std::env::var("RUST_LOG").unwrap_or_else(|_| "off".to_string()),
+                ..LogOptions::default()
+            };
+            logging::init(&options).unwrap().0
+        })
+        .clone()
+}
+
+/// A path under the temp dir that no other test uses.
+pub fn scratch_path(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("agent-e2e-{}-{}-{}", std::process::id(), n, name))
+}
@@ -0,0 +1,106 @@
+mod harness;
+
+use agent::protocol::PROTOCOL_VERSION;
+use agent::wire::Command;
+use harness::broker::Broker;
+use harness::{add_sensor, plc, TestAgent, MQTT_STATUS};
+use rumqttc::QoS;
+use serde_json::json;
+use std::time::Duration;
+
+const COMMANDS: &str = "synk9/e2e/commands";
+
+#[tokio::test]
+async fn announces_itself_with_a_retained_birth_message() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    let birth = broker.retained(MQTT_STATUS).expect("birth is retained");
+    assert_eq!(birth.qos, QoS::AtLeastOnce);
+    assert_eq!(birth.payload["status"], "online");
+    assert_eq!(birth.payload["agent"], "e2e");
+    assert_eq!(birth.payload["protocol"]["version"], PROTOCOL_VERSION);
+    assert_eq!(birth.payload["capabilities"]["transports"], json!(["socket.io", "mqtt"]));
+}
+
+#[tokio::test]
+async fn streams_samples_per_sensor_topic() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    let command = serde_json::to_value(Command::new(add_sensor("level", 512))).unwrap();
+    broker.publish(COMMANDS, &command);
+    let sample = broker.expect("synk9/e2e/telemetry/level").await;
+
+    assert_eq!(sample.qos, QoS::AtLeastOnce);
+    assert!(!sample.retain);
+    assert_eq!(sample.payload["sensor_id"], "level");
+    assert_eq!(sample.payload["value"], 321);
+    assert_eq!(sample.payload["quality"], "good");
+}
+
+#[tokio::test]
+async fn rejects_malformed_commands_on_the_events_topic() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    broker.publish(COMMANDS, &json!({ "v": PROTOCOL_VERSION, "command": { "Nope": {} } }));
+    let rejected = broker.expect("synk9/e2e/events/command_rejected").await;
+    assert_eq!(rejected.payload["error"]["code"], "deserialization");
+}
+
+#[tokio::test]
+async fn replaces_the_birth_with_a_death_message_on_shutdown() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    agent.shutdown().await;
+    let offline = broker.expect("synk9/e2e/events/agent_offline").await;
+    assert_eq!(offline.payload["reason"], "test finished");
+    let death = broker.expect(MQTT_STATUS).await;
+    assert!(death.retain);
+    assert_eq!(death.payload["status"], "offline");
+    assert_eq!(broker.retained(MQTT_STATUS).unwrap().payload["status"], "offline");
+}
+
+#[tokio::test]
+async fn broker_publishes_the_will_when_the_link_drops() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    broker.cut_link();
+    let will = broker.expect_where(MQTT_STATUS, |status| status["status"] == "offline").await;
+    assert!(will.retain);
+    assert_eq!(will.payload["agent"], "e2e");
+    broker.expect_where(MQTT_STATUS, |status| status["status"] == "online").await;
+}
+
+#[tokio::test]
+async fn recovers_once_an_outage_has_filled_the_client_queue() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = TestAgent::start_mqtt(&mut broker, &plc).await;
+
+    for i in 0..10 {
+        let command = Command::new(add_sensor(&format!("s{}", i), 500 + i));
+        broker.publish(COMMANDS, &serde_json::to_value(command).unwrap());
+    }
+    broker.expect("synk9/e2e/telemetry/s0").await;
+
+    broker.go_offline();
+    broker.expect_where(MQTT_STATUS, |status| status["status"] == "offline").await;
+    // Ten sensors polled every 100 ms queue far more than the client holds.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    broker.restore();
+    broker.expect_where(MQTT_STATUS, |status| status["status"] == "online").await;
+
+    // Commands are subscribed again and samples flow.
+    let command = serde_json::to_value(Command::new(add_sensor("after", 600))).unwrap();
+    broker.publish(COMMANDS, &command);
+    broker.expect("synk9/e2e/telemetry/after").await;
+}
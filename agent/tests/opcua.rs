@@ -0,0 +1,316 @@
+mod harness;
+
+use agent::config::{AclDefault, AgentConfig, ChEvent};
+use agent::opcua_server::OpcUaUser;
+use agent::wire::Command;
+use harness::broker::Broker;
+use harness::{add_sensor, mqtt_config, plc, scratch_path, TestAgent, EXPECT_TIMEOUT};
+use opcua::client::{Client, ClientBuilder, IdentityToken, Session};
+use opcua::crypto::CertificateStore;
+use opcua::types::{
+    AttributeId, BrowseDescription, BrowseDirection, BrowseResultMask, DataValue,
+    MessageSecurityMode, NodeId, ReferenceTypeId, StatusCode, TimestampsToReturn,
+    UserTokenPolicy, Variant, WriteValue,
+};
+use simulator::{Fault, FaultRule, Simulator, Table};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::time::{sleep, Instant};
+
+const COMMANDS: &str = "synk9/e2e/commands";
+const NAMESPACE: &str = "urn:synk9:agent";
+const USER: &str = "operator";
+const PASSWORD: &str = "secret";
+
+async fn start(broker: &mut Broker, plc: &Simulator, configure: impl FnOnce(&mut AgentConfig)) -> TestAgent {
+    let mut config = mqtt_config(broker, plc);
+    config.opcua.enabled = true;
+    config.opcua.listen = "127.0.0.1:0".to_string();
+    config.opcua.allow_unsecured = true;
+    config.opcua.pki_dir = scratch_path("pki").display().to_string();
+    configure(&mut config);
+    TestAgent::start_mqtt_with(broker, config).await
+}
+
+/// Starts an agent with one OPC UA user and returns its PKI directory.
+async fn start_with_user(
+    broker: &mut Broker,
+    plc: &Simulator,
+    configure: impl FnOnce(&mut AgentConfig),
+) -> (TestAgent, PathBuf) {
+    let pki = scratch_path("pki");
+    let agent = start(broker, plc, |config| {
+        config.opcua.pki_dir = pki.display().to_string();
+        config.opcua.users = vec![OpcUaUser {
+            name: USER.to_string(),
+            password: PASSWORD.to_string(),
+        }];
+        configure(config);
+    })
+    .await;
+    (agent, pki)
+}
+
+fn client(pki: &Path) -> Client {
+    ClientBuilder::new()
+        .application_name("synk9 e2e")
+        .application_uri("urn:synk9:e2e")
+        .pki_dir(pki)
+        .create_sample_keypair(true)
+        .trust_server_certs(true)
+        .session_retry_limit(3)
+        .client()
+        .unwrap()
+}
+
+fn url(agent: &TestAgent) -> String {
+    format!("opc.tcp://{}/", agent.opcua.as_ref().unwrap().local_addr())
+}
+
+/// Opens an anonymous session over the unsecured endpoint.
+async fn connect(agent: &TestAgent) -> (Arc<Session>, u16) {
+    let url = url(agent);
+    let endpoint = (url.as_str(), "None", MessageSecurityMode::None, UserTokenPolicy::anonymous());
+    open(client(&scratch_path("client-pki")), endpoint, IdentityToken::Anonymous).await
+}
+
+/// Signs in as the configured user over the encrypted endpoint, after
+/// placing the client's certificate in the server's trusted folder.
+async fn connect_as_user(agent: &TestAgent, server_pki: &Path) -> (Arc<Session>, u16) {
+    let client_pki = scratch_path("client-pki");
+    let client = client(&client_pki);
+    let cert = CertificateStore::read_cert(&client_pki.join("own/cert.der")).unwrap();
+    let trusted = server_pki.join("trusted").join(CertificateStore::cert_file_name(&cert));
+    std::fs::copy(client_pki.join("own/cert.der"), trusted).unwrap();
+
+    let url = url(agent);
+    let endpoint = (
+        url.as_str(),
+        "Basic256Sha256",
+        MessageSecurityMode::SignAndEncrypt,
+        UserTokenPolicy::anonymous(),
+    );
+    open(client, endpoint, IdentityToken::new_user_name(USER, PASSWORD)).await
+}
+
+async fn open(
+    mut client: Client,
+    endpoint: (&str, &str, MessageSecurityMode, UserTokenPolicy),
+    identity: IdentityToken,
+) -> (Arc<Session>, u16) {
+    let (session, event_loop) = client.connect_to_matching_endpoint(endpoint, identity).await.unwrap();
+    event_loop.spawn();
+    session.wait_for_connection().await;
+    let ns = session.get_namespace_index(NAMESPACE).await.unwrap();
+    (session, ns)
+}
+
+fn send(broker: &Broker, event: ChEvent) {
+    broker.publish(COMMANDS, &serde_json::to_value(Command::new(event)).unwrap());
+}
+
+async fn read(session: &Session, node: &NodeId) -> DataValue {
+    let values = session
+        .read(&[node.into()], TimestampsToReturn::Both, 0.0)
+        .await
+        .unwrap();
+    values.into_iter().next().unwrap()
+}
+
+/// Reads `node` until `matches` holds.
+async fn read_until(session: &Session, node: &NodeId, matches: impl Fn(&DataValue) -> bool) -> DataValue {
+    let deadline = Instant::now() + EXPECT_TIMEOUT;
+    loop {
+        let value = read(session, node).await;
+        if matches(&value) {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "{} not as expected, last read {:?}", node, value);
+        sleep(EXPECT_TIMEOUT / 100).await;
+    }
+}
+
+async fn browse(session: &Session, node: &NodeId) -> Vec<String> {
+    let description = BrowseDescription {
+        node_id: node.clone(),
+        browse_direction: BrowseDirection::Forward,
+        reference_type_id: ReferenceTypeId::Organizes.into(),
+        include_subtypes: true,
+        node_class_mask: 0,
+        result_mask: BrowseResultMask::All as u32,
+    };
+    let results = session.browse(&[description], 0, None).await.unwrap();
+    results[0]
+        .references
+        .iter()
+        .flatten()
+        .map(|reference| reference.browse_name.name.to_string())
+        .collect()
+}
+
+async fn write(session: &Session, node: &NodeId, value: Variant) -> StatusCode {
+    let write = WriteValue {
+        node_id: node.clone(),
+        attribute_id: AttributeId::Value as u32,
+        index_range: Default::default(),
+        value: DataValue::new_now(value),
+    };
+    session.write(&[write]).await.unwrap()[0]
+}
+
+#[tokio::test]
+async fn serves_sensor_values_by_device() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let agent = start(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect(&agent).await;
+
+    send(&broker, add_sensor("level", 512));
+    let level = NodeId::new(ns, "plc/level");
+    let value = read_until(&session, &level, |v| v.status.is_some_and(|s| s.is_good())).await;
+    assert_eq!(value.value, Some(Variant::UInt16(321)));
+    assert!(value.source_timestamp.is_some());
+
+    assert_eq!(browse(&session, &NodeId::new(ns, "Devices")).await, ["plc"]);
+    assert_eq!(browse(&session, &NodeId::new(ns, "plc")).await, ["level"]);
+}
+
+#[tokio::test]
+async fn reports_bad_quality_when_the_plc_is_unreachable() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let agent = start(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect(&agent).await;
+
+    send(&broker, add_sensor("level", 512));
+    let level = NodeId::new(ns, "plc/level");
+    read_until(&session, &level, |v| v.status.is_some_and(|s| s.is_good())).await;
+
+    plc.inject(FaultRule::new(Fault::Disconnect));
+    let value = read_until(&session, &level, |v| v.status.is_some_and(|s| !s.is_good())).await;
+    assert_eq!(value.status, Some(StatusCode::BadCommunicationError));
+}
+
+#[tokio::test]
+async fn writes_are_answered_once_the_plc_has_them() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, pki) = start_with_user(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect_as_user(&agent, &pki).await;
+
+    send(&broker, add_sensor("setpoint", 21));
+    let setpoint = NodeId::new(ns, "plc/setpoint");
+    read_until(&session, &setpoint, |v| v.value.is_some()).await;
+
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(1234)).await, StatusCode::Good);
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(1234));
+    assert_eq!(write(&session, &setpoint, Variant::Int32(70_000)).await, StatusCode::BadTypeMismatch);
+
+    plc.inject(FaultRule::new(Fault::Exception { code: 2 }).function(6).times(2));
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(42)).await, StatusCode::BadDeviceFailure);
+}
+
+#[tokio::test]
+async fn anonymous_sessions_cannot_write() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, _pki) = start_with_user(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect(&agent).await;
+
+    send(&broker, add_sensor("setpoint", 21));
+    let setpoint = NodeId::new(ns, "plc/setpoint");
+    read_until(&session, &setpoint, |v| v.value.is_some()).await;
+
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(1234)).await, StatusCode::BadUserAccessDenied);
+    sleep(EXPECT_TIMEOUT / 20).await;
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(0));
+}
+
+#[tokio::test]
+async fn nodes_are_read_only_without_users() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let agent = start(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect(&agent).await;
+
+    send(&broker, add_sensor("setpoint", 21));
+    let setpoint = NodeId::new(ns, "plc/setpoint");
+    read_until(&session, &setpoint, |v| v.value.is_some()).await;
+
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(1234)).await, StatusCode::BadUserAccessDenied);
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(0));
+}
+
+#[tokio::test]
+async fn writes_follow_the_running_acl() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, pki) = start_with_user(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect_as_user(&agent, &pki).await;
+
+    send(&broker, add_sensor("setpoint", 21));
+    let setpoint = NodeId::new(ns, "plc/setpoint");
+    read_until(&session, &setpoint, |v| v.value.is_some()).await;
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(1)).await, StatusCode::Good);
+
+    let mut config = mqtt_config(&broker, &plc);
+    config.save(&agent.config_path).unwrap();
+    config.acl.default = AclDefault::Deny;
+    send(
+        &broker,
+        ChEvent::ApplyConfig {
+            version: 1,
+            config: Box::new(config),
+        },
+    );
+    broker.expect("synk9/e2e/events/config_applied").await;
+
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(2)).await, StatusCode::BadUserAccessDenied);
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(1));
+}
+
+#[tokio::test]
+async fn removed_sensors_leave_the_address_space() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let agent = start(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect(&agent).await;
+
+    send(&broker, add_sensor("level", 512));
+    let level = NodeId::new(ns, "plc/level");
+    read_until(&session, &level, |v| v.value.is_some()).await;
+
+    send(&broker, ChEvent::RemoveSensor { id: "level".to_string() });
+    read_until(&session, &level, |v| v.status == Some(StatusCode::BadNodeIdUnknown)).await;
+    assert!(browse(&session, &NodeId::new(ns, "Devices")).await.is_empty());
+}
+
+#[tokio::test]
+async fn writes_reach_the_default_device_of_an_applied_config() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, pki) = start_with_user(&mut broker, &plc, |_| {}).await;
+    let (session, ns) = connect_as_user(&agent, &pki).await;
+
+    send(&broker, add_sensor("setpoint", 21));
+    let old = NodeId::new(ns, "plc/setpoint");
+    read_until(&session, &old, |v| v.value.is_some()).await;
+
+    let mut config = mqtt_config(&broker, &plc);
+    config.save(&agent.config_path).unwrap();
+    config.devices[0].name = "line".to_string();
+    send(
+        &broker,
+        ChEvent::ApplyConfig {
+            version: 1,
+            config: Box::new(config),
+        },
+    );
+    broker.expect("synk9/e2e/events/config_applied").await;
+
+    let setpoint = NodeId::new(ns, "line/setpoint");
+    read_until(&session, &setpoint, |v| v.value.is_some()).await;
+    assert_eq!(read(&session, &old).await.status, Some(StatusCode::BadNodeIdUnknown));
+    assert_eq!(write(&session, &setpoint, Variant::UInt16(1234)).await, StatusCode::Good);
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(1234));
+}
@@ -0,0 +1,95 @@
+mod harness;
+
+use agent::config::{AgentConfig, ChEvent, DeviceConfig};
+use harness::{add_sensor, config, plc, silent_plc, Backend, TestAgent};
+
+fn apply(version: u64, config: AgentConfig) -> ChEvent {
+    ChEvent::ApplyConfig {
+        version,
+        config: Box::new(config),
+    }
+}
+
+#[tokio::test]
+async fn saves_the_pushed_configuration_with_the_files_uplink() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let running = config(&backend, &plc);
+    let agent = TestAgent::start_with(&mut backend, running.clone()).await;
+
+    let mut on_disk = running.clone();
+    on_disk.uplink.fingerprint = Some("file-token".to_string());
+    on_disk.save(&agent.config_path).unwrap();
+
+    let mut pushed = running.clone();
+    pushed.uplink.url = "http://backend.invalid".to_string();
+    pushed.uplink.fingerprint = Some("pushed-token".to_string());
+    pushed.poll.interval_ms *= 2;
+    backend.send(&apply(1, pushed)).await;
+
+    let applied = backend.expect("config_applied").await;
+    assert_eq!(applied["version"], 1);
+    // The file's uplink differs from the one the agent was started with.
+    assert_eq!(applied["summary"]["restart_required"], serde_json::json!(["uplink"]));
+
+    let saved = AgentConfig::read_file(&agent.config_path).unwrap();
+    assert_eq!(saved.version, 1);
+    assert_eq!(saved.poll.interval_ms, running.poll.interval_ms * 2);
+    assert_eq!(saved.uplink, on_disk.uplink);
+}
+
+#[tokio::test]
+async fn rejects_a_configuration_whose_device_does_not_answer() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let running = config(&backend, &plc);
+    let agent = TestAgent::start_with(&mut backend, running.clone()).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    backend.expect("monitoring_streamline").await;
+
+    let mut pushed = running.clone();
+    let mut silent = pushed.devices[0].clone();
+    silent.name = "silent".to_string();
+    silent.address = silent_plc().await;
+    pushed.devices.push(DeviceConfig { retries: 0, ..silent });
+    backend.send(&apply(1, pushed)).await;
+
+    let rejected = backend.expect("config_rejected").await;
+    assert_eq!(rejected["version"], 1);
+    assert_eq!(rejected["running_version"], running.version);
+    assert!(!agent.config_path.exists());
+
+    backend.drain(std::time::Duration::from_millis(50)).await;
+    let sample = backend.expect("monitoring_streamline").await;
+    assert_eq!(sample["quality"], "good");
+}
+
+#[tokio::test]
+async fn answers_commands_while_new_devices_are_connected() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let running = config(&backend, &plc);
+    let agent = TestAgent::start_with(&mut backend, running.clone()).await;
+    running.save(&agent.config_path).unwrap();
+
+    let mut pushed = running.clone();
+    let mut silent = pushed.devices[0].clone();
+    silent.name = "silent".to_string();
+    silent.address = silent_plc().await;
+    pushed.devices.push(DeviceConfig { retries: 0, ..silent });
+    backend.send(&apply(1, pushed)).await;
+    let mut next = running.clone();
+    next.poll.interval_ms *= 2;
+    backend.send(&apply(2, next)).await;
+    backend.send(&ChEvent::HealthCheck).await;
+
+    // The health check is answered while the silent device is probed, and
+    // the second push waits for the first to be rejected.
+    backend.expect("health_check").await;
+    let rejected = backend.expect("config_rejected").await;
+    assert_eq!(rejected["version"], 1);
+    let applied = backend.expect("config_applied").await;
+    assert_eq!(applied["version"], 2);
+    assert_eq!(AgentConfig::read_file(&agent.config_path).unwrap().version, 2);
+}
@@ -0,0 +1,70 @@
+use agent::config::ChEvent;
+use agent::wire::{self, WIRE_VERSION};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+
+fn published_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("schema")
+        .join(format!("wire-v{}.json", WIRE_VERSION))
+}
+
+/// The published schema is generated from the Rust types; regenerate it with
+/// `UPDATE_SCHEMA=1 cargo test --test schema` after changing the wire format.
+#[test]
+fn published_schema_matches_the_code() {
+    let generated = serde_json::to_string_pretty(&wire::schema()).unwrap() + "\n";
+    let path = published_path();
+    if std::env::var_os("UPDATE_SCHEMA").is_some() {
+        fs::write(&path, &generated).unwrap();
+        return;
+    }
+    let published = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    assert!(
+        published == generated,
+        "{} is out of date; run UPDATE_SCHEMA=1 cargo test --test schema",
+        path.display()
+    );
+}
+
+#[test]
+fn schema_describes_every_command() {
+    let schema = wire::schema();
+    let commands = serde_json::to_string(&schema["$defs"]["ChEvent"]).unwrap();
+    for name in [
+        "Wait", "Stop", "Write", "AddSensor", "RemoveSensor", "EditSensor", "PauseAgent",
+        "HealthCheck", "SetLogLevel", "ReloadConfig", "ApplyConfig", "CleanUp",
+    ] {
+        assert!(commands.contains(&format!("\"{}\"", name)), "{} missing", name);
+    }
+    assert!(schema["events"]["monitoring_streamline"].is_object());
+}
+
+/// Commands exactly as the gateway sends them, routing fields stripped.
+#[test]
+fn parses_gateway_commands() {
+    let add = json!({
+        "v": WIRE_VERSION,
+        "command": { "AddSensor": {
+            "id": "s1", "label": "level", "start_register": 512, "register": "%MW512",
+            "end_register": 1, "s_type": "sensor", "r_type": "REG"
+        }}
+    });
+    assert!(matches!(wire::parse_command(&add), Ok(ChEvent::AddSensor { .. })));
+
+    let pause = json!({ "v": WIRE_VERSION, "command": "PauseAgent" });
+    assert!(matches!(wire::parse_command(&pause), Ok(ChEvent::PauseAgent)));
+
+    let legacy = json!("HealthCheck");
+    assert!(matches!(wire::parse_command(&legacy), Ok(ChEvent::HealthCheck)));
+}
+
+#[test]
+fn rejects_unknown_versions() {
+    for v in [json!(1), json!(WIRE_VERSION + 1), json!("3")] {
+        let message = json!({ "v": v, "command": "HealthCheck" });
+        assert!(wire::parse_command(&message).is_err(), "accepted v = {}", v);
+    }
+}
@@ -0,0 +1,283 @@
+mod harness;
+
+use agent::config::{AclDefault, AgentConfig, ChEvent};
+use agent::sparkplug::{datatype, Metric, MetricValue, Payload, BD_SEQ, REBIRTH};
+use agent::wire::Command;
+use harness::broker::{Broker, Published};
+use harness::{add_sensor, eventually, mqtt_config, plc, TestAgent, MQTT_STATUS};
+use prost::Message;
+use simulator::{Simulator, Table};
+
+const COMMANDS: &str = "synk9/e2e/commands";
+const NBIRTH: &str = "spBv1.0/plant/NBIRTH/e2e";
+const NDEATH: &str = "spBv1.0/plant/NDEATH/e2e";
+const NCMD: &str = "spBv1.0/plant/NCMD/e2e";
+const DBIRTH: &str = "spBv1.0/plant/DBIRTH/e2e/plc";
+const DDEATH: &str = "spBv1.0/plant/DDEATH/e2e/plc";
+const DDATA: &str = "spBv1.0/plant/DDATA/e2e/plc";
+const DCMD: &str = "spBv1.0/plant/DCMD/e2e/plc";
+
+fn sparkplug_config(broker: &Broker, plc: &Simulator) -> AgentConfig {
+    let mut config = mqtt_config(broker, plc);
+    config.uplink.mqtt.sparkplug.enabled = true;
+    config.uplink.mqtt.sparkplug.group_id = "plant".to_string();
+    config
+}
+
+async fn start(broker: &mut Broker, plc: &Simulator, deny_writes: bool) -> (TestAgent, Payload) {
+    let mut config = sparkplug_config(broker, plc);
+    if deny_writes {
+        config.acl.default = AclDefault::Deny;
+    }
+    start_with(broker, config).await
+}
+
+/// Starts a Sparkplug agent and returns it with its NBIRTH.
+async fn start_with(broker: &mut Broker, config: AgentConfig) -> (TestAgent, Payload) {
+    let agent = TestAgent::start_mqtt_with(broker, config).await;
+    let nbirth = decode(&broker.expect(NBIRTH).await);
+    (agent, nbirth)
+}
+
+fn decode(message: &Published) -> Payload {
+    Payload::decode(message.raw.as_slice()).unwrap()
+}
+
+fn command(metrics: Vec<Metric>) -> Vec<u8> {
+    Payload {
+        timestamp: None,
+        metrics,
+        seq: None,
+    }
+    .encode_to_vec()
+}
+
+fn write(name: Option<&str>, alias: Option<u64>, value: u32) -> Metric {
+    Metric {
+        name: name.map(str::to_string),
+        alias,
+        value: Some(MetricValue::Int(value)),
+        ..Default::default()
+    }
+}
+
+/// Registers a sensor through the JSON command topic and returns its DBIRTH.
+async fn add(broker: &mut Broker, id: &str, register: u16) -> Payload {
+    let command = serde_json::to_value(Command::new(add_sensor(id, register))).unwrap();
+    broker.publish(COMMANDS, &command);
+    let birth = broker
+        .expect_message(DBIRTH, |message| {
+            decode(message).metrics.iter().any(|m| m.name.as_deref() == Some(id))
+        })
+        .await;
+    decode(&birth)
+}
+
+#[tokio::test]
+async fn births_the_node_then_each_device_with_typed_metrics() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (_agent, nbirth) = start(&mut broker, &plc, false).await;
+
+    assert_eq!(nbirth.seq, Some(0));
+    assert!(matches!(Metric::find(&nbirth.metrics, BD_SEQ), Some(MetricValue::Long(_))));
+    assert_eq!(Metric::find(&nbirth.metrics, REBIRTH), Some(&MetricValue::Boolean(false)));
+
+    let dbirth = add(&mut broker, "level", 512).await;
+    let level = &dbirth.metrics[0];
+    assert_eq!(level.datatype, Some(datatype::UINT16));
+    assert_eq!(level.value, Some(MetricValue::Int(321)));
+    assert!(level.alias.is_some());
+
+    let data = decode(&broker.expect(DDATA).await);
+    assert_eq!(data.seq, Some(dbirth.seq.unwrap() + 1));
+    assert_eq!(data.metrics[0].alias, level.alias);
+    assert_eq!(data.metrics[0].name, None);
+    assert_eq!(data.metrics[0].value, Some(MetricValue::Int(321)));
+}
+
+#[tokio::test]
+async fn device_commands_write_through_the_agent() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, false).await;
+
+    let dbirth = add(&mut broker, "setpoint", 21).await;
+    broker.publish_raw(DCMD, command(vec![write(Some("setpoint"), None, 1234)]));
+    eventually("write by name", || plc.get(Table::HoldingRegisters, 21) == Some(1234)).await;
+
+    let alias = dbirth.metrics[0].alias;
+    broker.publish_raw(DCMD, command(vec![write(None, alias, 77)]));
+    eventually("write by alias", || plc.get(Table::HoldingRegisters, 21) == Some(77)).await;
+}
+
+#[tokio::test]
+async fn a_new_sensor_kills_and_rebirths_its_device() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, false).await;
+
+    add(&mut broker, "level", 512).await;
+    let command = serde_json::to_value(Command::new(add_sensor("setpoint", 21))).unwrap();
+    broker.publish(COMMANDS, &command);
+
+    let ddeath = decode(&broker.expect(DDEATH).await);
+    let dbirth = decode(&broker.expect(DBIRTH).await);
+    let names: Vec<_> = dbirth.metrics.iter().filter_map(|m| m.name.as_deref()).collect();
+    assert_eq!(names, ["level", "setpoint"]);
+    assert_eq!(dbirth.seq, ddeath.seq.map(|seq| (seq + 1) % 256));
+}
+
+#[tokio::test]
+async fn sensors_without_a_device_follow_the_default_device_of_a_new_config() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let running = sparkplug_config(&broker, &plc);
+    let (agent, _) = start_with(&mut broker, running.clone()).await;
+    running.save(&agent.config_path).unwrap();
+    add(&mut broker, "setpoint", 21).await;
+
+    let mut renamed = running.clone();
+    renamed.devices[0].name = "line".to_string();
+    let apply = ChEvent::ApplyConfig {
+        version: 1,
+        config: Box::new(renamed),
+    };
+    broker.publish(COMMANDS, &serde_json::to_value(Command::new(apply)).unwrap());
+    broker.expect("synk9/e2e/events/config_applied").await;
+
+    broker
+        .expect_message("spBv1.0/plant/DBIRTH/e2e/line", |message| {
+            decode(message).metrics.iter().any(|m| m.name.as_deref() == Some("setpoint"))
+        })
+        .await;
+    let dcmd = "spBv1.0/plant/DCMD/e2e/line";
+    broker.publish_raw(dcmd, command(vec![write(Some("setpoint"), None, 1234)]));
+    eventually("write to the new default device", || {
+        plc.get(Table::HoldingRegisters, 21) == Some(1234)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn device_writes_can_be_turned_off() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let mut config = sparkplug_config(&broker, &plc);
+    config.uplink.mqtt.sparkplug.device_writes = false;
+    let _agent = start_with(&mut broker, config).await;
+
+    add(&mut broker, "setpoint", 21).await;
+    broker.publish_raw(DCMD, command(vec![write(Some("setpoint"), None, 1234)]));
+    let rejected = broker.expect("synk9/e2e/events/command_rejected").await;
+    assert_eq!(rejected.payload["error"]["code"], "validation");
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(0));
+}
+
+#[tokio::test]
+async fn device_writes_respect_the_acl() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, true).await;
+
+    add(&mut broker, "setpoint", 21).await;
+    broker.publish_raw(DCMD, command(vec![write(Some("setpoint"), None, 1234)]));
+    broker.expect("synk9/e2e/events/write_denied").await;
+    assert_eq!(plc.get(Table::HoldingRegisters, 21), Some(0));
+}
+
+#[tokio::test]
+async fn rejects_writes_to_unknown_metrics() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, false).await;
+
+    broker.publish_raw(DCMD, command(vec![write(Some("nope"), None, 1)]));
+    let rejected = broker.expect("synk9/e2e/events/command_rejected").await;
+    assert_eq!(rejected.payload["error"]["code"], "validation");
+}
+
+#[tokio::test]
+async fn rebirth_restarts_the_sequence() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, false).await;
+
+    add(&mut broker, "level", 512).await;
+    broker.expect(DDATA).await;
+
+    let rebirth = Metric {
+        name: Some(REBIRTH.to_string()),
+        value: Some(MetricValue::Boolean(true)),
+        ..Default::default()
+    };
+    broker.publish_raw(NCMD, command(vec![rebirth]));
+    let nbirth = decode(&broker.expect(NBIRTH).await);
+    assert_eq!(nbirth.seq, Some(0));
+    let dbirth = decode(&broker.expect(DBIRTH).await);
+    assert_eq!(dbirth.seq, Some(1));
+    assert_eq!(dbirth.metrics[0].name.as_deref(), Some("level"));
+}
+
+#[tokio::test]
+async fn announces_death_with_the_birth_sequence() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, nbirth) = start(&mut broker, &plc, false).await;
+
+    agent.shutdown().await;
+    let ndeath = broker.expect(NDEATH).await;
+    assert!(!ndeath.retain);
+    let ndeath = decode(&ndeath);
+    assert_eq!(ndeath.seq, None);
+    assert_eq!(
+        Metric::find(&ndeath.metrics, BD_SEQ),
+        Metric::find(&nbirth.metrics, BD_SEQ)
+    );
+}
+
+#[tokio::test]
+async fn nbirth_and_ndeath_replace_the_status_messages() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (agent, _) = start(&mut broker, &plc, false).await;
+
+    agent.shutdown().await;
+    broker.expect(NDEATH).await;
+    assert!(broker.retained(MQTT_STATUS).is_none());
+}
+
+#[tokio::test]
+async fn broker_publishes_ndeath_when_the_link_drops() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let _agent = start(&mut broker, &plc, false).await;
+
+    broker.cut_link();
+    let ndeath = decode(&broker.expect(NDEATH).await);
+    assert!(Metric::find(&ndeath.metrics, BD_SEQ).is_some());
+    broker.expect(NBIRTH).await;
+}
+
+#[tokio::test]
+async fn every_session_gets_the_next_bd_seq_and_a_matching_will() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let (_agent, first) = start(&mut broker, &plc, false).await;
+
+    let Some(&MetricValue::Long(first_seq)) = Metric::find(&first.metrics, BD_SEQ) else {
+        panic!("NBIRTH without bdSeq");
+    };
+
+    broker.cut_link();
+    let will = decode(&broker.expect(NDEATH).await);
+    assert_eq!(Metric::find(&will.metrics, BD_SEQ), Some(&MetricValue::Long(first_seq)));
+
+    let second = decode(&broker.expect(NBIRTH).await);
+    let second_seq = (first_seq + 1) % 256;
+    assert_eq!(Metric::find(&second.metrics, BD_SEQ), Some(&MetricValue::Long(second_seq)));
+
+    broker.cut_link();
+    let will = decode(&broker.expect(NDEATH).await);
+    assert_eq!(Metric::find(&will.metrics, BD_SEQ), Some(&MetricValue::Long(second_seq)));
+}
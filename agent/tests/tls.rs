@@ -0,0 +1,249 @@
+mod harness;
+
+use agent::envelope::CommandVerifier;
+use agent::identity::AgentAuth;
+use agent::protocol::Negotiated;
+use agent::tls::TlsSettings;
+use agent::ws;
+use harness::broker::Broker;
+use harness::{add_sensor, config, mqtt_config, plc, scratch_path, Backend, TestAgent};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{Ssl, SslAcceptor, SslMethod};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+
+/// A self-signed certificate for 127.0.0.1, written to disk as a CA bundle.
+struct ServerCert {
+    key: PKey<Private>,
+    cert: X509,
+    ca_file: String,
+}
+
+impl ServerCert {
+    fn generate() -> Self {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "synk9 backend").unwrap();
+        let name = name.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let ca_file = scratch_path("ca.pem");
+        std::fs::write(&ca_file, cert.to_pem().unwrap()).unwrap();
+        Self {
+            key,
+            cert,
+            ca_file: ca_file.display().to_string(),
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        let digest = self.cert.digest(MessageDigest::sha256()).unwrap();
+        digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+    }
+
+    fn settings(&self, pin: String) -> TlsSettings {
+        TlsSettings {
+            ca_file: Some(self.ca_file.clone()),
+            pins: vec![pin],
+            ..Default::default()
+        }
+    }
+}
+
+/// Terminates TLS with `cert` and forwards the plaintext to `upstream`,
+/// counting the bytes clients managed to send.
+struct TlsFront {
+    addr: SocketAddr,
+    relayed: Arc<AtomicUsize>,
+}
+
+impl TlsFront {
+    async fn start(cert: &ServerCert, upstream: SocketAddr) -> Self {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&cert.key).unwrap();
+        acceptor.set_certificate(&cert.cert).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relayed = Arc::new(AtomicUsize::new(0));
+        let counter = relayed.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut tls = SslStream::new(ssl, stream).unwrap();
+                    if Pin::new(&mut tls).accept().await.is_err() {
+                        return;
+                    }
+                    let mut upstream = TcpStream::connect(upstream).await.unwrap();
+                    if let Ok((sent, _)) = copy_bidirectional(&mut tls, &mut upstream).await {
+                        counter.fetch_add(sent as usize, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        Self { addr, relayed }
+    }
+
+    fn relayed(&self) -> usize {
+        self.relayed.load(Ordering::SeqCst)
+    }
+}
+
+/// Echoes back whatever a client sends, for testing the tunnel on its own.
+async fn echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+fn backend_addr(backend: &Backend) -> SocketAddr {
+    backend.url().trim_start_matches("http://").parse().unwrap()
+}
+
+#[tokio::test]
+async fn tunnel_relays_to_a_pinned_server() {
+    let cert = ServerCert::generate();
+    let front = TlsFront::start(&cert, echo().await).await;
+    let tunnel = cert
+        .settings(cert.fingerprint())
+        .tunnel("127.0.0.1", front.addr.port())
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[tokio::test]
+async fn tunnel_sends_nothing_to_a_server_failing_the_pin() {
+    let cert = ServerCert::generate();
+    let front = TlsFront::start(&cert, echo().await).await;
+    let other = "00".repeat(32);
+    let tunnel = cert.settings(other).tunnel("127.0.0.1", front.addr.port()).await.unwrap();
+
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    stream.write_all(b"secret").await.unwrap();
+    let mut reply = Vec::new();
+    let _ = stream.read_to_end(&mut reply).await;
+    assert!(reply.is_empty());
+    assert_eq!(front.relayed(), 0);
+}
+
+#[tokio::test]
+async fn routes_pinned_urls_through_a_tunnel() {
+    let cert = ServerCert::generate();
+    let settings = cert.settings(cert.fingerprint());
+
+    let route = settings.route("https://backend.example:8443").await.unwrap();
+    assert!(route.url.starts_with("http://127.0.0.1:"));
+    assert!(!route.url.ends_with('/'));
+    assert_eq!(route.host.as_deref(), Some("backend.example:8443"));
+
+    let route = settings.route("wss://backend.example/socket").await.unwrap();
+    assert!(route.url.starts_with("ws://127.0.0.1:") && route.url.ends_with("/socket"));
+    assert_eq!(route.host.as_deref(), Some("backend.example"));
+
+    let route = TlsSettings::default().route("https://backend.example").await.unwrap();
+    assert_eq!(route.url, "https://backend.example");
+    assert_eq!(route.host, None);
+}
+
+#[tokio::test]
+async fn socket_io_connects_through_the_pinned_tunnel() {
+    let mut backend = Backend::start().await;
+    let plc = plc().await;
+    let cert = ServerCert::generate();
+    let front = TlsFront::start(&cert, backend_addr(&backend)).await;
+
+    let mut config = config(&backend, &plc);
+    config.uplink.url = format!("https://127.0.0.1:{}", front.addr.port());
+    config.uplink.tls = cert.settings(cert.fingerprint());
+    let _agent = TestAgent::start_with(&mut backend, config).await;
+
+    backend.send(&add_sensor("level", 512)).await;
+    let sample = backend.expect("monitoring_streamline").await;
+    assert_eq!(sample["value"], 321);
+}
+
+#[tokio::test]
+async fn socket_io_never_authenticates_to_a_server_failing_the_pin() {
+    let backend = Backend::start().await;
+    let cert = ServerCert::generate();
+    let front = TlsFront::start(&cert, backend_addr(&backend)).await;
+
+    let (tx, _rx) = mpsc::channel(1);
+    let settings = cert.settings("ab".repeat(32));
+    let route = settings
+        .route(&format!("https://127.0.0.1:{}", front.addr.port()))
+        .await
+        .unwrap();
+    let connected = ws::setup_socket_io(
+        &route,
+        tx,
+        AgentAuth::Legacy("secret".to_string()),
+        &settings,
+        Arc::new(CommandVerifier::from_key_file(None, "e2e").unwrap()),
+        Negotiated::default(),
+    )
+    .await;
+    assert!(connected.is_err());
+    assert_eq!(front.relayed(), 0);
+}
+
+#[tokio::test]
+async fn mqtt_connects_through_the_pinned_tunnel() {
+    let mut broker = Broker::start().await;
+    let plc = plc().await;
+    let cert = ServerCert::generate();
+    let upstream = broker.url().trim_start_matches("mqtt://").parse().unwrap();
+    let front = TlsFront::start(&cert, upstream).await;
+
+    let mut config = mqtt_config(&broker, &plc);
+    config.uplink.mqtt.url = format!("mqtts://127.0.0.1:{}", front.addr.port());
+    config.uplink.tls = cert.settings(cert.fingerprint());
+    // Returns once the birth message made it through the tunnel.
+    let _agent = TestAgent::start_mqtt_with(&mut broker, config).await;
+}
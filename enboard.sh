@@ -1,32 +1,25 @@
 #!/bin/bash
 
-if [ "$#" -ne 3 ]; then
-  echo "Usage: $0 new_hostname1 new_hostname2 backend_hostname"
+# Bootstraps agents with the backend URL and starts them. Device addresses,
+# polling and ACLs are no longer edited over SSH: the backend pushes them
+# with the ApplyConfig command and the agent persists them to agent.toml.
+
+if [ "$#" -ne 1 ]; then
+  echo "Usage: $0 backend_url"
   exit 1
 fi
 
 REMOTE1="pi@raspberrypi.local"
 REMOTE2="pi2@raspberrypi2.local"
-HOSTNAME1="$1"
-HOSTNAME2="$2"
-HOSTNAME3="$3"
-ENV_FILE=".env"  
-
-update_hostname() {
-  local remote="$1"
-  local new_hostname="$2"
-
-  echo "Updating HOSTNAME on $remote to $new_hostname"
-  sshpass -p "pi" ssh "$remote" "sed -i \"s|^HOSTNAME=.*|HOSTNAME=$new_hostname|\" \$HOME/$ENV_FILE" \
-    && echo "✔ $remote updated" || echo "❌ Failed to update $remote"
-}
+BACKEND_URL="$1"
+ENV_FILE=".env"
 
 update_backend_url() {
   local remote="$1"
-  local new_hostname="$2"
+  local backend_url="$2"
 
-  echo "Updating WS_URL on $remote to $new_hostname"
-  sshpass -p "pi" ssh "$remote" "sed -i \"s|^WS_URL=.*|WS_URL=$new_hostname|\" \$HOME/$ENV_FILE" \
+  echo "Updating WS_URL on $remote to $backend_url"
+  sshpass -p "pi" ssh "$remote" "sed -i \"s|^WS_URL=.*|WS_URL=$backend_url|\" \$HOME/$ENV_FILE" \
     && echo "✔ $remote updated" || echo "❌ Failed to update $remote"
 }
 
@@ -39,11 +32,8 @@ start_agent() {
 }
 
 
-update_hostname "$REMOTE1" "$HOSTNAME1"
-update_hostname "$REMOTE2" "$HOSTNAME2"
-
-update_backend_url "$REMOTE1" "$HOSTNAME3"
-update_backend_url "$REMOTE2" "$HOSTNAME3"
+update_backend_url "$REMOTE1" "$BACKEND_URL"
+update_backend_url "$REMOTE2" "$BACKEND_URL"
 
 start_agent "$REMOTE1"
 start_agent "$REMOTE2"
@@ -0,0 +1,54 @@
+import { Test, TestingModule } from '@nestjs/testing';
+import { getRepositoryToken } from '@nestjs/typeorm';
+import { generateKeyPairSync, randomBytes, sign } from 'crypto';
+import { Agent } from 'src/entities';
+import { REDIS_CLIENT } from 'src/connection/constants';
+import { AgentIdentityService, SIGNATURE_WINDOW_SECS } from './agent-identity.service';
+
+describe('AgentIdentityService', () => {
+  const { publicKey, privateKey } = generateKeyPairSync('ed25519');
+  const agent = { id: '1', fingerprint: 'agent-1', publicKey: publicKey.export({ type: 'spki', format: 'pem' }).toString() };
+  let service: AgentIdentityService;
+
+  function signed(timestamp = Math.floor(Date.now() / 1000), extra = '') {
+    const nonce = randomBytes(16).toString('hex');
+    const message = Buffer.from(`${agent.fingerprint}|${timestamp}|${nonce}|${extra}`);
+    return { agentId: agent.fingerprint, timestamp, nonce, signature: sign(null, message, privateKey).toString('base64') };
+  }
+
+  beforeEach(async () => {
+    const claimed = new Set<string>();
+    const redis = {
+      set: async (key: string) => {
+        if (claimed.has(key)) return null;
+        claimed.add(key);
+        return 'OK';
+      },
+    };
+    const module: TestingModule = await Test.createTestingModule({
+      providers: [
+        AgentIdentityService,
+        { provide: getRepositoryToken(Agent), useValue: { findOne: async () => agent } },
+        { provide: REDIS_CLIENT, useValue: redis },
+      ],
+    }).compile();
+
+    service = module.get<AgentIdentityService>(AgentIdentityService);
+  });
+
+  it('accepts a fresh signature once', async () => {
+    const request = signed();
+    expect(await service.verify(request)).toBe(agent);
+    expect(await service.verify(request)).toBeNull();
+  });
+
+  it('rejects timestamps outside the window', async () => {
+    const stale = Math.floor(Date.now() / 1000) - SIGNATURE_WINDOW_SECS - 1;
+    expect(await service.verify(signed(stale))).toBeNull();
+  });
+
+  it('rejects signatures over other data', async () => {
+    expect(await service.verify(signed(undefined, 'new key'))).toBeNull();
+    expect(await service.verify({ ...signed(), agentId: 'agent-2' })).toBeNull();
+  });
+});
@@ -0,0 +1,108 @@
+import { BadRequestException, Inject, Injectable, UnauthorizedException } from '@nestjs/common';
+import { InjectRepository } from '@nestjs/typeorm';
+import { createPublicKey, verify } from 'crypto';
+import Redis from 'ioredis';
+import { IsNull, Not, Repository } from 'typeorm';
+import { Agent } from 'src/entities';
+import { REDIS_CLIENT } from 'src/connection/constants';
+import { EnrollAgentDto } from './dto/enroll-agent.dto';
+import { RotateAgentDto } from './dto/rotate-agent.dto';
+
+/** How far a signed timestamp may drift from the server clock, in seconds. */
+export const SIGNATURE_WINDOW_SECS = 300;
+
+export interface SignedRequest {
+	agentId: string;
+	timestamp: number;
+	nonce: string;
+	signature: string;
+}
+
+/**
+ * Enrolls agents and checks the Ed25519 signatures they put on handshakes
+ * and key rotations. Nonces are kept in Redis for the signature window, so a
+ * captured handshake cannot be replayed, not even across a server restart.
+ */
+@Injectable()
+export class AgentIdentityService {
+	constructor(
+		@InjectRepository(Agent)
+		private agentRepository: Repository<Agent>,
+		@Inject(REDIS_CLIENT) private readonly redisClient: Redis,
+	) { }
+
+	async enroll({ code, public_key }: EnrollAgentDto) {
+		const agent = await this.agentRepository.findOne({ where: { enrollmentCode: code } });
+		if (!agent) {
+			throw new UnauthorizedException("Unknown enrollment code");
+		}
+		await this.agentRepository.update({ id: agent.id }, {
+			publicKey: parseKey(public_key),
+			enrollmentCode: null,
+		});
+		return { agent_id: agent.fingerprint };
+	}
+
+	async rotate(request: RotateAgentDto) {
+		const agent = await this.verify({
+			agentId: request.agent_id,
+			timestamp: request.timestamp,
+			nonce: request.nonce,
+			signature: request.signature,
+		}, request.public_key);
+		if (!agent) {
+			throw new UnauthorizedException("Invalid rotation signature");
+		}
+		await this.agentRepository.update({ id: agent.id }, { publicKey: parseKey(request.public_key) });
+		return { agent_id: agent.fingerprint };
+	}
+
+	/**
+	 * Checks `agentId|timestamp|nonce|extra` against the agent's enrolled key
+	 * and returns the agent when it holds.
+	 */
+	async verify(request: SignedRequest, extra = ""): Promise<Agent | null> {
+		const { agentId, timestamp, nonce, signature } = request;
+		if (typeof agentId !== "string" || typeof nonce !== "string" || typeof signature !== "string" || !Number.isInteger(timestamp)) {
+			return null;
+		}
+		const now = Math.floor(Date.now() / 1000);
+		if (Math.abs(now - timestamp) > SIGNATURE_WINDOW_SECS) {
+			return null;
+		}
+		const agent = await this.agentRepository.findOne({ where: { fingerprint: agentId, publicKey: Not(IsNull()) } });
+		if (!agent?.publicKey) {
+			return null;
+		}
+		const message = Buffer.from(`${agentId}|${timestamp}|${nonce}|${extra}`);
+		let valid = false;
+		try {
+			valid = verify(null, message, agent.publicKey, Buffer.from(signature, "base64"));
+		} catch {
+			return null;
+		}
+		if (!valid || !(await this.claimNonce(`${agentId}|${nonce}`))) {
+			return null;
+		}
+		return agent;
+	}
+
+	/**
+	 * Records `key`, returning false when it was already used in the window.
+	 * Redis expires it, so nothing has to be swept here.
+	 */
+	private async claimNonce(key: string) {
+		const claimed = await this.redisClient.set(`agent-nonce:${key}`, '1', 'EX', 2 * SIGNATURE_WINDOW_SECS, 'NX');
+		return claimed === 'OK';
+	}
+}
+
+function parseKey(pem: string) {
+	try {
+		const key = createPublicKey(pem);
+		if (key.asymmetricKeyType === "ed25519") {
+			return key.export({ type: "spki", format: "pem" }).toString();
+		}
+	} catch { }
+	throw new BadRequestException("public_key must be an Ed25519 key in PEM format");
+}
@@ -1,17 +1,34 @@
 import { Controller, Get, Post, Body, Patch, Param, Delete } from '@nestjs/common';
 import { AgentService } from './agent.service';
+import { AgentIdentityService } from './agent-identity.service';
 import { CreateAgentDto } from './dto/create-agent.dto';
 import { UpdateAgentDto } from './dto/update-agent.dto';
+import { EnrollAgentDto } from './dto/enroll-agent.dto';
+import { RotateAgentDto } from './dto/rotate-agent.dto';
+import { PushConfigDto } from './dto/push-config.dto';
 
 @Controller('agent')
 export class AgentController {
-	constructor(private readonly agentService: AgentService) { }
+	constructor(
+		private readonly agentService: AgentService,
+		private readonly identityService: AgentIdentityService,
+	) { }
 
 	@Post()
 	async create(@Body() createAgentDto: CreateAgentDto) {
 		return await this.agentService.create(createAgentDto);
 	}
 
+	@Post('enroll')
+	async enroll(@Body() enrollAgentDto: EnrollAgentDto) {
+		return await this.identityService.enroll(enrollAgentDto);
+	}
+
+	@Post('rotate')
+	async rotate(@Body() rotateAgentDto: RotateAgentDto) {
+		return await this.identityService.rotate(rotateAgentDto);
+	}
+
 	@Get()
 	findAll() {
 		return this.agentService.findAll();
@@ -27,6 +44,11 @@ export class AgentController {
 		return await this.agentService.update(id, updateAgentDto);
 	}
 
+	@Post(':id/config')
+	async pushConfig(@Param('id') id: string, @Body() pushConfigDto: PushConfigDto) {
+		return await this.agentService.pushConfig(id, pushConfigDto);
+	}
+
 	@Delete(':id')
 	async remove(@Param('id') id: string) {
 		return await this.agentService.remove(id);
@@ -1,13 +1,15 @@
 import { Module } from '@nestjs/common';
 import { AgentService } from './agent.service';
 import { AgentController } from './agent.controller';
+import { AgentIdentityService } from './agent-identity.service';
 import { TypeOrmModule } from '@nestjs/typeorm';
 import { Agent } from 'src/entities';
+import { ConnectionModule } from 'src/connection/connection.module';
 
 @Module({
-	imports: [TypeOrmModule.forFeature([Agent])],
+	imports: [TypeOrmModule.forFeature([Agent]), ConnectionModule],
 	controllers: [AgentController],
-	providers: [AgentService],
-	exports: [AgentService],
+	providers: [AgentService, AgentIdentityService],
+	exports: [AgentService, AgentIdentityService],
 })
 export class AgentModule { }
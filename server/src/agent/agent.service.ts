@@ -1,6 +1,7 @@
-import { BadRequestException, Injectable } from '@nestjs/common';
+import { BadRequestException, Injectable, NotFoundException } from '@nestjs/common';
 import { CreateAgentDto } from './dto/create-agent.dto';
 import { UpdateAgentDto } from './dto/update-agent.dto';
+import { PushConfigDto } from './dto/push-config.dto';
 import { InjectRepository } from '@nestjs/typeorm';
 import { Repository } from 'typeorm';
 import { Agent, AgentState } from 'src/entities';
@@ -56,10 +57,20 @@ export class AgentService {
 		if (!updatedAgent.affected) {
 			throw new Error(`Failed to update the agent with ID: ${id}`);
 		}
-		this.eventBus.emit("agent:updated", { id, locked: updateAgentDto.locked });
+		const agent = await this.findOne(id);
+		this.eventBus.emit("agent:updated", { id, locked: updateAgentDto.locked, agentFingerprint: agent?.fingerprint });
 		return updatedAgent;
 	}
 
+	/** Sends a full configuration to the agent; it reports config_applied or config_rejected. */
+	async pushConfig(id: string, { version, config }: PushConfigDto) {
+		const agent = await this.findOne(id);
+		if (!agent) {
+			throw new NotFoundException(`No agent with ID: ${id}`);
+		}
+		this.eventBus.emit("agent:config-pushed", { id, agentFingerprint: agent.fingerprint, version, config });
+	}
+
 	async remove(id: string) {
 		const deleted = await this.agentRepository.softDelete({ id })
 		if (deleted.affected) {
@@ -1,4 +1,4 @@
-import { IsString } from "class-validator";
+import { IsOptional, IsString } from "class-validator";
 
 export class CreateAgentDto {
 	@IsString()
@@ -12,4 +12,8 @@ export class CreateAgentDto {
 
 	@IsString()
 	fingerprint: string;
+
+	@IsOptional()
+	@IsString()
+	enrollmentCode?: string;
 }
@@ -0,0 +1,10 @@
+import { IsString } from "class-validator";
+
+export class EnrollAgentDto {
+	@IsString()
+	code: string;
+
+	/** Ed25519 public key, PEM encoded. */
+	@IsString()
+	public_key: string;
+}
@@ -0,0 +1,11 @@
+import { IsInt, IsObject, Min } from "class-validator";
+
+/** Full agent configuration, applied by the agent only if `version` is newer than its own. */
+export class PushConfigDto {
+	@IsInt()
+	@Min(1)
+	version: number;
+
+	@IsObject()
+	config: Record<string, unknown>;
+}
@@ -0,0 +1,19 @@
+import { IsInt, IsString } from "class-validator";
+
+/** New key, signed with the current one over `agent_id|timestamp|nonce|public_key`. */
+export class RotateAgentDto {
+	@IsString()
+	agent_id: string;
+
+	@IsString()
+	public_key: string;
+
+	@IsInt()
+	timestamp: number;
+
+	@IsString()
+	nonce: string;
+
+	@IsString()
+	signature: string;
+}
@@ -5,6 +5,17 @@ import { REDIS_CLIENT } from 'src/connection/constants'
 
 interface ConnectionMetadata {
 	userId: string
+	/** Wire protocol agreed with an agent; absent for dashboard clients. */
+	protocol?: number
+	agentVersion?: string
+	capabilities?: AgentCapabilities
+}
+
+export interface AgentCapabilities {
+	transports: string[]
+	data_types: string[]
+	batching: boolean
+	alarms: boolean
 }
 
 interface ConnectionData {
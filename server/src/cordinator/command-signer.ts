@@ -0,0 +1,43 @@
+import { Injectable, Logger } from '@nestjs/common';
+import { createPrivateKey, KeyObject, randomBytes, sign } from 'crypto';
+import { readFileSync } from 'fs';
+
+/** How long a signed command stays valid; agents refuse more than 300 s. */
+const ENVELOPE_TTL_SECS = 60;
+
+/**
+ * Signs agent commands with the Ed25519 key in `COMMAND_SIGNING_KEY_FILE`.
+ * Agents configured with the matching public key (`uplink.command_key_file`)
+ * only accept safety-relevant commands in such an envelope.
+ */
+@Injectable()
+export class CommandSigner {
+	private logger = new Logger(CommandSigner.name);
+	private key?: KeyObject;
+
+	constructor() {
+		const path = process.env.COMMAND_SIGNING_KEY_FILE;
+		if (!path) {
+			this.logger.warn("COMMAND_SIGNING_KEY_FILE is not set, agent commands are sent unsigned");
+			return;
+		}
+		this.key = createPrivateKey(readFileSync(path));
+		if (this.key.asymmetricKeyType !== "ed25519") {
+			throw new Error(`${path} must hold an Ed25519 private key`);
+		}
+	}
+
+	/**
+	 * Wraps `message` in an envelope only `agentId` opens, and only as a
+	 * `command`. Returns the message unchanged when no key is configured.
+	 */
+	seal(agentId: string, command: string, message: unknown): unknown {
+		if (!this.key) return message;
+		const payload = JSON.stringify(message);
+		const nonce = randomBytes(16).toString("hex");
+		const expires_at = Math.floor(Date.now() / 1000) + ENVELOPE_TTL_SECS;
+		const signed = Buffer.from(`${agentId}|${command}|${nonce}|${expires_at}|${payload}`);
+		const signature = sign(null, signed, this.key).toString("base64");
+		return { envelope: { agent_id: agentId, command, payload, nonce, expires_at, signature } };
+	}
+}
@@ -10,6 +10,7 @@ import {
 } from '@nestjs/websockets';
 import { Server, Socket } from 'socket.io';
 import { AgentService } from 'src/agent/agent.service';
+import { AgentIdentityService } from 'src/agent/agent-identity.service';
 import { ConnectionStore } from 'src/connection-store/connection-store.service';
 import { AgentState, Process, ProcessState } from 'src/entities';
 import { AgentEventType } from 'src/event-bus/agent-events';
@@ -20,6 +21,21 @@ import { SyncService } from './sync.service';
 import { StreamManager } from 'src/process-engine/stream.service';
 import { LoggerService } from 'src/logger/logger.service';
 import { ParsersService } from 'src/parsers/parser-builder.service';
+import { agentMessage, WIRE_VERSION } from './wire';
+import { CommandSigner } from './command-signer';
+
+/** Newest agent wire protocol this gateway understands. */
+const SERVER_PROTOCOL_VERSION = WIRE_VERSION;
+const SERVER_VERSION = process.env.npm_package_version ?? "unknown";
+
+/**
+ * Picks the newest protocol both sides speak. Agents without a `protocol`
+ * field predate the handshake and speak version 1.
+ */
+function negotiateProtocol(offer?: { version?: number; min?: number }): number | undefined {
+	const version = Math.min(offer?.version ?? 1, SERVER_PROTOCOL_VERSION);
+	return version >= (offer?.min ?? 1) ? version : undefined;
+}
 
 @WebSocketGateway({ cors: true })
 export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, OnGatewayDisconnect {
@@ -32,9 +48,11 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		private connectionStore: ConnectionStore,
 		private processService: ProcessService,
 		private agentService: AgentService,
+		private identityService: AgentIdentityService,
 		private syncService: SyncService,
 		private loggerService: LoggerService,
 		private parserService: ParsersService,
+		private commandSigner: CommandSigner,
 	) { }
 
 	async afterInit(server: Server) {
@@ -51,6 +69,7 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		this.eventBus.on("alert:ai").subscribe(this.aiAlert.bind(this));
 		this.eventBus.on("agent:updated").subscribe(this.agentUpdate.bind(this));
 		this.eventBus.on("agent:cleanup").subscribe(this.agentCleanUp.bind(this));
+		this.eventBus.on("agent:config-pushed").subscribe(this.agentConfigPushed.bind(this));
 		const agents = await this.agentService.findAll();
 		if (agents && agents.length) {
 			for (const agent of agents) {
@@ -60,14 +79,15 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 	}
 
 	serverEmit<K extends AgentEventType>(event: K, payload: any) {
-		this.server.emit("data", { [event]: payload });
+		// Broadcasts reach agents of every version, so stay unversioned.
+		this.server.emit("data", agentMessage(event, payload, 1));
 	}
 	async notifyAgents<K extends AgentEventType>(event: K, payload: any) {
 		const agentsIds = this.connectionStore.getAllIds().filter(sock => sock.startsWith("sock-agent-"));
 		for (const id of agentsIds) {
 			const connection = await this.connectionStore.get(id);
-			const d = connection?.socket.emit("data", { [event]: payload });
-			console.log(d);
+			if (!connection) continue;
+			connection.socket.emit("data", this.commandFor(connection.metadata, event, payload));
 		}
 	}
 	async notifyAgentViaFingerprint<K extends AgentEventType>(event: K, payload: any) {
@@ -81,11 +101,17 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 			}
 			if (agent?.metadata.userId === payload.agentFingerprint) {
 				console.log("foundnn", agent);
-				agent?.socket.emit("data", { [event]: payload });
+				agent.socket.emit("data", this.commandFor(agent.metadata, event, payload));
 			}
 		}
 	}
 
+	/** The command as the agent behind `metadata` understands it, signed for that agent. */
+	private commandFor<K extends AgentEventType>(metadata: { userId: string; protocol?: number }, event: K, payload: any) {
+		const message = agentMessage(event, payload, metadata.protocol ?? 1);
+		return this.commandSigner.seal(metadata.userId, event, message);
+	}
+
 	async notifyClients<K extends AppEvents>(event: keyof K, payload: any, channel: "data" | "step-data" | "agent-disconnected" | "alert" | "ai" | "start-monitoring") {
 		const agentsIds = this.connectionStore.getAllIds().filter(sock => sock.startsWith("sock-client-"));
 
@@ -105,6 +131,10 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		this.logger.log('Sensor updated:', payload);
 		await this.notifyAgentViaFingerprint("CleanUp", payload);
 	}
+	async agentConfigPushed({ agentFingerprint, version, config }: AppEvents['agent:config-pushed']) {
+		this.logger.log(`Pushing config version ${version} to agent ${agentFingerprint}`);
+		await this.notifyAgentViaFingerprint("ApplyConfig", { agentFingerprint, version, config });
+	}
 	async sensorUpdated(payload: any) {
 		this.logger.log('Sensor updated:', payload);
 		// this.serverEmit("EditSensor", payload)
@@ -153,11 +183,32 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		}
 		else if (authType === "agent") {
 			console.log("otk", client.handshake.auth.token)
+			const auth = client.handshake.auth;
+			if (!await this.authenticateAgent(auth)) {
+				this.logger.warn(`Rejected agent ${auth.token}: invalid or missing handshake signature`);
+				client.disconnect(true);
+				return;
+			}
+			const protocol = negotiateProtocol(auth.protocol);
+			if (protocol === undefined) {
+				this.logger.warn(`Agent ${auth.token} (${auth.agent_version}) needs protocol ${auth.protocol?.min} or newer, server speaks up to ${SERVER_PROTOCOL_VERSION}`);
+				client.emit("protocol_unsupported", { max: SERVER_PROTOCOL_VERSION });
+				client.disconnect(true);
+				return;
+			}
 			const id = `sock-agent-${client.id}`
-			await this.connectionStore.set(id, client, { userId: client.handshake.auth.token })
-			const d = await this.agentService.changeAgentState(client.handshake.auth.token, AgentState.ready);
+			await this.connectionStore.set(id, client, {
+				userId: auth.token,
+				protocol,
+				agentVersion: auth.agent_version,
+				capabilities: auth.capabilities,
+			})
+			const d = await this.agentService.changeAgentState(auth.token, AgentState.ready);
 			console.log(d);
-			client.emit("data", "HealthCheck");
+			// Agents that predate the handshake ignore the hello and keep
+			// speaking protocol 1.
+			client.emit("server_hello", { protocol, server_version: SERVER_VERSION });
+			client.emit("data", this.commandSigner.seal(auth.token, "HealthCheck", agentMessage("HealthCheck", null, protocol)));
 			return;
 		} else {
 			console.log("auths", client.handshake.auth.token)
@@ -168,6 +219,25 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		}
 	}
 
+	/**
+	 * Enrolled agents must sign the handshake with their key; agents without
+	 * one may still use their shared fingerprint until they enroll.
+	 */
+	private async authenticateAgent(auth: Record<string, any>) {
+		if (auth.signature !== undefined) {
+			const agent = await this.identityService.verify({
+				agentId: auth.token,
+				timestamp: auth.timestamp,
+				nonce: auth.nonce,
+				signature: auth.signature,
+			});
+			return agent !== null;
+		}
+		if (typeof auth.token !== "string") return false;
+		const agent = await this.agentService.findByFingerprint(auth.token);
+		return agent !== null && !agent.publicKey;
+	}
+
 	async handleDisconnect(client: Socket) {
 		this.logger.log(`Client disconnected: ${client.id}`);
 		const agentSocketId = await this.connectionStore.get("sock-agent-" + client.id)
@@ -323,7 +393,37 @@ export class CordinatorGateway implements OnGatewayInit, OnGatewayConnection, On
 		const agentId = await this.connectionStore.get("sock-agent-" + client.id)
 		if (!agentId) return;
 
-		this.syncService.syncAgentWithServerData(data, agentId?.metadata.userId as string);
+		// Older agents send the bare sensor list, newer ones a full health report.
+		const sensors = Array.isArray(data) ? data : data?.sensors;
+		this.syncService.syncAgentWithServerData(sensors, agentId?.metadata.userId as string);
 
 	}
+	@SubscribeMessage('config_applied')
+	async handleConfigApplied(
+		@MessageBody() data: any,
+		@ConnectedSocket() client: Socket,
+	): Promise<void> {
+		this.logger.log(`Agent ${client.id} applied config version ${data?.version}`);
+	}
+	@SubscribeMessage('config_rejected')
+	async handleConfigRejected(
+		@MessageBody() data: any,
+		@ConnectedSocket() client: Socket,
+	): Promise<void> {
+		this.logger.warn(`Agent ${client.id} rejected config version ${data?.version}, running ${data?.running_version}: ${data?.message}`);
+	}
+	@SubscribeMessage('agent_offline')
+	async handleAgentOffline(
+		@MessageBody() data: any,
+		@ConnectedSocket() client: Socket,
+	): Promise<void> {
+		this.logger.warn(`Agent ${client.id} going offline: ${data?.reason}`);
+	}
+	@SubscribeMessage('command_failed')
+	async handleCommandFailed(
+		@MessageBody() data: any,
+		@ConnectedSocket() client: Socket,
+	): Promise<void> {
+		this.logger.warn(`Agent ${client.id} failed ${data?.command} [${data?.error?.code}]: ${data?.message}`);
+	}
 }
@@ -4,12 +4,13 @@ import { ConnectionStore } from 'src/connection-store/connection-store.service';
 import { ConnectionModule } from 'src/connection/connection.module';
 import { ProcessModule } from 'src/process/process.module';
 import { SyncService } from './sync.service';
+import { CommandSigner } from './command-signer';
 import { ParsersService } from 'src/parsers/parser-builder.service';
 
 @Global()
 @Module({
 	imports: [ConnectionModule, ProcessModule, AgentModule],
-	providers: [ConnectionStore, SyncService , ParsersService],
-	exports: [ConnectionStore, SyncService]
+	providers: [ConnectionStore, SyncService , ParsersService, CommandSigner],
+	exports: [ConnectionStore, SyncService, CommandSigner]
 })
 export class CordinatorModule { }
@@ -4,6 +4,18 @@ import { EventBusService } from 'src/event-bus/event-bus.service';
 import { isEqual } from "lodash"
 import { ParsersService } from 'src/parsers/parser-builder.service';
 
+/**
+ * What the agent's health check and the database have in common, so the
+ * sensor lists can be compared whatever else either side adds. Alert rules
+ * ("general" sensors) are not part of the comparison.
+ */
+function sensorKeys(sensors?: { id: string; start_register: number; end_register: number; s_type: string }[]) {
+	return (sensors ?? [])
+		.filter(sensor => sensor.s_type === "sensor")
+		.map(({ id, start_register, end_register }) => ({ id, start_register, end_register }))
+		.sort((a, b) => a.id.localeCompare(b.id));
+}
+
 @Injectable()
 export class SyncService {
 	constructor(
@@ -25,10 +37,10 @@ export class SyncService {
 				s_type: "sensor"
 			})
 		)
-		const isSynced = isEqual(data, sensors);
+		const isSynced = isEqual(sensorKeys(data), sensorKeys(sensors));
 		console.log(isSynced)
 		if (isSynced) return;
-		this.eventBus.emit("agent:cleanup", { id: agent?.id as string });
+		this.eventBus.emit("agent:cleanup", { id: agent?.id as string, agentFingerprint: fingerprint });
 		agent?.sensors.map(sensor => (
 			this.eventBus.emit("sensor:created",
 				{
@@ -53,7 +65,7 @@ export class SyncService {
 						end_register: 1,
 						agentFingerprint: agent.fingerprint,
 						s_type: "general",
-						r_type: parser?.addressToModbus(rule.memoryAddress).type as string,
+						r_type: parser?.addressToModbus(rule.memoryAddress).type?.toUpperCase() as string,
 					})
 			))
 		))
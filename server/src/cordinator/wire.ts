@@ -0,0 +1,30 @@
+import { AgentCommands, AgentEventType } from 'src/event-bus/agent-events';
+
+/** Version of the agent wire schema, see agent/schema/wire-v3.json. */
+export const WIRE_VERSION = 3;
+
+/** First protocol whose commands are wrapped in `{ v, command }`. */
+const FIRST_VERSIONED = 3;
+
+/** Commands without a payload; the agent expects them as bare strings. */
+const UNIT_COMMANDS = new Set<AgentEventType>([
+	"Wait", "Stop", "PauseAgent", "HealthCheck", "ReloadConfig", "CleanUp",
+]);
+
+/**
+ * Builds the message an agent on `protocol` understands. Routing fields such
+ * as `agentFingerprint` are the gateway's business and never sent.
+ */
+export function agentMessage<K extends AgentEventType>(
+	event: K,
+	payload: (AgentCommands[K] & { agentFingerprint?: string }) | Record<string, unknown> | null,
+	protocol: number,
+): unknown {
+	let command: unknown = event;
+	if (!UNIT_COMMANDS.has(event)) {
+		const data: Record<string, unknown> = { ...(payload ?? {}) };
+		delete data.agentFingerprint;
+		command = { [event]: data };
+	}
+	return protocol >= FIRST_VERSIONED ? { v: WIRE_VERSION, command } : command;
+}
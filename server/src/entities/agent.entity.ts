@@ -39,6 +39,14 @@ export class Agent {
 	@Column({ nullable: false })
 	plcId: string;
 
+	/** One-time code the agent presents to `POST /agent/enroll`. */
+	@Column({ type: 'varchar', nullable: true })
+	enrollmentCode: string | null;
+
+	/** Ed25519 key (PEM) the enrolled agent signs its handshakes with. */
+	@Column({ type: 'text', nullable: true })
+	publicKey: string | null;
+
 	@DeleteDateColumn()
 	deletedAt?: Date;
 
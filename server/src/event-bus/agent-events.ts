@@ -1,11 +1,34 @@
-export type AgentEventType =
-	"Wait"
-	| "Stop"
-	| "Write"
-	| "AddSensor"
-	| "RemoveSensor"
-	| "EditSensor"
-	| "PauseAgent"
-	| "HealthCheck"
-	| "CleanUp";
+/**
+ * Commands the gateway sends to agents, keyed by command name. Mirrors the
+ * `ChEvent` definition in agent/schema/wire-v3.json, which is generated from
+ * the agent's Rust types; change both together.
+ */
+export interface AgentCommands {
+	Wait: null;
+	Stop: null;
+	Write: { reg: number; val: number; r_type: string; device?: string };
+	AddSensor: SensorCommand;
+	RemoveSensor: { id: string };
+	EditSensor: SensorCommand;
+	/** Toggles between paused and running. */
+	PauseAgent: null;
+	HealthCheck: null;
+	SetLogLevel: { filter: string };
+	ReloadConfig: null;
+	ApplyConfig: { version: number; config: Record<string, unknown> };
+	CleanUp: null;
+}
 
+export interface SensorCommand {
+	id: string;
+	label: string;
+	start_register: number;
+	register: string;
+	end_register: number;
+	s_type: "sensor" | "general";
+	/** "REG" for holding registers, anything else reads coils. */
+	r_type: string;
+	device?: string;
+}
+
+export type AgentEventType = keyof AgentCommands;
@@ -2,9 +2,10 @@ export interface AppEvents {
 	'user:created': { id: string; name: string };
 	'user:deleted': { id: string };
 	'agent:created': { id: string };
-	'agent:updated': { id: string, locked?: boolean };
+	'agent:updated': { id: string, locked?: boolean, agentFingerprint?: string };
 	'agent:deleted': { id: string }
-	'agent:cleanup': { id: string }
+	'agent:cleanup': { id: string, agentFingerprint: string }
+	'agent:config-pushed': { id: string, agentFingerprint: string, version: number, config: Record<string, unknown> }
 	'agent:disconnected': { processId: string }
 	'agent:sync': { id: string, label: string, start_register: number, end_register: number, agentFingerprint: string };
 